@@ -6,9 +6,17 @@ extern crate fuse;
 extern crate gitty_backup_rs;
 extern crate libc;
 extern crate lru_time_cache;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 extern crate time;
 
 use bimap::BiMap;
+use chrono::DateTime;
+use chrono::Datelike;
+use chrono::FixedOffset;
+use chrono::Timelike;
 use chrono::Utc;
 use fuse::FileAttr;
 use fuse::FileType;
@@ -16,38 +24,75 @@ use fuse::Filesystem;
 use fuse::ReplyAttr;
 use fuse::ReplyData;
 use fuse::ReplyDirectory;
+use fuse::ReplyEmpty;
 use fuse::ReplyEntry;
+use fuse::ReplyOpen;
+use fuse::ReplyStatfs;
+use fuse::ReplyXattr;
 use fuse::Request;
 use fuse::FUSE_ROOT_ID;
-use gitty_backup_rs::commits::walk_commits;
+use gitty_backup_rs::database::commit_graph::CommitGraphEntry;
+use gitty_backup_rs::database::crypto::KeySource;
 use gitty_backup_rs::database::fs_database::FSDatabase;
+use gitty_backup_rs::database::BlobReader;
 use gitty_backup_rs::database::GittyDatabase;
+use gitty_backup_rs::database::StorageStats;
 use gitty_backup_rs::model::*;
+use libc::c_int;
+use libc::EBADF;
 use libc::EINVAL;
 use libc::EIO;
 use libc::EISDIR;
+use libc::ENODATA;
 use libc::ENOENT;
+use libc::ENOTDIR;
+use libc::ERANGE;
+use libc::EROFS;
+use libc::O_ACCMODE;
+use libc::O_RDONLY;
 use lru_time_cache::LruCache;
-use std::borrow::Cow;
-use std::collections::hash_map::Entry;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::env;
 use std::ffi::OsStr;
 use std::ffi::OsString;
-use std::fs::File;
+use std::hash::Hash;
+use std::io::Cursor;
 use std::io::Read;
-use std::io::Seek;
-use std::io::SeekFrom;
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
 use std::time::Duration;
 use std::time::SystemTime;
 use time::Timespec;
 type Inode = u64;
+// inodes of entries and commits are derived from their hashes so they are the same on every
+// mount. Below this are the inodes of fixed entries like the root
+const RESERVED_INODES: Inode = 1 << 16;
+const BY_DATE_INODE: Inode = 2;
+const LATEST_INODE: Inode = 3;
+const HISTORY_INODE: Inode = 4;
+const GITTY_DIR_INODE: Inode = 5;
+const STATS_INODE: Inode = 6;
+// metadata of the commit, in each commit directory
+const COMMIT_INFO_NAME: &str = ".gitty-commit.json";
+// hashed inodes never have the top bit set, on a collision an inode from this range is used
+const FALLBACK_INODES: Inode = 1 << 63;
 const TTL: Timespec = Timespec {
     sec: 2 << 62,
     nsec: 0,
 }; // immutable, cache forever
 
+// the root and by-date change with new snapshots
+const VIRTUAL_TTL: Timespec = Timespec { sec: 1, nsec: 0 };
+
 const STD_ATTR: FileAttr = FileAttr {
     ino: 0,
     atime: Timespec { sec: 0, nsec: 0 },
@@ -64,18 +109,168 @@ const STD_ATTR: FileAttr = FileAttr {
     kind: FileType::Directory,
     rdev: 0,
 };
+// shared by the threads serving requests
 struct GittyViewer<'a> {
-    db: &'a GittyDatabase,
-    // TODO: BidirMap is really slow
-    inode_commits: BiMap<Inode, GittyCommitRef>,
-    commits: HashMap<GittyCommitRef, GittyCommit>,
-    root_map: HashMap<OsString, GittyCommitRef>,
-    // map inode <-> parent tree, name, content hash
-    inode_trees_blobs: BiMap<Inode, (GittyTreeRef, OsString, OwnedGittyObjectRef)>,
-    trees: HashMap<GittyTreeRef, GittyTree>,
-    inode_max: Inode,
+    db: &'a (dyn GittyDatabase + Sync),
+    inodes: Mutex<Inodes>,
+    commits: Mutex<LruCache<GittyCommitRef, Arc<GittyCommit>>>,
+    // listing of the root, built again when HEAD changes
+    history: Mutex<Option<Arc<History>>>,
+    // for statfs and .gitty/stats.json, counted again when HEAD changes
+    stats: Mutex<Option<Arc<RepoStats>>>,
+    // versions of the paths in history/, emptied when HEAD changes
+    path_histories: Mutex<LruCache<PathHistoryKey, Arc<PathHistory>>>,
+    history_entries: usize,
+    trees: Mutex<LruCache<GittyTreeRef, Arc<GittyTree>>>,
     root_mtime: Duration,
-    blob_read_cache: LruCache<Inode, File>,
+    // blob readers of open files, each locked on its own so different files are read in parallel
+    file_handles: Mutex<HashMap<u64, Arc<Mutex<BlobReader>>>>,
+    fh_max: AtomicU64,
+}
+
+// inodes known to the kernel
+struct Inodes {
+    // TODO: BidirMap is really slow
+    commits: BiMap<Inode, GittyCommitRef>,
+    // map inode <-> entry of a snapshot directory
+    trees_blobs: BiMap<Inode, EntryKey>,
+    // parent tree and name the entry was last looked up with, for its attributes. files
+    // with the same content share an inode like hard links, so they share them too
+    locations: HashMap<Inode, (GittyTreeRef, OsString)>,
+    // by-date directories, by year, month and day
+    dates: BiMap<Inode, Vec<u32>>,
+    // history/ directories, by path below the commit roots
+    paths: BiMap<Inode, Vec<OsString>>,
+    // .gitty-commit.json files, by commit
+    commit_infos: BiMap<Inode, GittyCommitRef>,
+    // by-date/<year>/<month>/<day>/<time> symlinks, by commit
+    commit_links: BiMap<Inode, GittyCommitRef>,
+    // references the kernel holds to each inode, its mapping is dropped when they are forgotten
+    lookups: HashMap<Inode, u64>,
+    // last inode given out after a collision
+    max: Inode,
+    // hashed inode of the keys given a fallback inode, entries below a directory are hashed
+    // with it so they don't depend on the order of lookups in this mount
+    fallbacks: HashMap<Inode, Inode>,
+}
+
+// commits reachable from HEAD, newest first
+struct History {
+    head: GittyCommitRef,
+    commits: Vec<HistoryEntry>,
+    by_name: HashMap<OsString, usize>,
+}
+
+struct HistoryEntry {
+    commit_ref: GittyCommitRef,
+    root: GittyHash,
+    // unique name in the root
+    name: String,
+    // shown as by-date/<year>/<month>/<day>/<time_name>
+    date: Vec<u32>,
+    time_name: String,
+    commit_time: DateTime<FixedOffset>,
+    // of the snapshot
+    files: u64,
+    bytes: u64,
+}
+
+struct RepoStats {
+    head: GittyCommitRef,
+    storage: StorageStats,
+}
+
+// content of .gitty/stats.json
+#[derive(Serialize)]
+struct StatsFile<'a> {
+    storage: &'a StorageStats,
+    // total size of the snapshots, as if each was stored in full
+    snapshot_bytes: u64,
+    dedup_ratio: f64,
+    // newest first
+    snapshots: Vec<SnapshotStats>,
+}
+
+#[derive(Serialize)]
+struct SnapshotStats {
+    name: String,
+    commit: String,
+    time: String,
+    files: u64,
+    bytes: u64,
+}
+
+// content of .gitty-commit.json
+#[derive(Serialize)]
+struct CommitInfo<'a> {
+    hash: &'a GittyHash,
+    #[serde(flatten)]
+    commit: &'a GittyCommit,
+}
+
+// every distinct version of a path across the commits, and the names found below it
+struct PathHistory {
+    // oldest first, named after the first commit that has them, with the tree containing them
+    versions: Vec<(OsString, GittyTreeRef, GittyTreeEntry)>,
+    children: BTreeSet<OsString>,
+    // the directory at the path in each commit, oldest first
+    dirs: Vec<Option<GittyTreeRef>>,
+}
+
+// an entry of the root, of a by-date or of a history directory
+enum VirtualEntry {
+    Fixed(Inode, FileType),
+    Date(Vec<u32>),
+    Commit(GittyCommitRef),
+    // symlink to the directory of the commit in the root
+    CommitLink(GittyCommitRef),
+    Path(Vec<OsString>),
+    // entry of the parent tree, shown under another name
+    Version(GittyTreeRef, GittyTreeEntry),
+    CommitInfo(GittyCommitRef),
+}
+
+// an entry of a snapshot directory, the same key is the same inode
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum EntryKey {
+    // inode of the directory it is shown in, parent tree, name, content
+    Tree(Inode, GittyTreeRef, OsString, GittyTreeRef),
+    // content and attributes, wherever it is shown
+    Blob(GittyBlobRef, FileAttrs),
+}
+
+// what the attributes of a file are made of, so files sharing an inode can't show each
+// other's
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct FileAttrs {
+    is_symlink: bool,
+    size: u64,
+    modified: DateTime<Utc>,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+}
+
+// HEAD, path below the commit roots
+type PathHistoryKey = (GittyCommitRef, Vec<OsString>);
+
+// number of objects kept in memory, evicted ones are loaded from the database again
+struct CacheLimits {
+    trees: usize,
+    commits: usize,
+    // directories of a path in each commit, summed over the paths in history/ kept in
+    // memory, as each path history has one for every commit
+    history_entries: usize,
+}
+
+impl Default for CacheLimits {
+    fn default() -> CacheLimits {
+        CacheLimits {
+            trees: 10_000,
+            commits: 10_000,
+            history_entries: 1_000_000,
+        }
+    }
 }
 
 fn find_tree_entry<'a>(tree: &'a GittyTree, name: &'a OsStr) -> Option<&'a GittyTreeEntry> {
@@ -87,115 +282,857 @@ fn find_tree_entry<'a>(tree: &'a GittyTree, name: &'a OsStr) -> Option<&'a Gitty
     })
 }
 
-fn commit_fname(commit: &GittyCommit) -> String {
-    (commit.commit_time
-        - chrono::Duration::nanoseconds(commit.commit_time.timestamp_subsec_nanos() as i64))
+fn entry_name(entry: &GittyTreeEntry) -> &OsString {
+    match entry {
+        GittyTreeEntry::Tree(t) => &t.name,
+        GittyTreeEntry::Blob(b) => &b.name,
+    }
+}
+
+fn entry_hash(entry: &GittyTreeEntry) -> &GittyHash {
+    match entry {
+        GittyTreeEntry::Tree(t) => &t.hash,
+        GittyTreeEntry::Blob(b) => &b.hash,
+    }
+}
+
+fn entry_kind(entry: &GittyTreeEntry) -> FileType {
+    match entry {
+        GittyTreeEntry::Tree(_) => FileType::Directory,
+        GittyTreeEntry::Blob(b) if b.is_symlink => FileType::Symlink,
+        GittyTreeEntry::Blob(_) => FileType::RegularFile,
+    }
+}
+
+fn hash_bytes(hash: &GittyHash) -> Vec<u8> {
+    let mut bytes = vec![hash.algorithm.code()];
+    bytes.extend_from_slice(&hash.digest);
+    bytes
+}
+
+fn hashed_inode(parts: &[&[u8]]) -> Inode {
+    let mut hasher = GittyHasher::new(HashAlgorithm::Blake3);
+    for part in parts {
+        // length prefixed so different parts can't produce the same input
+        hasher.input(&(part.len() as u64).to_be_bytes());
+        hasher.input(part);
+    }
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&hasher.result().digest[..8]);
+    u64::from_be_bytes(bytes) & !FALLBACK_INODES
+}
+
+fn entry_key(dir: Inode, tree_ref: &GittyTreeRef, entry: &GittyTreeEntry) -> EntryKey {
+    match entry {
+        GittyTreeEntry::Tree(t) => EntryKey::Tree(
+            dir,
+            tree_ref.clone(),
+            t.name.clone(),
+            GittyTreeRef {
+                hash: t.hash.clone(),
+            },
+        ),
+        GittyTreeEntry::Blob(b) => EntryKey::Blob(
+            GittyBlobRef {
+                hash: b.hash.clone(),
+            },
+            FileAttrs {
+                is_symlink: b.is_symlink,
+                size: b.size,
+                modified: b.modified,
+                mode: b.permissions.mode,
+                uid: b.permissions.uid,
+                gid: b.permissions.gid,
+            },
+        ),
+    }
+}
+
+fn commit_hashed_inode(commit_ref: &GittyCommitRef) -> Inode {
+    hashed_inode(&[b"commit", &hash_bytes(&commit_ref.hash)])
+}
+
+fn date_hashed_inode(date: &[u32]) -> Inode {
+    let bytes: Vec<u8> = date.iter().flat_map(|n| n.to_be_bytes().to_vec()).collect();
+    hashed_inode(&[b"date", &bytes])
+}
+
+fn commit_info_hashed_inode(commit_ref: &GittyCommitRef) -> Inode {
+    hashed_inode(&[b"commit-info", &hash_bytes(&commit_ref.hash)])
+}
+
+fn commit_link_hashed_inode(commit_ref: &GittyCommitRef) -> Inode {
+    hashed_inode(&[b"commit-link", &hash_bytes(&commit_ref.hash)])
+}
+
+fn path_hashed_inode(path: &[OsString]) -> Inode {
+    let mut parts: Vec<&[u8]> = vec![b"path"];
+    parts.extend(path.iter().map(|name| name.as_bytes()));
+    hashed_inode(&parts)
+}
+
+fn commit_fname(commit_time: &DateTime<FixedOffset>) -> String {
+    (*commit_time - chrono::Duration::nanoseconds(commit_time.timestamp_subsec_nanos() as i64))
         .to_string()
 }
-impl<'a> GittyViewer<'a> {
-    fn new(db: &'a mut GittyDatabase) -> GittyViewer<'a> {
-        GittyViewer {
-            db,
-            inode_trees_blobs: BiMap::new(),
-            inode_commits: BiMap::new(),
-            inode_max: 1,
-            root_mtime: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap(),
-            trees: HashMap::new(),
-            commits: HashMap::new(),
-            root_map: HashMap::new(),
-            blob_read_cache: LruCache::with_expiry_duration_and_capacity(
-                Duration::from_secs(60),
-                500,
-            ),
+
+// base, or base with a number if taken returns true for it
+fn unique_name(base: String, taken: impl Fn(&str) -> bool) -> String {
+    if !taken(&base) {
+        return base;
+    }
+    (2..)
+        .map(|n| format!("{} ({})", base, n))
+        .find(|name| !taken(name))
+        .unwrap()
+}
+
+impl History {
+    // commits newest first, like in the commit graph
+    fn new(head: GittyCommitRef, commits: Vec<CommitGraphEntry>) -> History {
+        let mut names = HashSet::new();
+        let mut by_time = HashSet::new();
+        let mut entries = Vec::with_capacity(commits.len());
+        // oldest first, so the names of existing commits don't change when new ones are added
+        for commit in commits.into_iter().rev() {
+            let time = commit.commit_time;
+            let name = unique_name(commit_fname(&time), |n| names.contains(n));
+            names.insert(name.clone());
+            let date = vec![time.year() as u32, time.month(), time.day()];
+            let time_name = unique_name(format!("{:02}:{:02}", time.hour(), time.minute()), |n| {
+                by_time.contains(&(date.clone(), n.to_owned()))
+            });
+            by_time.insert((date.clone(), time_name.clone()));
+            entries.push(HistoryEntry {
+                commit_ref: commit.commit_ref,
+                root: commit.root,
+                name,
+                date,
+                time_name,
+                commit_time: time,
+                files: commit.files,
+                bytes: commit.bytes,
+            });
+        }
+        entries.reverse();
+        let by_name = entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| (OsString::from(&entry.name), i))
+            .collect();
+        History {
+            head,
+            commits: entries,
+            by_name,
         }
     }
-    fn get_tree(&mut self, r: &GittyTreeRef) -> Option<&GittyTree> {
-        match self.trees.entry(r.clone()) {
-            Entry::Occupied(o) => Some(o.into_mut()),
-            Entry::Vacant(v) => match self.db.load_tree(r) {
-                Ok(e) => Some(v.insert(e)),
-                Err(_) => None,
-            },
+
+    // the entries of by-date/<date...>
+    fn date_entries(&self, date: &[u32]) -> Vec<(OsString, VirtualEntry)> {
+        let matching = self
+            .commits
+            .iter()
+            .rev()
+            .filter(|c| c.date.starts_with(date));
+        if date.len() == 3 {
+            return matching
+                .map(|c| {
+                    (
+                        OsString::from(&c.time_name),
+                        VirtualEntry::CommitLink(c.commit_ref.clone()),
+                    )
+                })
+                .collect();
+        }
+        let parts: BTreeSet<u32> = matching.map(|c| c.date[date.len()]).collect();
+        parts
+            .into_iter()
+            .map(|part| {
+                let mut child = date.to_vec();
+                child.push(part);
+                let name = if date.is_empty() {
+                    format!("{:04}", part)
+                } else {
+                    format!("{:02}", part)
+                };
+                (OsString::from(name), VirtualEntry::Date(child))
+            })
+            .collect()
+    }
+}
+
+// a commit is shown as the directory of its root tree
+fn commit_entry(commit: &GittyCommit) -> GittyTreeEntry {
+    GittyTreeEntry::Tree(GittyTreeMetadata {
+        hash: commit.root.clone(),
+        name: OsStr::new(&commit_fname(&commit.commit_time)).to_owned(),
+        modified: commit.commit_time.with_timezone(&Utc),
+        permissions: Permissions {
+            kind: "unix".to_owned(),
+            mode: 0o755,
+            uid: 0,
+            gid: 0,
+        },
+    })
+}
+
+impl Inodes {
+    fn new() -> Inodes {
+        Inodes {
+            commits: BiMap::new(),
+            trees_blobs: BiMap::new(),
+            locations: HashMap::new(),
+            dates: BiMap::new(),
+            paths: BiMap::new(),
+            commit_infos: BiMap::new(),
+            commit_links: BiMap::new(),
+            lookups: HashMap::new(),
+            max: FALLBACK_INODES,
+            fallbacks: HashMap::new(),
         }
     }
-    fn tree_ele_to_inode(
+    // inode of a key, the hashed inode unless another key has it
+    fn register<K: Clone + Eq + Hash>(
         &mut self,
-        tree: &GittyTree,
-        tree_ref: &GittyTreeRef,
-        name: &OsStr,
+        map: fn(&mut Inodes) -> &mut BiMap<Inode, K>,
+        key: &K,
+        hashed: Inode,
     ) -> Inode {
-        let entry = find_tree_entry(tree, name).unwrap();
-        self.tree_entry_to_inode(tree_ref, entry)
-    }
-    fn tree_entry_to_inode(&mut self, tree_ref: &GittyTreeRef, entry: &GittyTreeEntry) -> Inode {
-        let (name, entry_wrap) = match entry {
-            GittyTreeEntry::Tree(t) => (
-                t.name.clone(),
-                OwnedGittyObjectRef::Tree(GittyTreeRef {
-                    hash: t.hash.clone(),
-                }),
-            ),
-            GittyTreeEntry::Blob(t) => (
-                t.name.clone(),
-                OwnedGittyObjectRef::Blob(GittyBlobRef {
-                    hash: t.hash.clone(),
-                }),
-            ),
-        };
-
-        let tp = (tree_ref.clone(), name.to_owned(), entry_wrap);
-        if let Some(inode) = self.inode_trees_blobs.get_by_right(&tp) {
+        if let Some(inode) = map(self).get_by_right(key) {
             return *inode;
         }
-
-        self.inode_max += 1;
-        let inode = self.inode_max;
-        self.inode_trees_blobs.insert(inode, tp);
+        let inode = self.free_inode(hashed);
+        map(self).insert(inode, key.clone());
         inode
     }
-    fn commit_to_inode(
+    // inode shown in directory listings. the key is only registered if another key has its
+    // hashed inode, so the listing shows the fallback inode lookup gives it. such keys stay
+    // until the kernel looks them up and forgets them
+    fn shown<K: Clone + Eq + Hash>(
         &mut self,
-        commit_ref: Cow<GittyCommitRef>,
-        commit: Cow<GittyCommit>,
+        map: fn(&mut Inodes) -> &mut BiMap<Inode, K>,
+        key: &K,
+        hashed: Inode,
     ) -> Inode {
-        if let Some(inode) = self.inode_commits.get_by_right(&commit_ref) {
+        if let Some(inode) = map(self).get_by_right(key) {
             return *inode;
         }
-
-        self.inode_max += 1;
-        let inode = self.inode_max;
-        self.inode_commits
-            .insert(inode, commit_ref.as_ref().clone());
-        self.commits
-            .insert(commit_ref.into_owned(), commit.into_owned());
+        if self.is_free(hashed) {
+            return hashed;
+        }
+        self.register(map, key, hashed)
+    }
+    // identical subtrees in different places don't share inodes, the kernel expects a
+    // directory to have a single parent. files only depend on their content and attributes
+    fn entry_hashed_inode(&self, key: &EntryKey) -> Inode {
+        match key {
+            EntryKey::Tree(dir, tree_ref, name, tree) => {
+                let dir = self.fallbacks.get(dir).unwrap_or(dir);
+                hashed_inode(&[
+                    b"entry",
+                    &dir.to_be_bytes(),
+                    &hash_bytes(&tree_ref.hash),
+                    name.as_bytes(),
+                    b"tree",
+                    &hash_bytes(&tree.hash),
+                ])
+            }
+            EntryKey::Blob(blob, attrs) => hashed_inode(&[
+                b"blob",
+                &hash_bytes(&blob.hash),
+                &[attrs.is_symlink as u8],
+                &attrs.size.to_be_bytes(),
+                &attrs.modified.timestamp().to_be_bytes(),
+                &attrs.modified.timestamp_subsec_nanos().to_be_bytes(),
+                &attrs.mode.to_be_bytes(),
+                &attrs.uid.to_be_bytes(),
+                &attrs.gid.to_be_bytes(),
+            ]),
+        }
+    }
+    // inode of an entry passed to the kernel by lookup
+    fn entry_to_inode(
+        &mut self,
+        dir: Inode,
+        tree_ref: &GittyTreeRef,
+        entry: &GittyTreeEntry,
+    ) -> Inode {
+        let key = entry_key(dir, tree_ref, entry);
+        let hashed = self.entry_hashed_inode(&key);
+        let inode = self.register(|inodes| &mut inodes.trees_blobs, &key, hashed);
+        self.locations
+            .insert(inode, (tree_ref.clone(), entry_name(entry).clone()));
+        self.remember(inode);
+        inode
+    }
+    fn entry_inode(
+        &mut self,
+        dir: Inode,
+        tree_ref: &GittyTreeRef,
+        entry: &GittyTreeEntry,
+    ) -> Inode {
+        let key = entry_key(dir, tree_ref, entry);
+        let hashed = self.entry_hashed_inode(&key);
+        let inode = self.shown(|inodes| &mut inodes.trees_blobs, &key, hashed);
+        if self.trees_blobs.contains_left(&inode) {
+            self.locations
+                .entry(inode)
+                .or_insert_with(|| (tree_ref.clone(), entry_name(entry).clone()));
+        }
+        inode
+    }
+    fn commit_to_inode(&mut self, commit_ref: &GittyCommitRef) -> Inode {
+        let hashed = commit_hashed_inode(commit_ref);
+        let inode = self.register(|inodes| &mut inodes.commits, commit_ref, hashed);
+        self.remember(inode);
+        inode
+    }
+    fn commit_inode(&mut self, commit_ref: &GittyCommitRef) -> Inode {
+        let hashed = commit_hashed_inode(commit_ref);
+        self.shown(|inodes| &mut inodes.commits, commit_ref, hashed)
+    }
+    fn date_to_inode(&mut self, date: Vec<u32>) -> Inode {
+        let hashed = date_hashed_inode(&date);
+        let inode = self.register(|inodes| &mut inodes.dates, &date, hashed);
+        self.remember(inode);
+        inode
+    }
+    fn date_inode(&mut self, date: &[u32]) -> Inode {
+        let hashed = date_hashed_inode(date);
+        self.shown(|inodes| &mut inodes.dates, &date.to_vec(), hashed)
+    }
+    fn path_to_inode(&mut self, path: Vec<OsString>) -> Inode {
+        let hashed = path_hashed_inode(&path);
+        let inode = self.register(|inodes| &mut inodes.paths, &path, hashed);
+        self.remember(inode);
+        inode
+    }
+    fn path_inode(&mut self, path: &[OsString]) -> Inode {
+        let hashed = path_hashed_inode(path);
+        self.shown(|inodes| &mut inodes.paths, &path.to_vec(), hashed)
+    }
+    fn commit_info_to_inode(&mut self, commit_ref: &GittyCommitRef) -> Inode {
+        let hashed = commit_info_hashed_inode(commit_ref);
+        let inode = self.register(|inodes| &mut inodes.commit_infos, commit_ref, hashed);
+        self.remember(inode);
         inode
     }
-    fn inode_to_tree(&self, inode: Inode) -> Option<Cow<GittyTreeEntry>> {
-        self.inode_trees_blobs
+    fn commit_info_inode(&mut self, commit_ref: &GittyCommitRef) -> Inode {
+        let hashed = commit_info_hashed_inode(commit_ref);
+        self.shown(|inodes| &mut inodes.commit_infos, commit_ref, hashed)
+    }
+    fn commit_link_to_inode(&mut self, commit_ref: &GittyCommitRef) -> Inode {
+        let hashed = commit_link_hashed_inode(commit_ref);
+        let inode = self.register(|inodes| &mut inodes.commit_links, commit_ref, hashed);
+        self.remember(inode);
+        inode
+    }
+    fn commit_link_inode(&mut self, commit_ref: &GittyCommitRef) -> Inode {
+        let hashed = commit_link_hashed_inode(commit_ref);
+        self.shown(|inodes| &mut inodes.commit_links, commit_ref, hashed)
+    }
+    // by-date or history directory with the inode
+    fn virtual_dir(&self, inode: Inode) -> Option<VirtualEntry> {
+        if let Some(date) = self.dates.get_by_left(&inode) {
+            return Some(VirtualEntry::Date(date.clone()));
+        }
+        self.paths
             .get_by_left(&inode)
-            .and_then(|(r, n, _)| self.trees.get(r).map(|p| (p, n.as_ref())))
-            .and_then(|(a, b)| find_tree_entry(a, b))
-            .map(|p| Cow::Borrowed(p))
-            .or_else(|| {
-                self.inode_commits
-                    .get_by_left(&inode)
-                    .and_then(|r| self.commits.get(r))
-                    .and_then(|c| {
-                        Some(Cow::Owned(GittyTreeEntry::Tree(GittyTreeMetadata {
-                            hash: c.root.clone(),
-                            name: OsStr::new(&commit_fname(&c)).to_owned(),
-                            modified: c.commit_time.with_timezone(&Utc),
-                            permissions: Permissions {
-                                kind: "unix".to_owned(),
-                                mode: 0o755,
-                                uid: 0,
-                                gid: 0,
-                            },
-                        })))
+            .map(|path| VirtualEntry::Path(path.clone()))
+    }
+    fn is_free(&self, hashed: Inode) -> bool {
+        hashed >= RESERVED_INODES
+            && !self.trees_blobs.contains_left(&hashed)
+            && !self.commits.contains_left(&hashed)
+            && !self.dates.contains_left(&hashed)
+            && !self.paths.contains_left(&hashed)
+            && !self.commit_infos.contains_left(&hashed)
+            && !self.commit_links.contains_left(&hashed)
+    }
+    // the hashed inode unless it is already used for something else
+    fn free_inode(&mut self, hashed: Inode) -> Inode {
+        if self.is_free(hashed) {
+            return hashed;
+        }
+        self.max += 1;
+        self.fallbacks.insert(self.max, hashed);
+        self.max
+    }
+    // the kernel got one more reference to the inode
+    fn remember(&mut self, inode: Inode) {
+        *self.lookups.entry(inode).or_insert(0) += 1;
+    }
+    fn forget(&mut self, inode: Inode, nlookup: u64) {
+        let remaining = match self.lookups.get_mut(&inode) {
+            Some(count) => {
+                *count = count.saturating_sub(nlookup);
+                *count
+            }
+            None => return,
+        };
+        if remaining == 0 {
+            self.lookups.remove(&inode);
+            self.trees_blobs.remove_by_left(&inode);
+            self.locations.remove(&inode);
+            self.fallbacks.remove(&inode);
+            self.commits.remove_by_left(&inode);
+            self.dates.remove_by_left(&inode);
+            self.paths.remove_by_left(&inode);
+            self.commit_infos.remove_by_left(&inode);
+            self.commit_links.remove_by_left(&inode);
+        }
+    }
+}
+
+// the object is loaded without holding the lock, so other threads can use the cache meanwhile
+fn cached<K: Clone + Ord, V>(
+    cache: &Mutex<LruCache<K, Arc<V>>>,
+    key: &K,
+    load: impl FnOnce() -> Option<V>,
+) -> Option<Arc<V>> {
+    if let Some(value) = cache.lock().unwrap().get(key) {
+        return Some(value.clone());
+    }
+    let value = Arc::new(load()?);
+    cache.lock().unwrap().insert(key.clone(), value.clone());
+    Some(value)
+}
+
+// with size 0 the caller asks how large the value is
+fn reply_xattr(value: &[u8], size: u32, reply: ReplyXattr) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if (size as usize) < value.len() {
+        reply.error(ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
+
+impl<'a> GittyViewer<'a> {
+    fn new(db: &'a (dyn GittyDatabase + Sync), limits: &CacheLimits) -> GittyViewer<'a> {
+        GittyViewer {
+            db,
+            inodes: Mutex::new(Inodes::new()),
+            root_mtime: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap(),
+            trees: Mutex::new(LruCache::with_capacity(limits.trees)),
+            commits: Mutex::new(LruCache::with_capacity(limits.commits)),
+            history: Mutex::new(None),
+            stats: Mutex::new(None),
+            path_histories: Mutex::new(LruCache::with_capacity(limits.history_entries.max(1))),
+            history_entries: limits.history_entries,
+            file_handles: Mutex::new(HashMap::new()),
+            fh_max: AtomicU64::new(0),
+        }
+    }
+    fn get_tree(&self, r: &GittyTreeRef) -> Option<Arc<GittyTree>> {
+        cached(&self.trees, r, || self.db.load_tree(r).ok())
+    }
+    fn get_commit(&self, r: &GittyCommitRef) -> Option<Arc<GittyCommit>> {
+        cached(&self.commits, r, || self.db.load_commit(r).ok())
+    }
+    fn inode_to_tree(&self, inode: Inode) -> Option<GittyTreeEntry> {
+        let (location, commit_ref) = {
+            let inodes = self.inodes.lock().unwrap();
+            (
+                inodes.locations.get(&inode).cloned(),
+                inodes.commits.get_by_left(&inode).cloned(),
+            )
+        };
+        if let Some((tree_ref, name)) = location {
+            return self
+                .get_tree(&tree_ref)
+                .and_then(|tree| find_tree_entry(&tree, &name).cloned());
+        }
+        self.get_commit(&commit_ref?).map(|c| commit_entry(&c))
+    }
+    // tree shown as the directory with the inode
+    fn dir_tree(&self, inode: Inode) -> Result<GittyTreeRef, c_int> {
+        let (key, commit_ref) = {
+            let inodes = self.inodes.lock().unwrap();
+            (
+                inodes.trees_blobs.get_by_left(&inode).cloned(),
+                inodes.commits.get_by_left(&inode).cloned(),
+            )
+        };
+        match (key, commit_ref) {
+            (Some(EntryKey::Tree(_, _, _, t)), _) => Ok(t),
+            (Some(EntryKey::Blob(..)), _) => Err(ENOTDIR),
+            (None, Some(commit_ref)) => match self.get_commit(&commit_ref) {
+                Some(commit) => Ok(GittyTreeRef {
+                    hash: commit.root.clone(),
+                }),
+                None => Err(EIO),
+            },
+            (None, None) => Err(ENOENT),
+        }
+    }
+    fn history(&self) -> Result<Arc<History>, c_int> {
+        let head = self.db.get_head_commit().map_err(|e| {
+            eprintln!("get_head: {:?}", GittyError::from(e));
+            EIO
+        })?;
+        if let Some(ref history) = *self.history.lock().unwrap() {
+            if history.head == head {
+                return Ok(history.clone());
+            }
+        }
+        // read from the commit graph, the commits themselves are loaded when looked up
+        let commits = self.db.commit_graph().map_err(|e| {
+            eprintln!("commit_graph: {:?}", GittyError::from(e));
+            EIO
+        })?;
+        // the path histories of the old HEAD are not used again, and the new ones are as long
+        // as the new history
+        let capacity = self.history_entries / commits.len().max(1);
+        *self.path_histories.lock().unwrap() = LruCache::with_capacity(capacity.max(1));
+        let history = Arc::new(History::new(head, commits));
+        *self.history.lock().unwrap() = Some(history.clone());
+        Ok(history)
+    }
+    // name of the HEAD commit in the root
+    fn latest_target(&self) -> Result<String, c_int> {
+        match self.history()?.commits.first() {
+            Some(head) => Ok(head.name.clone()),
+            None => Err(ENOENT),
+        }
+    }
+    // target of latest and the by-date symlinks, None for other inodes
+    fn link_target(&self, inode: Inode) -> Option<Result<String, c_int>> {
+        if inode == LATEST_INODE {
+            return Some(self.latest_target());
+        }
+        let commit_ref = self
+            .inodes
+            .lock()
+            .unwrap()
+            .commit_links
+            .get_by_left(&inode)?
+            .clone();
+        Some(self.history().and_then(|history| {
+            history
+                .commits
+                .iter()
+                .find(|c| c.commit_ref == commit_ref)
+                // from by-date/<year>/<month>/<day>
+                .map(|c| format!("../../../../{}", c.name))
+                .ok_or(ENOENT)
+        }))
+    }
+    fn stats(&self) -> Result<Arc<RepoStats>, c_int> {
+        let head = self.db.get_head_commit().map_err(|e| {
+            eprintln!("get_head: {:?}", GittyError::from(e));
+            EIO
+        })?;
+        if let Some(ref stats) = *self.stats.lock().unwrap() {
+            if stats.head == head {
+                return Ok(stats.clone());
+            }
+        }
+        let storage = self.db.storage_stats().map_err(|e| {
+            eprintln!("storage_stats: {:?}", GittyError::from(e));
+            EIO
+        })?;
+        let stats = Arc::new(RepoStats { head, storage });
+        *self.stats.lock().unwrap() = Some(stats.clone());
+        Ok(stats)
+    }
+    // content of .gitty/stats.json
+    fn stats_json(&self) -> Result<Vec<u8>, c_int> {
+        let history = self.history()?;
+        let stats = self.stats()?;
+        let snapshots: Vec<SnapshotStats> = history
+            .commits
+            .iter()
+            .map(|entry| SnapshotStats {
+                name: entry.name.clone(),
+                commit: entry.commit_ref.hash.to_string(),
+                time: entry.commit_time.to_rfc3339(),
+                files: entry.files,
+                bytes: entry.bytes,
+            })
+            .collect();
+        let snapshot_bytes = snapshots.iter().map(|s| s.bytes).sum();
+        let dedup_ratio = if stats.storage.bytes() > 0 {
+            snapshot_bytes as f64 / stats.storage.bytes() as f64
+        } else {
+            0.0
+        };
+        let mut json = serde_json::to_vec_pretty(&StatsFile {
+            storage: &stats.storage,
+            snapshot_bytes,
+            dedup_ratio,
+            snapshots,
+        })
+        .map_err(|e| {
+            eprintln!("stats.json: {}", e);
+            EIO
+        })?;
+        json.push(b'\n');
+        Ok(json)
+    }
+    // built from the history of the parent path, so each commit's tree is only searched for
+    // the last name
+    fn path_history(&self, history: &History, path: &[OsString]) -> Option<PathHistory> {
+        let (parent_dirs, name) = match path.split_last() {
+            Some((name, parent)) => (
+                self.cached_path_history(history, parent)?.dirs.clone(),
+                Some(name),
+            ),
+            None => {
+                let roots = history.commits.iter().rev().map(|c| {
+                    Some(GittyTreeRef {
+                        hash: c.root.clone(),
                     })
+                });
+                (roots.collect(), None)
+            }
+        };
+        let mut result = PathHistory {
+            versions: vec![],
+            children: BTreeSet::new(),
+            dirs: Vec::with_capacity(parent_dirs.len()),
+        };
+        // most commits share their trees with the previous one
+        let mut found: HashMap<GittyTreeRef, Option<GittyTreeEntry>> = HashMap::new();
+        let mut seen_versions = HashSet::new();
+        let mut seen_dirs = HashSet::new();
+        for (commit, parent) in history.commits.iter().rev().zip(parent_dirs) {
+            // the path in this commit, as entry of its parent and as directory
+            let (version, dir) = match (name, parent) {
+                (Some(name), Some(parent)) => {
+                    if !found.contains_key(&parent) {
+                        let tree = self.get_tree(&parent)?;
+                        found.insert(parent.clone(), find_tree_entry(&tree, name).cloned());
+                    }
+                    let dir = match found[&parent] {
+                        Some(GittyTreeEntry::Tree(ref t)) => Some(GittyTreeRef {
+                            hash: t.hash.clone(),
+                        }),
+                        _ => None,
+                    };
+                    (found[&parent].clone().map(|entry| (parent, entry)), dir)
+                }
+                (None, root) => (None, root),
+                (Some(_), None) => (None, None),
+            };
+            if let Some((parent, entry)) = version {
+                if seen_versions.insert(entry_hash(&entry).clone()) {
+                    result
+                        .versions
+                        .push((OsString::from(&commit.name), parent, entry));
+                }
+            }
+            if let Some(ref dir) = dir {
+                if seen_dirs.insert(dir.clone()) {
+                    let tree = self.get_tree(dir)?;
+                    result
+                        .children
+                        .extend(tree.entries.iter().map(|e| entry_name(e).clone()));
+                }
+            }
+            result.dirs.push(dir);
+        }
+        Some(result)
+    }
+    fn cached_path_history(
+        &self,
+        history: &History,
+        path: &[OsString],
+    ) -> Option<Arc<PathHistory>> {
+        let key = (history.head.clone(), path.to_vec());
+        cached(&self.path_histories, &key, || {
+            self.path_history(history, path)
+        })
+    }
+    // subdirectories for the names below the path, then its versions
+    fn path_entries(&self, path: &[OsString]) -> Result<Vec<(OsString, VirtualEntry)>, c_int> {
+        let history = self.history()?;
+        let path_history = self.cached_path_history(&history, path).ok_or(EIO)?;
+        let mut entries: Vec<(OsString, VirtualEntry)> = path_history
+            .children
+            .iter()
+            .map(|name| {
+                let mut child = path.to_vec();
+                child.push(name.clone());
+                (name.clone(), VirtualEntry::Path(child))
             })
+            .collect();
+        entries.extend(path_history.versions.iter().map(|(name, parent, entry)| {
+            (
+                name.clone(),
+                VirtualEntry::Version(parent.clone(), entry.clone()),
+            )
+        }));
+        Ok(entries)
+    }
+    // entries of the root, the by-date and the history directories, None for other inodes
+    fn virtual_entries(
+        &self,
+        inode: Inode,
+    ) -> Option<Result<Vec<(OsString, VirtualEntry)>, c_int>> {
+        if inode == GITTY_DIR_INODE {
+            return Some(Ok(vec![(
+                OsString::from("stats.json"),
+                VirtualEntry::Fixed(STATS_INODE, FileType::RegularFile),
+            )]));
+        }
+        let dir = match inode {
+            FUSE_ROOT_ID => None,
+            BY_DATE_INODE => Some(VirtualEntry::Date(vec![])),
+            HISTORY_INODE => Some(VirtualEntry::Path(vec![])),
+            _ => Some(self.inodes.lock().unwrap().virtual_dir(inode)?),
+        };
+        if let Some(VirtualEntry::Path(path)) = dir {
+            return Some(self.path_entries(&path));
+        }
+        let history = match self.history() {
+            Ok(history) => history,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(Ok(match dir {
+            Some(VirtualEntry::Date(date)) => history.date_entries(&date),
+            _ => {
+                let mut entries = vec![
+                    (
+                        OsString::from(".gitty"),
+                        VirtualEntry::Fixed(GITTY_DIR_INODE, FileType::Directory),
+                    ),
+                    (
+                        OsString::from("by-date"),
+                        VirtualEntry::Fixed(BY_DATE_INODE, FileType::Directory),
+                    ),
+                    (
+                        OsString::from("history"),
+                        VirtualEntry::Fixed(HISTORY_INODE, FileType::Directory),
+                    ),
+                ];
+                // nothing to point to before the first snapshot
+                if !history.commits.is_empty() {
+                    entries.push((
+                        OsString::from("latest"),
+                        VirtualEntry::Fixed(LATEST_INODE, FileType::Symlink),
+                    ));
+                }
+                entries.extend(history.commits.iter().map(|c| {
+                    (
+                        OsString::from(&c.name),
+                        VirtualEntry::Commit(c.commit_ref.clone()),
+                    )
+                }));
+                entries
+            }
+        }))
+    }
+    fn virtual_lookup(&self, parent: Inode, name: &OsStr) -> Option<Result<VirtualEntry, c_int>> {
+        if parent != FUSE_ROOT_ID {
+            let entries = match self.virtual_entries(parent)? {
+                Ok(entries) => entries,
+                Err(e) => return Some(Err(e)),
+            };
+            return Some(
+                entries
+                    .into_iter()
+                    .find(|(n, _)| n == name)
+                    .map(|(_, entry)| entry)
+                    .ok_or(ENOENT),
+            );
+        }
+        if name == "by-date" {
+            return Some(Ok(VirtualEntry::Fixed(BY_DATE_INODE, FileType::Directory)));
+        }
+        if name == ".gitty" {
+            return Some(Ok(VirtualEntry::Fixed(
+                GITTY_DIR_INODE,
+                FileType::Directory,
+            )));
+        }
+        if name == "history" {
+            return Some(Ok(VirtualEntry::Fixed(HISTORY_INODE, FileType::Directory)));
+        }
+        if name == "latest" {
+            return Some(Ok(VirtualEntry::Fixed(LATEST_INODE, FileType::Symlink)));
+        }
+        Some(self.history().and_then(|history| {
+            history
+                .by_name
+                .get(name)
+                .map(|&i| VirtualEntry::Commit(history.commits[i].commit_ref.clone()))
+                .ok_or(ENOENT)
+        }))
+    }
+    // content of stats.json and the .gitty-commit.json files, None for other inodes
+    fn virtual_file(&self, inode: Inode) -> Option<Result<Vec<u8>, c_int>> {
+        if inode == STATS_INODE {
+            return Some(self.stats_json());
+        }
+        let commit_ref = self
+            .inodes
+            .lock()
+            .unwrap()
+            .commit_infos
+            .get_by_left(&inode)?
+            .clone();
+        let commit = match self.get_commit(&commit_ref) {
+            Some(commit) => commit,
+            None => return Some(Err(EIO)),
+        };
+        let info = CommitInfo {
+            hash: &commit_ref.hash,
+            commit: &commit,
+        };
+        let json = serde_json::to_vec_pretty(&info).map(|mut json| {
+            json.push(b'\n');
+            json
+        });
+        Some(json.map_err(|e| {
+            eprintln!("{}: {}", COMMIT_INFO_NAME, e);
+            EIO
+        }))
+    }
+    // attributes of the root, the by-date, history and .gitty directories, latest and the
+    // virtual files, None for other inodes
+    fn virtual_attr(&self, inode: Inode, uid: u32, gid: u32) -> Option<Result<FileAttr, c_int>> {
+        let time = Timespec {
+            sec: self.root_mtime.as_secs() as i64,
+            nsec: self.root_mtime.subsec_nanos() as i32,
+        };
+        let dir = FileAttr {
+            ino: inode,
+            atime: time,
+            mtime: time,
+            ctime: time,
+            crtime: time,
+            gid,
+            uid,
+            perm: 0o755,
+            kind: FileType::Directory,
+            ..STD_ATTR
+        };
+        if let Some(content) = self.virtual_file(inode) {
+            return Some(content.map(|content| FileAttr {
+                size: content.len() as u64,
+                blocks: (content.len() as u64).div_ceil(512),
+                perm: 0o444,
+                kind: FileType::RegularFile,
+                ..dir
+            }));
+        }
+        if let Some(target) = self.link_target(inode) {
+            return Some(target.map(|target| FileAttr {
+                size: target.len() as u64,
+                perm: 0o777,
+                kind: FileType::Symlink,
+                ..dir
+            }));
+        }
+        match inode {
+            FUSE_ROOT_ID | BY_DATE_INODE | HISTORY_INODE | GITTY_DIR_INODE => Some(Ok(dir)),
+            _ if self.inodes.lock().unwrap().virtual_dir(inode).is_some() => Some(Ok(dir)),
+            _ => None,
+        }
     }
 
     fn entry_to_attr(entry: &GittyTreeEntry, ino: Inode) -> FileAttr {
@@ -249,84 +1186,127 @@ impl<'a> GittyViewer<'a> {
             }
         }
     }
-}
 
-const GENERATION: u64 = 0;
-impl<'a> Filesystem for GittyViewer<'a> {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let tree_ref = {
-            if parent == FUSE_ROOT_ID {
-                if let Some(commit_ref) = self.root_map.get(name).map(|p| p.clone()) {
-                    let c = self.commits.get(&commit_ref).unwrap().clone();
-                    let ino = self.commit_to_inode(Cow::Owned(commit_ref), Cow::Owned(c));
-                    if let Some(entry) = self.inode_to_tree(ino) {
-                        let attr = GittyViewer::entry_to_attr(&entry, ino);
-                        reply.entry(&TTL, &attr, GENERATION);
-                        return;
-                    }
-                    reply.error(ENOENT);
-                    return;
-                //GittyTreeRef { self.commits.get(commit_ref).unwrap().root }
-                } else {
-                    reply.error(ENOENT);
-                    return;
-                }
-            } else if let Some((_, _, hash)) = self.inode_trees_blobs.get_by_left(&parent) {
-                if let OwnedGittyObjectRef::Tree(t) = hash {
-                    t.clone()
-                } else {
-                    panic!("not a dir");
-                }
-            } else if let Some(x) = self.inode_commits.get_by_left(&parent) {
-                let hash = self.commits.get(x).unwrap().root.clone();
-                GittyTreeRef { hash }
-            } else {
-                reply.error(ENOENT);
+    fn lookup(&self, parent: Inode, name: &OsStr, uid: u32, gid: u32, reply: ReplyEntry) {
+        match self.virtual_lookup(parent, name) {
+            Some(Ok(entry)) => {
+                self.lookup_virtual(parent, entry, uid, gid, reply);
+                return;
+            }
+            Some(Err(e)) => {
+                reply.error(e);
+                return;
+            }
+            None => {}
+        }
+        let tree_ref = match self.dir_tree(parent) {
+            Ok(tree_ref) => tree_ref,
+            Err(e) => {
+                reply.error(e);
                 return;
             }
         };
-        let (tree, entry) = {
-            if let Some(tree) = self.get_tree(&tree_ref) {
-                if let Some(entry) = find_tree_entry(&tree, name) {
-                    (tree.clone(), entry.clone())
-                } else {
-                    reply.error(ENOENT);
-                    return;
+        let entry = match self
+            .get_tree(&tree_ref)
+            .and_then(|tree| find_tree_entry(&tree, name).cloned())
+        {
+            Some(entry) => entry,
+            None => {
+                let commit_ref = self
+                    .inodes
+                    .lock()
+                    .unwrap()
+                    .commits
+                    .get_by_left(&parent)
+                    .cloned();
+                match commit_ref {
+                    Some(commit_ref) if name == COMMIT_INFO_NAME => {
+                        let entry = VirtualEntry::CommitInfo(commit_ref);
+                        self.lookup_virtual(parent, entry, uid, gid, reply)
+                    }
+                    _ => reply.error(ENOENT),
                 }
-            } else {
-                reply.error(ENOENT);
                 return;
             }
         };
-        let ino = self.tree_ele_to_inode(&tree, &tree_ref, name);
+        let ino = self
+            .inodes
+            .lock()
+            .unwrap()
+            .entry_to_inode(parent, &tree_ref, &entry);
         let attr = GittyViewer::entry_to_attr(&entry, ino);
         reply.entry(&TTL, &attr, GENERATION);
-        return;
     }
 
-    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
-        if ino == FUSE_ROOT_ID {
-            let time = Timespec {
-                sec: self.root_mtime.as_secs() as i64,
-                nsec: self.root_mtime.subsec_nanos() as i32,
-            };
+    // entry of the directory parent
+    fn lookup_virtual(
+        &self,
+        parent: Inode,
+        entry: VirtualEntry,
+        uid: u32,
+        gid: u32,
+        reply: ReplyEntry,
+    ) {
+        let ino = match entry {
+            VirtualEntry::Fixed(ino, _) => ino,
+            VirtualEntry::Date(date) => self.inodes.lock().unwrap().date_to_inode(date),
+            VirtualEntry::CommitLink(commit_ref) => self
+                .inodes
+                .lock()
+                .unwrap()
+                .commit_link_to_inode(&commit_ref),
+            VirtualEntry::Path(path) => self.inodes.lock().unwrap().path_to_inode(path),
+            VirtualEntry::CommitInfo(commit_ref) => self
+                .inodes
+                .lock()
+                .unwrap()
+                .commit_info_to_inode(&commit_ref),
+            VirtualEntry::Version(tree_ref, entry) => {
+                let ino = self
+                    .inodes
+                    .lock()
+                    .unwrap()
+                    .entry_to_inode(parent, &tree_ref, &entry);
+                let attr = GittyViewer::entry_to_attr(&entry, ino);
+                reply.entry(&TTL, &attr, GENERATION);
+                return;
+            }
+            VirtualEntry::Commit(commit_ref) => {
+                let entry = match self.get_commit(&commit_ref) {
+                    Some(commit) => commit_entry(&commit),
+                    None => {
+                        reply.error(EIO);
+                        return;
+                    }
+                };
+                let ino = self.inodes.lock().unwrap().commit_to_inode(&commit_ref);
+                let attr = GittyViewer::entry_to_attr(&entry, ino);
+                reply.entry(&TTL, &attr, GENERATION);
+                return;
+            }
+        };
+        match self.virtual_attr(ino, uid, gid) {
+            Some(Ok(attr)) => reply.entry(&VIRTUAL_TTL, &attr, GENERATION),
+            Some(Err(e)) => reply.error(e),
+            None => reply.error(ENOENT),
+        }
+    }
 
-            reply.attr(
-                &TTL,
-                &FileAttr {
-                    ino: FUSE_ROOT_ID,
-                    atime: time,
-                    mtime: time,
-                    ctime: time,
-                    crtime: time,
-                    gid: req.gid(),
-                    uid: req.uid(),
-                    perm: 0o755,
-                    kind: FileType::Directory,
-                    ..STD_ATTR
-                },
-            );
-            return;
+    fn forget(&self, ino: Inode, nlookup: u64) {
+        self.inodes.lock().unwrap().forget(ino, nlookup);
+    }
+
+    fn getattr(&self, ino: Inode, uid: u32, gid: u32, reply: ReplyAttr) {
+        match self.virtual_attr(ino, uid, gid) {
+            Some(Ok(attr)) => {
+                reply.attr(&VIRTUAL_TTL, &attr);
+                return;
+            }
+            Some(Err(e)) => {
+                reply.error(e);
+                return;
+            }
+            None => {}
         }
         if let Some(entry) = self.inode_to_tree(ino) {
             let attr = GittyViewer::entry_to_attr(&entry, ino);
@@ -336,128 +1316,261 @@ impl<'a> Filesystem for GittyViewer<'a> {
         reply.error(ENOENT);
     }
 
-    // TODO: implement proper state based i/o
-    fn read(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        size: u32,
-        reply: ReplyData,
-    ) {
-        use lru_time_cache::Entry;
-
-        let mut f = match self.blob_read_cache.entry(ino) {
-            Entry::Occupied(o) => o.into_mut(),
-            Entry::Vacant(v) => {
-                if let Some((_, _, hash)) = self.inode_trees_blobs.get_by_left(&ino) {
-                    match hash {
-                        OwnedGittyObjectRef::Tree(t) => {
-                            reply.error(EISDIR);
-                            return;
-                        }
-                        OwnedGittyObjectRef::Commit(t) => {
-                            reply.error(EINVAL);
-                            return;
-                        }
-                        OwnedGittyObjectRef::Blob(blob_ref) => {
-                            if let Ok(path) = self.db.load_blob(blob_ref) {
-                                v.insert(File::open(path).unwrap())
-                            } else {
-                                reply.error(EIO);
-                                return;
-                            }
-                        }
-                    }
-                } else {
-                    reply.error(ENOENT);
-                    return;
+    fn open(&self, ino: Inode, flags: u32, reply: ReplyOpen) {
+        match self.open_file(ino, flags) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    // the new file handle
+    fn open_file(&self, ino: Inode, flags: u32) -> Result<u64, c_int> {
+        if flags as i32 & O_ACCMODE != O_RDONLY {
+            return Err(EROFS);
+        }
+        if let Some(content) = self.virtual_file(ino) {
+            return Ok(self.add_file_handle(Box::new(Cursor::new(content?))));
+        }
+        let blob_ref = {
+            let inodes = self.inodes.lock().unwrap();
+            match inodes.trees_blobs.get_by_left(&ino) {
+                Some(EntryKey::Blob(blob_ref, _)) => blob_ref.clone(),
+                Some(EntryKey::Tree(..)) => return Err(EISDIR),
+                None if ino < RESERVED_INODES
+                    || inodes.commits.contains_left(&ino)
+                    || inodes.virtual_dir(ino).is_some() =>
+                {
+                    return Err(EISDIR)
                 }
+                None => return Err(ENOENT),
             }
         };
-        f.seek(SeekFrom::Start(offset as u64)).unwrap();
+        // every open gets its own reader, so readers of the same file don't share a position
+        match self.db.load_blob(&blob_ref) {
+            Ok(reader) => Ok(self.add_file_handle(reader)),
+            Err(e) => {
+                eprintln!("open {}: {:?}", blob_ref.hash, GittyError::from(e));
+                Err(EIO)
+            }
+        }
+    }
+
+    fn add_file_handle(&self, reader: BlobReader) -> u64 {
+        let fh = self.fh_max.fetch_add(1, Ordering::Relaxed) + 1;
+        self.file_handles
+            .lock()
+            .unwrap()
+            .insert(fh, Arc::new(Mutex::new(reader)));
+        fh
+    }
+
+    fn read(&self, fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        match self.read_file(fh, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    // up to size bytes at offset, less only at the end of the file
+    fn read_file(&self, fh: u64, offset: i64, size: u32) -> Result<Vec<u8>, c_int> {
+        if offset < 0 {
+            return Err(EINVAL);
+        }
+        let reader = match self.file_handles.lock().unwrap().get(&fh) {
+            Some(reader) => reader.clone(),
+            None => return Err(EBADF),
+        };
         let mut buf = vec![0; size as usize];
-        match f.read(&mut buf[..]) {
+        let result = reader.lock().unwrap().read_at(offset as u64, &mut buf[..]);
+        match result {
             Ok(size) => {
-                reply.data(&buf[0..size]);
-                return;
+                buf.truncate(size);
+                Ok(buf)
             }
             Err(e) => {
                 eprintln!("read: {:?}", e);
-                reply.error(EIO);
-                return;
+                Err(EIO)
             }
         }
     }
 
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        if ino == FUSE_ROOT_ID {
-            match self.db.get_head_commit() {
-                Ok(head) => {
-                    for (i, commit) in walk_commits(self.db, head)
-                        .enumerate()
-                        .skip(offset as usize)
-                    {
-                        match commit {
-                            Ok((commit_ref, commit)) => {
-                                //let r = GittyTreeRef { hash: commit.root };
-                                //let root = GittyObjectRef::Tree(&r);
-                                let fname = commit_fname(&commit);
-                                self.root_map
-                                    .insert(OsStr::new(&fname).to_owned(), commit_ref.clone());
-                                let inode = self
-                                    .commit_to_inode(Cow::Owned(commit_ref), Cow::Owned(commit));
-                                let full =
-                                    reply.add(inode, (i + 1) as i64, FileType::Directory, fname);
-                                if full {
-                                    reply.ok();
-                                    return;
-                                }
-                            }
-                            Err(e) => {
-                                eprintln!("walk_commits: {:?}", e);
-                                reply.error(EIO);
-                                return;
-                            }
-                        };
-                    }
-                    reply.ok();
+    fn release(&self, fh: u64, reply: ReplyEmpty) {
+        self.file_handles.lock().unwrap().remove(&fh);
+        reply.ok();
+    }
+
+    // user.gitty.hash of commit directories, user.gitty.tree_hash of directories and
+    // user.gitty.blob_hash of files, so they can be mapped back to the objects
+    fn xattrs(&self, ino: Inode) -> Vec<(&'static str, String)> {
+        let (key, commit_ref) = {
+            let inodes = self.inodes.lock().unwrap();
+            (
+                inodes.trees_blobs.get_by_left(&ino).cloned(),
+                inodes.commits.get_by_left(&ino).cloned(),
+            )
+        };
+        match (key, commit_ref) {
+            (Some(EntryKey::Tree(_, _, _, t)), _) => {
+                vec![("user.gitty.tree_hash", t.hash.to_string())]
+            }
+            (Some(EntryKey::Blob(b, _)), _) => {
+                vec![("user.gitty.blob_hash", b.hash.to_string())]
+            }
+            (_, Some(commit_ref)) => {
+                let mut xattrs = vec![("user.gitty.hash", commit_ref.hash.to_string())];
+                if let Some(commit) = self.get_commit(&commit_ref) {
+                    xattrs.push(("user.gitty.tree_hash", commit.root.to_string()));
                 }
+                xattrs
+            }
+            _ => vec![],
+        }
+    }
+
+    fn getxattr(&self, ino: Inode, name: &OsStr, size: u32, reply: ReplyXattr) {
+        match self.xattrs(ino).into_iter().find(|(n, _)| name == *n) {
+            Some((_, value)) => reply_xattr(value.as_bytes(), size, reply),
+            None => reply.error(ENODATA),
+        }
+    }
+
+    fn listxattr(&self, ino: Inode, size: u32, reply: ReplyXattr) {
+        let mut names = Vec::new();
+        for (name, _) in self.xattrs(ino) {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        reply_xattr(&names, size, reply);
+    }
+
+    fn statfs(&self, reply: ReplyStatfs) {
+        match self.stats() {
+            Ok(stats) => {
+                let blocks = stats.storage.bytes().div_ceil(BLOCK_SIZE);
+                // read-only, so nothing is free
+                reply.statfs(
+                    blocks,
+                    0,
+                    0,
+                    stats.storage.objects(),
+                    0,
+                    BLOCK_SIZE as u32,
+                    255,
+                    BLOCK_SIZE as u32,
+                );
+            }
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn readlink(&self, ino: Inode, reply: ReplyData) {
+        if let Some(target) = self.link_target(ino) {
+            match target {
+                Ok(target) => reply.data(target.as_bytes()),
+                Err(e) => reply.error(e),
+            }
+            return;
+        }
+        let blob_ref = match self.inode_to_tree(ino) {
+            // snapshots made before symlinks were stored have no target
+            Some(GittyTreeEntry::Blob(ref b)) if b.is_symlink && b.hash == PLACEHOLDER_HASH => {
+                reply.error(ENODATA);
+                return;
+            }
+            Some(GittyTreeEntry::Blob(ref b)) if b.is_symlink => GittyBlobRef {
+                hash: b.hash.clone(),
+            },
+            Some(_) => {
+                reply.error(EINVAL);
+                return;
+            }
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let mut target = Vec::new();
+        match self
+            .db
+            .load_blob(&blob_ref)
+            .map_err(GittyError::from)
+            .and_then(|mut r| r.read_to_end(&mut target).map_err(GittyError::from))
+        {
+            Ok(_) => reply.data(&target),
+            Err(e) => {
+                eprintln!("readlink: {:?}", e);
+                reply.error(EIO);
+            }
+        }
+    }
+
+    fn readdir(&self, ino: Inode, offset: i64, mut reply: ReplyDirectory) {
+        if let Some(entries) = self.virtual_entries(ino) {
+            let entries = match entries {
+                Ok(entries) => entries,
                 Err(e) => {
-                    eprintln!("get_head: {:?}", GittyError::from(e));
-                    reply.error(EIO);
+                    reply.error(e);
+                    return;
+                }
+            };
+            let dir = ino;
+            for (i, (name, entry)) in entries.into_iter().enumerate().skip(offset as usize) {
+                let (ino, kind) = match entry {
+                    VirtualEntry::Fixed(ino, kind) => (ino, kind),
+                    VirtualEntry::Date(date) => (
+                        self.inodes.lock().unwrap().date_inode(&date),
+                        FileType::Directory,
+                    ),
+                    VirtualEntry::Commit(commit_ref) => (
+                        self.inodes.lock().unwrap().commit_inode(&commit_ref),
+                        FileType::Directory,
+                    ),
+                    VirtualEntry::CommitLink(commit_ref) => (
+                        self.inodes.lock().unwrap().commit_link_inode(&commit_ref),
+                        FileType::Symlink,
+                    ),
+                    VirtualEntry::Path(path) => (
+                        self.inodes.lock().unwrap().path_inode(&path),
+                        FileType::Directory,
+                    ),
+                    VirtualEntry::Version(tree_ref, entry) => (
+                        self.inodes
+                            .lock()
+                            .unwrap()
+                            .entry_inode(dir, &tree_ref, &entry),
+                        entry_kind(&entry),
+                    ),
+                    VirtualEntry::CommitInfo(commit_ref) => (
+                        self.inodes.lock().unwrap().commit_info_inode(&commit_ref),
+                        FileType::RegularFile,
+                    ),
+                };
+                if reply.add(ino, (i + 1) as i64, kind, name) {
+                    break;
                 }
             }
+            reply.ok();
         } else {
-            let tree_ref = {
-                if let Some((_, _, tree_hash)) = self.inode_trees_blobs.get_by_left(&ino) {
-                    match tree_hash {
-                        OwnedGittyObjectRef::Tree(t) => t.clone(),
-                        _ => {
-                            reply.error(EINVAL);
-                            return;
-                        }
-                    }
-                } else if let Some(commit_ref) = self.inode_commits.get_by_left(&ino) {
-                    GittyTreeRef {
-                        hash: self.commits.get(commit_ref).unwrap().root.clone(),
-                    }
-                } else {
-                    reply.error(ENOENT);
+            let tree_ref = match self.dir_tree(ino) {
+                Ok(tree_ref) => tree_ref,
+                Err(e) => {
+                    reply.error(e);
+                    return;
+                }
+            };
+            let tree = match self.get_tree(&tree_ref) {
+                Some(tree) => tree,
+                None => {
+                    reply.error(EIO);
                     return;
                 }
             };
-            let tree = (*self.get_tree(&tree_ref).unwrap()).clone();
             for (i, entry) in tree.entries.iter().enumerate().skip(offset as usize) {
-                let ino = self.tree_entry_to_inode(&tree_ref, entry);
+                let ino = self
+                    .inodes
+                    .lock()
+                    .unwrap()
+                    .entry_inode(ino, &tree_ref, entry);
                 let (name, kind) = match entry {
                     GittyTreeEntry::Tree(t) => (t.name.clone(), FileType::Directory),
                     GittyTreeEntry::Blob(t) => (
@@ -475,24 +1588,970 @@ impl<'a> Filesystem for GittyViewer<'a> {
                     return;
                 }
             }
+            // after the snapshot's own files, which win if one has the same name
+            let commit_ref = self
+                .inodes
+                .lock()
+                .unwrap()
+                .commits
+                .get_by_left(&ino)
+                .cloned();
+            if let Some(commit_ref) = commit_ref {
+                let name = OsStr::new(COMMIT_INFO_NAME);
+                if offset as usize <= tree.entries.len() && find_tree_entry(&tree, name).is_none() {
+                    let info = self.inodes.lock().unwrap().commit_info_inode(&commit_ref);
+                    let offset = (tree.entries.len() + 1) as i64;
+                    reply.add(info, offset, FileType::RegularFile, name);
+                }
+            }
             reply.ok();
         }
     }
 }
 
+type Job<'a> = Box<dyn FnOnce(&GittyViewer<'a>) + Send + 'a>;
+
+// the kernel's requests are received on one thread and served by a pool of threads, so one
+// slow read doesn't block everything else. forget and release are cheap and done right away
+struct ThreadedViewer<'a> {
+    viewer: &'a GittyViewer<'a>,
+    jobs: Sender<Job<'a>>,
+}
+
+impl<'a> ThreadedViewer<'a> {
+    fn spawn(&self, job: impl FnOnce(&GittyViewer<'a>) + Send + 'a) {
+        self.jobs.send(Box::new(job)).expect("fuse workers died");
+    }
+}
+
+const GENERATION: u64 = 0;
+// reported by statfs, the stored bytes are counted in blocks of this size
+const BLOCK_SIZE: u64 = 4096;
+impl<'a> Filesystem for ThreadedViewer<'a> {
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let name = name.to_owned();
+        let (uid, gid) = (req.uid(), req.gid());
+        self.spawn(move |viewer| viewer.lookup(parent, &name, uid, gid, reply));
+    }
+
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        self.viewer.forget(ino, nlookup);
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        let (uid, gid) = (req.uid(), req.gid());
+        self.spawn(move |viewer| viewer.getattr(ino, uid, gid, reply));
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        self.spawn(move |viewer| viewer.open(ino, flags, reply));
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        reply: ReplyData,
+    ) {
+        self.spawn(move |viewer| viewer.read(fh, offset, size, reply));
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.viewer.release(fh, reply);
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        self.spawn(move |viewer| viewer.readlink(ino, reply));
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let name = name.to_owned();
+        self.spawn(move |viewer| viewer.getxattr(ino, &name, size, reply));
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        self.spawn(move |viewer| viewer.listxattr(ino, size, reply));
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        self.spawn(move |viewer| viewer.statfs(reply));
+    }
+
+    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, reply: ReplyDirectory) {
+        self.spawn(move |viewer| viewer.readdir(ino, offset, reply));
+    }
+}
+
+// serves requests until the file system is unmounted
+fn serve(
+    viewer: &GittyViewer,
+    threads: usize,
+    mountpoint: &str,
+    options: &[&OsStr],
+) -> std::io::Result<()> {
+    let (job_sender, job_receiver) = mpsc::channel::<Job>();
+    let job_receiver = Mutex::new(job_receiver);
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let job_receiver = &job_receiver;
+            scope.spawn(move || loop {
+                let job = match job_receiver.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => return, // unmounted
+                };
+                job(viewer);
+            });
+        }
+        // the sender is dropped with the ThreadedViewer when the session ends
+        fuse::mount(
+            ThreadedViewer {
+                viewer,
+                jobs: job_sender,
+            },
+            &mountpoint,
+            options,
+        )
+    })
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: fuse <database> <mountpoint> [--tree-cache <trees>] [--commit-cache <commits>] \
+         [--history-cache <entries>] [--threads <threads>]"
+    );
+    std::process::exit(2);
+}
+
+fn parse_limit(value: Option<String>) -> usize {
+    value
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(|| usage())
+}
+
 fn main() {
     env_logger::init();
-    let dbdir = env::args().nth(1).unwrap();
-    let mountpoint = env::args().nth(2).unwrap();
+    let mut args = env::args().skip(1);
+    let dbdir = args.next().unwrap_or_else(|| usage());
+    let mountpoint = args.next().unwrap_or_else(|| usage());
+    let mut limits = CacheLimits::default();
+    let mut threads = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tree-cache" => limits.trees = parse_limit(args.next()),
+            "--commit-cache" => limits.commits = parse_limit(args.next()),
+            "--history-cache" => limits.history_entries = parse_limit(args.next()),
+            "--threads" => threads = parse_limit(args.next()).max(1),
+            _ => usage(),
+        }
+    }
     let mut options: Vec<String> = ["-o", "ro", "-o", "auto_unmount", "-o"]
         .into_iter()
         .map(|p| String::from(*p))
         .collect();
 
-    let mut db = FSDatabase::create_or_open(Path::new(&dbdir)).unwrap_or_else(|m| {
-        panic!("{}", m);
-    });
+    let db =
+        FSDatabase::create_or_open(Path::new(&dbdir), KeySource::from_env()).unwrap_or_else(|m| {
+            panic!("{}", m);
+        });
     options.push(format!("fsname=gitty:{}", dbdir));
     let options = options.iter().map(|o| o.as_ref()).collect::<Vec<&OsStr>>();
-    fuse::mount(GittyViewer::new(&mut db), &mountpoint, &options).unwrap();
+    let viewer = GittyViewer::new(&db, &limits);
+    serve(&viewer, threads, &mountpoint, &options).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use gitty_backup_rs::database::memory_database::MemoryDatabase;
+    use std::io;
+    use std::io::Seek;
+    use std::io::SeekFrom;
+
+    fn permissions(mode: u32) -> Permissions {
+        Permissions {
+            kind: "unix".to_owned(),
+            mode,
+            uid: 1000,
+            gid: 100,
+        }
+    }
+
+    #[test]
+    fn blob_attr() {
+        let modified = Utc.timestamp(1_500_000_000, 123);
+        let entry = GittyTreeEntry::Blob(GittyBlobMetadata {
+            name: OsString::from("file"),
+            modified,
+            permissions: permissions(0o100640),
+            size: 4242,
+            is_symlink: false,
+            unstable: false,
+            hash: PLACEHOLDER_HASH,
+        });
+        let attr = GittyViewer::entry_to_attr(&entry, 17);
+        assert_eq!(attr.ino, 17);
+        assert_eq!(attr.kind, FileType::RegularFile);
+        assert_eq!(attr.size, 4242);
+        assert_eq!(attr.perm & 0o7777, 0o640);
+        assert_eq!((attr.uid, attr.gid), (1000, 100));
+        assert_eq!(attr.mtime, Timespec::new(1_500_000_000, 123));
+        assert_eq!(attr.nlink, 1);
+    }
+
+    #[test]
+    fn symlink_attr() {
+        let entry = GittyTreeEntry::Blob(GittyBlobMetadata {
+            name: OsString::from("link"),
+            modified: Utc.timestamp(0, 0),
+            permissions: permissions(0o120777),
+            size: 5,
+            is_symlink: true,
+            unstable: false,
+            hash: PLACEHOLDER_HASH,
+        });
+        let attr = GittyViewer::entry_to_attr(&entry, 2);
+        assert_eq!(attr.kind, FileType::Symlink);
+        // the length of the target
+        assert_eq!(attr.size, 5);
+    }
+
+    #[test]
+    fn tree_attr() {
+        let entry = GittyTreeEntry::Tree(GittyTreeMetadata {
+            name: OsString::from("dir"),
+            modified: Utc.timestamp(1_000, 0),
+            permissions: permissions(0o040755),
+            hash: PLACEHOLDER_HASH,
+        });
+        let attr = GittyViewer::entry_to_attr(&entry, 3);
+        assert_eq!(attr.kind, FileType::Directory);
+        assert_eq!(attr.size, 0);
+        assert_eq!(attr.perm & 0o7777, 0o755);
+        assert_eq!(attr.ctime, Timespec::new(1_000, 0));
+    }
+
+    fn file(name: &str, byte: u8) -> GittyTreeEntry {
+        GittyTreeEntry::Blob(GittyBlobMetadata {
+            name: OsString::from(name),
+            modified: Utc.timestamp(0, 0),
+            permissions: permissions(0o100644),
+            size: 0,
+            is_symlink: false,
+            unstable: false,
+            hash: GittyHash {
+                algorithm: HashAlgorithm::Blake3,
+                digest: [byte; 32],
+            },
+        })
+    }
+
+    #[test]
+    fn inodes_are_stable() {
+        let parent = GittyTreeRef {
+            hash: PLACEHOLDER_HASH,
+        };
+        let commit = GittyCommitRef {
+            hash: PLACEHOLDER_HASH,
+        };
+        let other_parent = GittyTreeRef {
+            hash: GittyHash {
+                algorithm: HashAlgorithm::Blake3,
+                digest: [9; 32],
+            },
+        };
+        let mut first = Inodes::new();
+        let a = first.entry_to_inode(FUSE_ROOT_ID, &parent, &file("a", 1));
+        let c = first.commit_to_inode(&commit);
+        assert!((RESERVED_INODES..FALLBACK_INODES).contains(&a));
+        assert_ne!(a, c);
+        assert_ne!(
+            a,
+            first.entry_to_inode(FUSE_ROOT_ID, &parent, &file("a", 2))
+        );
+        // an unchanged file in another snapshot keeps its inode
+        assert_eq!(a, first.entry_to_inode(c, &other_parent, &file("a", 1)));
+        // the same content with other attributes is another file
+        let mut changed = file("a", 1);
+        if let GittyTreeEntry::Blob(ref mut b) = changed {
+            b.permissions.mode = 0o100600;
+        }
+        assert_ne!(a, first.entry_to_inode(FUSE_ROOT_ID, &parent, &changed));
+        if let GittyTreeEntry::Blob(ref mut b) = changed {
+            b.permissions.mode = 0o100644;
+            b.modified = Utc.timestamp(1, 0);
+        }
+        assert_ne!(a, first.entry_to_inode(FUSE_ROOT_ID, &parent, &changed));
+
+        // another mount
+        let mut second = Inodes::new();
+        assert_eq!(second.entry_inode(FUSE_ROOT_ID, &parent, &file("a", 1)), a);
+        assert_eq!(
+            second.entry_to_inode(FUSE_ROOT_ID, &parent, &file("a", 1)),
+            a
+        );
+        assert_eq!(second.commit_inode(&commit), c);
+        assert_eq!(second.commit_to_inode(&commit), c);
+    }
+
+    #[test]
+    fn inode_collision_falls_back() {
+        let parent = GittyTreeRef {
+            hash: PLACEHOLDER_HASH,
+        };
+        let a = Inodes::new().entry_to_inode(FUSE_ROOT_ID, &parent, &file("a", 1));
+        let other = EntryKey::Tree(
+            FUSE_ROOT_ID,
+            parent.clone(),
+            OsString::from("other"),
+            parent.clone(),
+        );
+        let mut colliding = Inodes::new();
+        colliding.trees_blobs.insert(a, other);
+        // listed before it is looked up, with the inode lookup gives it
+        let listed = colliding.entry_inode(FUSE_ROOT_ID, &parent, &file("a", 1));
+        assert!(listed > FALLBACK_INODES);
+        assert_eq!(
+            colliding.entry_to_inode(FUSE_ROOT_ID, &parent, &file("a", 1)),
+            listed
+        );
+        assert_eq!(
+            colliding.entry_inode(FUSE_ROOT_ID, &parent, &file("a", 1)),
+            listed
+        );
+
+        // entries below a fallback directory are hashed as below the directory it collided
+        // with, not with the order of lookups in this mount
+        let commit = GittyCommitRef {
+            hash: PLACEHOLDER_HASH,
+        };
+        let dir = Inodes::new().commit_to_inode(&commit);
+        let sub = GittyTreeEntry::Tree(GittyTreeMetadata {
+            name: OsString::from("sub"),
+            modified: Utc.timestamp(0, 0),
+            permissions: permissions(0o40755),
+            hash: PLACEHOLDER_HASH,
+        });
+        let expected = Inodes::new().entry_to_inode(dir, &parent, &sub);
+        for earlier in 0..2 {
+            let mut inodes = Inodes::new();
+            inodes.max += earlier;
+            inodes.paths.insert(dir, vec![]);
+            let fallback = inodes.commit_to_inode(&commit);
+            assert_eq!(fallback, FALLBACK_INODES + earlier + 1);
+            assert_eq!(inodes.entry_to_inode(fallback, &parent, &sub), expected);
+        }
+    }
+
+    #[test]
+    fn forgotten_inodes_are_released() {
+        let parent = GittyTreeRef {
+            hash: PLACEHOLDER_HASH,
+        };
+        let mut inodes = Inodes::new();
+        let a = inodes.entry_to_inode(FUSE_ROOT_ID, &parent, &file("a", 1));
+        inodes.entry_to_inode(FUSE_ROOT_ID, &parent, &file("a", 1));
+        inodes.forget(a, 1);
+        assert!(inodes.trees_blobs.contains_left(&a));
+        inodes.forget(a, 1);
+        assert!(!inodes.trees_blobs.contains_left(&a));
+        assert!(inodes.lookups.is_empty());
+        // looked up again
+        assert_eq!(
+            inodes.entry_to_inode(FUSE_ROOT_ID, &parent, &file("a", 1)),
+            a
+        );
+    }
+
+    #[test]
+    fn evicted_objects_are_reloaded() {
+        let db = MemoryDatabase::new();
+        let first = db
+            .store_tree(GittyTree {
+                entries: vec![file("a", 1)],
+            })
+            .unwrap_or_else(|_| panic!());
+        let second = db
+            .store_tree(GittyTree {
+                entries: vec![file("b", 2)],
+            })
+            .unwrap_or_else(|_| panic!());
+        let commit = db.store_commit(dummy_commit()).unwrap_or_else(|_| panic!());
+        let limits = CacheLimits {
+            trees: 1,
+            commits: 1,
+            ..CacheLimits::default()
+        };
+        let viewer = GittyViewer::new(&db, &limits);
+        let (a, b, c) = {
+            let mut inodes = viewer.inodes.lock().unwrap();
+            (
+                inodes.entry_to_inode(FUSE_ROOT_ID, &first, &file("a", 1)),
+                inodes.entry_to_inode(FUSE_ROOT_ID, &second, &file("b", 2)),
+                inodes.commit_to_inode(&commit),
+            )
+        };
+        for _ in 0..2 {
+            for &(inode, name) in &[(a, "a"), (b, "b")] {
+                match viewer.inode_to_tree(inode) {
+                    Some(GittyTreeEntry::Blob(ref blob)) => assert_eq!(blob.name, name),
+                    _ => panic!("no entry {}", name),
+                }
+            }
+            assert!(viewer.inode_to_tree(c).is_some());
+        }
+        assert_eq!(viewer.trees.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn caches_are_shared_between_threads() {
+        let db = MemoryDatabase::new();
+        let tree = db
+            .store_tree(GittyTree {
+                entries: vec![file("a", 1)],
+            })
+            .unwrap_or_else(|_| panic!());
+        let viewer = GittyViewer::new(&db, &CacheLimits::default());
+        let inodes: Vec<Inode> = thread::scope(|scope| {
+            let threads: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let inode = viewer.inodes.lock().unwrap().entry_to_inode(
+                            FUSE_ROOT_ID,
+                            &tree,
+                            &file("a", 1),
+                        );
+                        assert!(viewer.inode_to_tree(inode).is_some());
+                        inode
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        assert!(inodes.iter().all(|&inode| inode == inodes[0]));
+        assert_eq!(viewer.inodes.lock().unwrap().lookups[&inodes[0]], 8);
+    }
+
+    // inode of a file with content in its own snapshot tree
+    fn blob_inode(db: &MemoryDatabase, viewer: &GittyViewer, content: &[u8]) -> Inode {
+        let stored = db
+            .store_blob_from_reader(&mut Cursor::new(content))
+            .unwrap_or_else(|_| panic!());
+        let entry = GittyTreeEntry::Blob(GittyBlobMetadata {
+            name: OsString::from("blob"),
+            modified: Utc.timestamp(0, 0),
+            permissions: permissions(0o100644),
+            size: stored.size,
+            is_symlink: false,
+            unstable: false,
+            hash: stored.blob_ref.hash,
+        });
+        let tree = db
+            .store_tree(GittyTree {
+                entries: vec![entry.clone()],
+            })
+            .unwrap_or_else(|_| panic!());
+        viewer
+            .inodes
+            .lock()
+            .unwrap()
+            .entry_to_inode(FUSE_ROOT_ID, &tree, &entry)
+    }
+
+    #[test]
+    fn concurrent_reads_of_one_blob() {
+        let db = MemoryDatabase::new();
+        let viewer = GittyViewer::new(&db, &CacheLimits::default());
+        let content: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let inode = blob_inode(&db, &viewer, &content);
+        let open = || viewer.open_file(inode, O_RDONLY as u32).unwrap();
+        let shared = open();
+        let chunks: Vec<Vec<u8>> = thread::scope(|scope| {
+            let threads: Vec<_> = (0..4)
+                .map(|t| {
+                    let (viewer, content) = (&viewer, &content);
+                    // two readers share a handle, the others have their own
+                    let fh = if t < 2 { shared } else { open() };
+                    scope.spawn(move || {
+                        let mut read = vec![0; content.len()];
+                        let mut offsets: Vec<usize> = (0..content.len()).step_by(4096).collect();
+                        if t % 2 == 1 {
+                            offsets.reverse();
+                        }
+                        for offset in offsets {
+                            let data = viewer
+                                .read_file(fh, offset as i64, 4096)
+                                .unwrap_or_else(|e| panic!("read: {}", e));
+                            read[offset..offset + data.len()].copy_from_slice(&data);
+                        }
+                        read
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        for read in chunks {
+            assert!(read == content);
+        }
+        // reading past the end is not an error
+        assert_eq!(viewer.read_file(shared, 200_000, 10), Ok(vec![]));
+    }
+
+    #[test]
+    fn reads_need_an_open_file() {
+        let db = MemoryDatabase::new();
+        let viewer = GittyViewer::new(&db, &CacheLimits::default());
+        assert_eq!(viewer.read_file(1, 0, 10), Err(EBADF));
+        let inode = blob_inode(&db, &viewer, b"content");
+        let fh = viewer.open_file(inode, O_RDONLY as u32).unwrap();
+        assert_eq!(viewer.read_file(fh, 0, 10), Ok(b"content".to_vec()));
+        assert_eq!(viewer.read_file(fh, -1, 10), Err(EINVAL));
+        viewer.file_handles.lock().unwrap().remove(&fh);
+        assert_eq!(viewer.read_file(fh, 0, 10), Err(EBADF));
+    }
+
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "corrupt"))
+        }
+    }
+
+    impl Seek for FailingReader {
+        fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn failing_reads_are_io_errors() {
+        let db = MemoryDatabase::new();
+        let viewer = GittyViewer::new(&db, &CacheLimits::default());
+        let fh = viewer.add_file_handle(Box::new(FailingReader));
+        assert_eq!(viewer.read_file(fh, 0, 10), Err(EIO));
+    }
+
+    #[test]
+    fn shared_directories_have_one_parent() {
+        let db = MemoryDatabase::new();
+        let tree = |entries| {
+            db.store_tree(GittyTree { entries })
+                .unwrap_or_else(|_| panic!())
+        };
+        let dir = |name: &str, tree_ref: &GittyTreeRef| {
+            GittyTreeEntry::Tree(GittyTreeMetadata {
+                name: OsString::from(name),
+                hash: tree_ref.hash.clone(),
+                modified: Utc.timestamp(0, 0),
+                permissions: permissions(0o40755),
+            })
+        };
+        let sub = tree(vec![file("a", 1)]);
+        let shared = tree(vec![dir("sub", &sub)]);
+        let roots = [
+            tree(vec![dir("dir", &shared)]),
+            tree(vec![dir("dir", &shared), file("b", 2)]),
+        ];
+        let mut inodes = Inodes::new();
+        let found: Vec<(Inode, Inode)> = roots
+            .iter()
+            .enumerate()
+            .map(|(i, root)| {
+                let mut commit = dummy_commit();
+                commit.root = root.hash.clone();
+                commit.depth = i as u64;
+                let commit_ref = db.store_commit(commit).unwrap_or_else(|_| panic!());
+                let commit_dir = inodes.commit_to_inode(&commit_ref);
+                let outer = inodes.entry_to_inode(commit_dir, root, &dir("dir", &shared));
+                let subdir = inodes.entry_to_inode(outer, &shared, &dir("sub", &sub));
+                (subdir, inodes.entry_to_inode(subdir, &sub, &file("a", 1)))
+            })
+            .collect();
+        // the same tree, reached through different directories
+        assert_ne!(found[0].0, found[1].0);
+        // while the unchanged file in it keeps its inode
+        assert_eq!(found[0].1, found[1].1);
+    }
+
+    #[test]
+    fn history_names_are_unique() {
+        let commit = |byte| GittyCommitRef {
+            hash: GittyHash {
+                algorithm: HashAlgorithm::Blake3,
+                digest: [byte; 32],
+            },
+        };
+        let entry = |byte, secs| CommitGraphEntry {
+            commit_ref: commit(byte),
+            commit_time: chrono::FixedOffset::east(3600).timestamp(secs, 0),
+            depth: byte as u64,
+            root: PLACEHOLDER_HASH,
+            files: 0,
+            bytes: 0,
+        };
+        // newest first
+        let history = History::new(
+            commit(3),
+            vec![
+                entry(3, 1_500_000_030),
+                entry(2, 1_500_000_000),
+                entry(1, 1_500_000_000),
+            ],
+        );
+        let names: Vec<&str> = history.commits.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(
+            names[1..],
+            [format!("{} (2)", names[2]), names[2].to_owned()]
+        );
+        assert_ne!(names[0], names[2]);
+        assert_eq!(history.by_name[OsStr::new(names[1])], 1);
+
+        let years = history.date_entries(&[]);
+        assert_eq!(years.len(), 1);
+        assert_eq!(years[0].0, "2017");
+        let days = history.date_entries(&[2017, 7]);
+        assert_eq!(days.len(), 1);
+        assert_eq!(days[0].0, "14");
+        let times: Vec<OsString> = history
+            .date_entries(&[2017, 7, 14])
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(times, ["03:40", "03:40 (2)", "03:40 (3)"]);
+    }
+
+    #[test]
+    fn by_date_leads_to_commits() {
+        let db = MemoryDatabase::new();
+        let first = db.get_head_commit().unwrap_or_else(|_| panic!());
+        let mut second = dummy_commit();
+        second.commit_time = chrono::FixedOffset::east(0).timestamp(1_500_000_000, 0);
+        second.parents = vec![first.hash];
+        second.depth = 1;
+        let second = db.store_commit(second).unwrap_or_else(|_| panic!());
+        db.update_head_commit(&second).unwrap_or_else(|_| panic!());
+        let viewer = GittyViewer::new(&db, &CacheLimits::default());
+
+        let mut inode = BY_DATE_INODE;
+        for name in &["2017", "07", "14", "02:40"] {
+            let entry = match viewer.virtual_lookup(inode, OsStr::new(name)) {
+                Some(Ok(entry)) => entry,
+                _ => panic!("no entry {}", name),
+            };
+            inode = match entry {
+                VirtualEntry::Date(date) => viewer.inodes.lock().unwrap().date_to_inode(date),
+                VirtualEntry::CommitLink(commit_ref) => {
+                    assert_eq!(commit_ref, second);
+                    let link = viewer
+                        .inodes
+                        .lock()
+                        .unwrap()
+                        .commit_link_to_inode(&commit_ref);
+                    match viewer.virtual_attr(link, 0, 0) {
+                        Some(Ok(attr)) => assert_eq!(attr.kind, FileType::Symlink),
+                        _ => panic!(),
+                    }
+                    let target = match viewer.link_target(link) {
+                        Some(Ok(target)) => target,
+                        _ => panic!(),
+                    };
+                    let name = target.trim_start_matches("../../../../");
+                    assert_eq!(viewer.history().unwrap().by_name[OsStr::new(name)], 0);
+                    break;
+                }
+                _ => panic!(),
+            };
+            match viewer.virtual_attr(inode, 0, 0) {
+                Some(Ok(attr)) => assert_eq!(attr.kind, FileType::Directory),
+                _ => panic!(),
+            }
+        }
+        let years: Vec<OsString> = match viewer.virtual_entries(BY_DATE_INODE) {
+            Some(Ok(entries)) => entries.into_iter().map(|(name, _)| name).collect(),
+            _ => panic!(),
+        };
+        assert_eq!(years, ["2017"]);
+
+        let latest = viewer.latest_target().unwrap();
+        assert_eq!(viewer.history().unwrap().by_name[OsStr::new(&latest)], 0);
+        match viewer.virtual_lookup(FUSE_ROOT_ID, OsStr::new(&latest)) {
+            Some(Ok(VirtualEntry::Commit(commit_ref))) => assert_eq!(commit_ref, second),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn history_lists_distinct_versions() {
+        let db = MemoryDatabase::new();
+        let tree = |entries| {
+            db.store_tree(GittyTree { entries })
+                .unwrap_or_else(|_| panic!())
+        };
+        let dir = |entries| {
+            GittyTreeEntry::Tree(GittyTreeMetadata {
+                name: OsString::from("dir"),
+                hash: tree(entries).hash,
+                modified: Utc.timestamp(0, 0),
+                permissions: permissions(0o40755),
+            })
+        };
+        let roots = vec![
+            tree(vec![dir(vec![file("a", 1)])]),
+            tree(vec![dir(vec![file("a", 1), file("b", 2)])]),
+            tree(vec![dir(vec![file("a", 3)])]),
+            // deleted
+            tree(vec![file("other", 4)]),
+        ];
+        let mut head = db.get_head_commit().unwrap_or_else(|_| panic!());
+        for (i, root) in roots.into_iter().enumerate() {
+            let mut commit = dummy_commit();
+            commit.commit_time = chrono::FixedOffset::east(0).timestamp(i as i64, 0);
+            commit.parents = vec![head.hash];
+            commit.depth = i as u64 + 1;
+            commit.root = root.hash;
+            head = db.store_commit(commit).unwrap_or_else(|_| panic!());
+        }
+        db.update_head_commit(&head).unwrap_or_else(|_| panic!());
+        let viewer = GittyViewer::new(&db, &CacheLimits::default());
+        let commit_names: Vec<OsString> = viewer
+            .history()
+            .unwrap()
+            .commits
+            .iter()
+            .rev()
+            .map(|c| OsString::from(&c.name))
+            .collect();
+
+        let names = |path: &[&str]| -> Vec<OsString> {
+            let path: Vec<OsString> = path.iter().map(OsString::from).collect();
+            viewer
+                .path_entries(&path)
+                .unwrap()
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect()
+        };
+        assert_eq!(names(&[]), ["dir", "other"]);
+        let mut expected = vec![OsString::from("a"), OsString::from("b")];
+        expected.extend_from_slice(&commit_names[0..3]);
+        assert_eq!(names(&["dir"]), expected);
+        assert_eq!(
+            names(&["dir", "a"]),
+            [commit_names[0].clone(), commit_names[2].clone()]
+        );
+        assert!(names(&["missing"]).is_empty());
+
+        let a = vec![OsString::from("dir"), OsString::from("a")];
+        let inode = viewer.inodes.lock().unwrap().path_to_inode(a);
+        let version = match viewer.virtual_lookup(inode, &commit_names[2]) {
+            Some(Ok(VirtualEntry::Version(parent, entry))) => viewer
+                .inodes
+                .lock()
+                .unwrap()
+                .entry_to_inode(inode, &parent, &entry),
+            _ => panic!(),
+        };
+        match viewer.inode_to_tree(version) {
+            Some(GittyTreeEntry::Blob(ref blob)) => assert_eq!(blob.hash.digest, [3; 32]),
+            _ => panic!(),
+        }
+
+        // the same file in its snapshot is the same inode
+        let snapshot = {
+            let history = viewer.history().unwrap();
+            let commit = &history.commits[history.by_name[&commit_names[2]]];
+            let root = GittyTreeRef {
+                hash: commit.root.clone(),
+            };
+            let root_tree = viewer.get_tree(&root).unwrap();
+            let dir_entry = find_tree_entry(&root_tree, OsStr::new("dir")).unwrap();
+            let dir_tree = GittyTreeRef {
+                hash: entry_hash(dir_entry).clone(),
+            };
+            let file_tree = viewer.get_tree(&dir_tree).unwrap();
+            let file_entry = find_tree_entry(&file_tree, OsStr::new("a")).unwrap();
+            let mut inodes = viewer.inodes.lock().unwrap();
+            let commit_dir = inodes.commit_to_inode(&commit.commit_ref);
+            let dir = inodes.entry_to_inode(commit_dir, &root, dir_entry);
+            inodes.entry_to_inode(dir, &dir_tree, file_entry)
+        };
+        assert_eq!(snapshot, version);
+        match viewer.inode_to_tree(snapshot) {
+            Some(GittyTreeEntry::Blob(ref blob)) => assert_eq!(blob.hash.digest, [3; 32]),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn path_histories_are_bounded_by_commits() {
+        let db = MemoryDatabase::new();
+        let dir = db
+            .store_tree(GittyTree {
+                entries: vec![file("a", 1)],
+            })
+            .unwrap_or_else(|_| panic!());
+        let root = db
+            .store_tree(GittyTree {
+                entries: vec![GittyTreeEntry::Tree(GittyTreeMetadata {
+                    name: OsString::from("dir"),
+                    hash: dir.hash,
+                    modified: Utc.timestamp(0, 0),
+                    permissions: permissions(0o40755),
+                })],
+            })
+            .unwrap_or_else(|_| panic!());
+        let mut head = db.get_head_commit().unwrap_or_else(|_| panic!());
+        for i in 0..4 {
+            let mut commit = dummy_commit();
+            commit.commit_time = chrono::FixedOffset::east(0).timestamp(i, 0);
+            commit.parents = vec![head.hash];
+            commit.depth = i as u64 + 1;
+            commit.root = root.hash.clone();
+            head = db.store_commit(commit).unwrap_or_else(|_| panic!());
+        }
+        db.update_head_commit(&head).unwrap_or_else(|_| panic!());
+        let limits = CacheLimits {
+            history_entries: 10,
+            ..CacheLimits::default()
+        };
+        let viewer = GittyViewer::new(&db, &limits);
+        assert_eq!(viewer.history().unwrap().commits.len(), 4);
+
+        let path = [OsString::from("dir"), OsString::from("a")];
+        let entries = viewer.path_entries(&path).unwrap();
+        assert_eq!(entries.len(), 1);
+        // the histories of a, dir and the root don't fit in 10 entries of 4 commits
+        assert_eq!(viewer.path_histories.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn stats_file_lists_snapshots() {
+        let db = MemoryDatabase::new();
+        let blob = db
+            .store_blob_from_reader(&mut Cursor::new(vec![7u8; 1000]))
+            .unwrap_or_else(|_| panic!());
+        let mut root = file("a", 0);
+        if let GittyTreeEntry::Blob(ref mut b) = root {
+            b.hash = blob.blob_ref.hash;
+        }
+        let root = db
+            .store_tree(GittyTree {
+                entries: vec![root],
+            })
+            .unwrap_or_else(|_| panic!());
+        let mut head = db.get_head_commit().unwrap_or_else(|_| panic!());
+        for i in 0..2 {
+            let mut commit = dummy_commit();
+            commit.commit_time = chrono::FixedOffset::east(0).timestamp(i, 0);
+            commit.parents = vec![head.hash];
+            commit.depth = i as u64 + 1;
+            commit.root = root.hash.clone();
+            commit.snapshot.files = 1;
+            commit.snapshot.bytes = 1000;
+            head = db.store_commit(commit).unwrap_or_else(|_| panic!());
+        }
+        db.update_head_commit(&head).unwrap_or_else(|_| panic!());
+        let viewer = GittyViewer::new(&db, &CacheLimits::default());
+
+        let stats = viewer.stats().unwrap();
+        assert_eq!(stats.storage.blobs, 1);
+        assert_eq!(stats.storage.commits, 3);
+        let content = viewer.stats_json().unwrap();
+        match viewer.virtual_attr(STATS_INODE, 0, 0) {
+            Some(Ok(attr)) => assert_eq!(attr.size, content.len() as u64),
+            _ => panic!(),
+        }
+        let json: serde_json::Value = serde_json::from_slice(&content).unwrap();
+        assert_eq!(json["snapshot_bytes"], 2000);
+        assert!(json["dedup_ratio"].as_f64().unwrap() > 1.0);
+        let snapshots = json["snapshots"].as_array().unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0]["commit"], head.hash.to_string());
+        assert_eq!(snapshots[0]["name"], viewer.latest_target().unwrap());
+    }
+
+    #[test]
+    fn commit_metadata_is_exposed() {
+        let db = MemoryDatabase::new();
+        let root = db
+            .store_tree(GittyTree {
+                entries: vec![file("a", 1)],
+            })
+            .unwrap_or_else(|_| panic!());
+        let mut commit = dummy_commit();
+        commit.root = root.hash.clone();
+        commit.message = "nightly".to_owned();
+        let commit_ref = db.store_commit(commit).unwrap_or_else(|_| panic!());
+        let viewer = GittyViewer::new(&db, &CacheLimits::default());
+        let (dir, info, a) = {
+            let mut inodes = viewer.inodes.lock().unwrap();
+            let dir = inodes.commit_to_inode(&commit_ref);
+            (
+                dir,
+                inodes.commit_info_to_inode(&commit_ref),
+                inodes.entry_to_inode(dir, &root, &file("a", 1)),
+            )
+        };
+
+        let json = match viewer.virtual_file(info) {
+            Some(Ok(json)) => json,
+            _ => panic!(),
+        };
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["hash"], commit_ref.hash.to_string());
+        assert_eq!(json["root"], root.hash.to_string());
+        assert_eq!(json["message"], "nightly");
+        match viewer.virtual_attr(info, 0, 0) {
+            Some(Ok(attr)) => assert_eq!(attr.kind, FileType::RegularFile),
+            _ => panic!(),
+        }
+
+        assert_eq!(
+            viewer.xattrs(dir),
+            [
+                ("user.gitty.hash", commit_ref.hash.to_string()),
+                ("user.gitty.tree_hash", root.hash.to_string()),
+            ]
+        );
+        let blob_hash = match file("a", 1) {
+            GittyTreeEntry::Blob(b) => b.hash.to_string(),
+            _ => unreachable!(),
+        };
+        assert_eq!(viewer.xattrs(a), [("user.gitty.blob_hash", blob_hash)]);
+        assert!(viewer.xattrs(BY_DATE_INODE).is_empty());
+    }
+
+    fn dummy_commit() -> GittyCommit {
+        let author = GittyAuthor {
+            name: "user".to_owned(),
+            email: "user@host".to_owned(),
+        };
+        let time = chrono::FixedOffset::east(0).timestamp(0, 0);
+        GittyCommit {
+            author: author.clone(),
+            committer: author,
+            author_time: time,
+            commit_time: time,
+            message: String::new(),
+            depth: 0,
+            parents: vec![],
+            root: PLACEHOLDER_HASH,
+            snapshot: GittySnapshotInfo::default(),
+        }
+    }
 }
@@ -2,6 +2,7 @@ extern crate bk_tree;
 extern crate chrono;
 extern crate env_logger;
 extern crate ignore;
+extern crate libc;
 extern crate serde;
 extern crate serde_json;
 #[macro_use]
@@ -14,32 +15,458 @@ extern crate hex;
 extern crate rand;
 extern crate sha2;
 extern crate whoami;
+use gitty_backup_rs::exclude::ExcludeOptions;
+use gitty_backup_rs::exclude::ExcludeRules;
+use gitty_backup_rs::fs_walk::ErrorPolicy;
+use gitty_backup_rs::fs_walk::SnapshotSource;
+use gitty_backup_rs::fs_walk::WalkOptions;
 use gitty_backup_rs::model::GittyError;
+use gitty_backup_rs::model::HashAlgorithm;
+use gitty_backup_rs::mounts::VIRTUAL_FS_TYPES;
+use gitty_backup_rs::progress::format_bytes;
+use gitty_backup_rs::progress::JsonEvents;
+use gitty_backup_rs::progress::NoObserver;
+use gitty_backup_rs::progress::ProgressBar;
+use gitty_backup_rs::progress::WalkObserver;
+use std::ffi::OsString;
+use std::io::Read;
+use std::os::unix::ffi::OsStringExt;
 use std::path::Path;
+use std::path::PathBuf;
 extern crate gitty_backup_rs;
 use gitty_backup_rs::commits;
-use gitty_backup_rs::database;
+use gitty_backup_rs::database::config::FORMAT_VERSION;
+use gitty_backup_rs::database::crypto::KeySource;
+use gitty_backup_rs::database::fs_database::FSDatabase;
+use gitty_backup_rs::database::fs_database::FSDatabaseConfig;
+use gitty_backup_rs::database::memory_database::MemoryDatabase;
 use gitty_backup_rs::database::GittyDatabase;
+use gitty_backup_rs::model::GittyCommit;
+use gitty_backup_rs::model::GittyCommitRef;
+
+const USAGE: &str = "usage:
+    gitty [snapshot] <source>... <database> [options]
+    gitty [snapshot] --stdin-name <path> <database> [options]
+    gitty check-ignore <source> <database> <path>...
+    gitty change-key <database> (--new-key-file <file> | GITTY_NEW_PASSPHRASE=...)
+    gitty repack <database>
+    gitty migrate <database>
+
+With more than one source (or --files-from), every source is stored at its absolute
+path below the root of the snapshot. With --stdin-name, the data read from stdin is stored as a
+file at the given path in the previous snapshot, which is kept otherwise
+(e.g. pg_dump | gitty --stdin-name dumps/db.sql).
+
+A new repository is encrypted if a key is given with --key-file (at least 32 random bytes, e.g.
+from head -c 32 /dev/urandom), GITTY_KEY_FILE or GITTY_PASSPHRASE. change-key protects the
+repository with a new key file or passphrase.
+
+Small files, directories and commits are bundled in pack files. repack moves small objects that
+are stored on their own into packs. A repository written by an older version of gitty keeps its
+format, without packs, until migrate upgrades it to the current format, which older versions
+can't read.
+
+options:
+    --key-file <file>       key of an encrypted repository
+    --hash <algorithm>      hash function of a new repository, blake3 (default) or sha256
+    --files-from <file>     also back up the paths listed in file, one per line (- for stdin)
+    --exclude <pattern>     exclude paths matching the gitignore-style pattern
+    --exclude-caches        skip directories tagged with CACHEDIR.TAG
+    --one-file-system       don't descend into directories on other filesystems
+    --skip-fs-type <type>   don't descend into mount points of this filesystem type
+    --skip-virtual-fs       skip proc, sysfs and other pseudo filesystems
+    --threads <n>           number of threads storing files (default: one per cpu)
+    --on-error <policy>     abort, skip (default) or retry[:n] when a path can't be read
+    --unstable-retries <n>  how often to re-read files that change while reading (default: 3)
+    --dry-run               only estimate the size of the snapshot, don't store anything
+    --json-events           print progress as one JSON object per line on stdout
+    --quiet                 don't show a progress bar";
+
+struct CliOptions {
+    command: String,
+    positional: Vec<String>,
+    key_file: Option<String>,
+    hash_algorithm: HashAlgorithm,
+    new_key_file: Option<String>,
+    files_from: Option<String>,
+    stdin_name: Option<String>,
+    dry_run: bool,
+    exclude: Vec<String>,
+    exclude_caches: bool,
+    one_file_system: bool,
+    skip_fs_types: Vec<String>,
+    threads: usize,
+    on_error: ErrorPolicy,
+    unstable_retries: u32,
+    json_events: bool,
+    quiet: bool,
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(1);
+}
+
+fn parse_args(mut args: Vec<String>) -> CliOptions {
+    let command = match args.first().map(|s| s.as_str()) {
+        Some("snapshot") | Some("check-ignore") | Some("change-key") | Some("repack")
+        | Some("migrate") => args.remove(0),
+        _ => "snapshot".to_owned(),
+    };
+    let mut opts = CliOptions {
+        command,
+        positional: vec![],
+        key_file: None,
+        hash_algorithm: HashAlgorithm::default(),
+        new_key_file: None,
+        files_from: None,
+        stdin_name: None,
+        dry_run: false,
+        exclude: vec![],
+        exclude_caches: false,
+        one_file_system: false,
+        skip_fs_types: vec![],
+        threads: 0,
+        on_error: ErrorPolicy::Skip,
+        unstable_retries: 3,
+        json_events: false,
+        quiet: false,
+    };
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--key-file" => opts.key_file = Some(args.next().unwrap_or_else(|| usage())),
+            "--hash" => {
+                opts.hash_algorithm = args
+                    .next()
+                    .and_then(|a| HashAlgorithm::from_name(&a))
+                    .unwrap_or_else(|| usage())
+            }
+            "--new-key-file" => opts.new_key_file = Some(args.next().unwrap_or_else(|| usage())),
+            "--files-from" => opts.files_from = Some(args.next().unwrap_or_else(|| usage())),
+            "--stdin-name" => opts.stdin_name = Some(args.next().unwrap_or_else(|| usage())),
+            "--exclude" => opts.exclude.push(args.next().unwrap_or_else(|| usage())),
+            "--exclude-caches" => opts.exclude_caches = true,
+            "--one-file-system" => opts.one_file_system = true,
+            "--skip-fs-type" => opts
+                .skip_fs_types
+                .push(args.next().unwrap_or_else(|| usage())),
+            "--skip-virtual-fs" => opts
+                .skip_fs_types
+                .extend(VIRTUAL_FS_TYPES.iter().map(|t| t.to_string())),
+            "--threads" => {
+                opts.threads = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            "--on-error" => {
+                opts.on_error = args
+                    .next()
+                    .and_then(|p| parse_error_policy(&p))
+                    .unwrap_or_else(|| usage())
+            }
+            "--unstable-retries" => {
+                opts.unstable_retries = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            "--dry-run" => opts.dry_run = true,
+            "--json-events" => opts.json_events = true,
+            "--quiet" => opts.quiet = true,
+            "-h" | "--help" => usage(),
+            _ if arg.starts_with("--") => usage(),
+            _ => opts.positional.push(arg),
+        }
+    }
+    if opts.stdin_name.is_some() && (opts.files_from.is_some() || opts.positional.len() != 1) {
+        // a stream can't be combined with other sources
+        usage();
+    }
+    let only_database = opts.files_from.is_some() || opts.stdin_name.is_some();
+    let required = if opts.command == "snapshot" && only_database
+        || opts.command == "change-key"
+        || opts.command == "repack"
+        || opts.command == "migrate"
+    {
+        1
+    } else {
+        2
+    };
+    if opts.positional.len() < required {
+        usage();
+    }
+    opts
+}
+
+fn key_source(opts: &CliOptions) -> Option<KeySource> {
+    opts.key_file
+        .as_ref()
+        .map(|path| KeySource::KeyFile(PathBuf::from(path)))
+        .or_else(KeySource::from_env)
+}
+
+fn change_key(opts: &CliOptions) -> Result<(), GittyError> {
+    let new_key = match opts.new_key_file {
+        Some(ref path) => KeySource::KeyFile(PathBuf::from(path)),
+        None => match std::env::var("GITTY_NEW_PASSPHRASE") {
+            Ok(passphrase) => KeySource::Passphrase(passphrase),
+            Err(_) => usage(),
+        },
+    };
+    open_database(opts)?.change_key(&new_key)
+}
+
+fn database_config(opts: &CliOptions, root: &Path) -> FSDatabaseConfig {
+    FSDatabaseConfig {
+        root: root.to_path_buf(),
+        object_prefix_length: 3,
+        key: key_source(opts),
+        hash_algorithm: opts.hash_algorithm,
+    }
+}
+
+// for commands that work on an existing repository
+fn open_database(opts: &CliOptions) -> Result<FSDatabase, GittyError> {
+    let config = database_config(opts, Path::new(&opts.positional[0]));
+    FSDatabase::open(config)?.ok_or_else(|| {
+        GittyError::new(
+            opts.command.clone(),
+            Box::new(format!("{} is not a repository", opts.positional[0])),
+        )
+    })
+}
+
+fn repack(opts: &CliOptions) -> Result<(), GittyError> {
+    let stats = open_database(opts)?.repack()?;
+    eprintln!(
+        "moved {} objects ({}) into packs",
+        stats.objects,
+        format_bytes(stats.bytes)
+    );
+    Ok(())
+}
+
+fn migrate(opts: &CliOptions) -> Result<(), GittyError> {
+    let stats = open_database(opts)?.migrate()?;
+    if stats.from_version == FORMAT_VERSION {
+        eprintln!("the repository already has the current format");
+        return Ok(());
+    }
+    eprintln!(
+        "upgraded the repository from format {} to {}",
+        stats.from_version, FORMAT_VERSION
+    );
+    eprintln!(
+        "    rewrote {} trees and {} commits",
+        stats.trees, stats.commits
+    );
+    eprintln!(
+        "    moved {} objects ({}) into packs",
+        stats.repacked.objects,
+        format_bytes(stats.repacked.bytes)
+    );
+    Ok(())
+}
+
+fn parse_error_policy(policy: &str) -> Option<ErrorPolicy> {
+    match policy {
+        "abort" => Some(ErrorPolicy::Abort),
+        "skip" => Some(ErrorPolicy::Skip),
+        "retry" => Some(ErrorPolicy::Retry(3)),
+        _ if policy.starts_with("retry:") => policy[6..].parse().ok().map(ErrorPolicy::Retry),
+        _ => None,
+    }
+}
+
+fn read_files_from(file: &str) -> std::io::Result<Vec<PathBuf>> {
+    let content = if file == "-" {
+        let mut content = Vec::new();
+        std::io::stdin().read_to_end(&mut content)?;
+        content
+    } else {
+        std::fs::read(file)?
+    };
+    Ok(content
+        .split(|&c| c == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| PathBuf::from(OsString::from_vec(line.to_vec())))
+        .collect())
+}
+
+fn snapshot_source(opts: &CliOptions) -> Result<SnapshotSource, GittyError> {
+    if let Some(ref name) = opts.stdin_name {
+        return Ok(SnapshotSource::Stream {
+            path: PathBuf::from(name),
+            reader: Box::new(std::io::stdin()),
+        });
+    }
+    let mut sources: Vec<PathBuf> = opts.positional[..opts.positional.len() - 1]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+    if let Some(ref file) = opts.files_from {
+        let paths = read_files_from(file)
+            .map_err(|e| GittyError::new(format!("--files-from {}", file), Box::new(e)))?;
+        sources.extend(paths);
+    } else if sources.len() == 1 {
+        return Ok(SnapshotSource::Dir(sources.remove(0)));
+    }
+    Ok(SnapshotSource::Paths(sources))
+}
+
+fn walk_options(opts: &CliOptions, db: Option<&FSDatabase>) -> WalkOptions {
+    WalkOptions {
+        exclude: ExcludeOptions {
+            exclude_file: db.map(|db| db.exclude_path()),
+            patterns: opts.exclude.clone(),
+            exclude_caches: opts.exclude_caches,
+        },
+        one_file_system: opts.one_file_system,
+        skip_fs_types: opts.skip_fs_types.clone(),
+        threads: opts.threads,
+        on_error: opts.on_error,
+        unstable_retries: opts.unstable_retries,
+        mount_table: None,
+    }
+}
+
+fn check_ignore(
+    opts: &CliOptions,
+    source: &Path,
+    db: Option<&FSDatabase>,
+) -> Result<(), GittyError> {
+    let mut rules = ExcludeRules::new(source, &walk_options(opts, db).exclude)?;
+    for path in &opts.positional[2..] {
+        let path = PathBuf::from(path);
+        let path = if path.is_relative() {
+            source.join(path)
+        } else {
+            path
+        };
+        match rules.explain(&path)? {
+            Some((matched, reason)) => println!(
+                "{}\t{}{}",
+                reason,
+                path.display(),
+                if matched != path {
+                    format!(" (via {})", matched.display())
+                } else {
+                    String::new()
+                }
+            ),
+            None => println!("::\t{}", path.display()),
+        }
+    }
+    Ok(())
+}
 
 fn main() -> Result<(), GittyError> {
     env_logger::init_from_env(
         env_logger::Env::default().filter_or("RUST_LOG", "gitty_backup_rs=info"),
     );
-    let args: Vec<_> = std::env::args().collect();
-    let path = Path::new(&args[1]);
-    let dbpath = Path::new(&args[2]);
-    let ignorepath = path.join(".gittyignore");
-
-    let mut db = database::fs_database::FSDatabase::create_or_open(dbpath).unwrap_or_else(|m| {
-        panic!("{}", m);
-    });
+    let opts = parse_args(std::env::args().skip(1).collect());
+    if opts.command == "change-key" {
+        return change_key(&opts);
+    }
+    if opts.command == "repack" {
+        return repack(&opts);
+    }
+    if opts.command == "migrate" {
+        return migrate(&opts);
+    }
+    if opts.command == "check-ignore" {
+        let path = Path::new(&opts.positional[0]);
+        let dbpath = Path::new(&opts.positional[1]);
+        // without a repository there is no repository exclude file, nothing is created
+        let db = FSDatabase::open(database_config(&opts, dbpath))?;
+        return check_ignore(&opts, path, db.as_ref());
+    }
+    let source = snapshot_source(&opts)?;
+    let dbpath = Path::new(opts.positional.last().unwrap());
+
+    // a dry run must not create the repository
+    let db = if opts.dry_run && !dbpath.exists() {
+        None
+    } else {
+        Some(FSDatabase::open_or_create(database_config(&opts, dbpath))?)
+    };
     /*{
         let head = db.get_head_commit()?;
         for commit in commits::walk_commits(&mut db, head) {
             println!("{:?}", commit);
         }
     }*/
-    commits::commit_current_state_to_head(path, &mut db, &ignorepath)
-        .unwrap_or_else(|m| panic!("{}", m));
+    let walk_options = walk_options(&opts, db.as_ref());
+    let mut observer: Box<dyn WalkObserver> = if opts.json_events {
+        Box::new(JsonEvents::new(std::io::stdout()))
+    } else if opts.quiet || unsafe { libc::isatty(2) } != 1 {
+        // redrawing the bar only makes sense on a terminal, errors are logged either way
+        Box::new(NoObserver)
+    } else {
+        // estimate the progress from the size of the previous snapshot, if it can be read
+        let previous_bytes = db
+            .as_ref()
+            .and_then(|db| db.get_head_commit().and_then(|h| db.load_commit(&h)).ok())
+            .map(|commit| commit.snapshot.bytes);
+        Box::new(ProgressBar::new(previous_bytes))
+    };
+    if opts.dry_run {
+        let memory_db = MemoryDatabase::without_content();
+        let commit_ref = commits::commit_current_state_to_head(
+            source,
+            &memory_db,
+            &walk_options,
+            &mut *observer,
+        )?;
+        let commit = memory_db.load_commit(&commit_ref)?;
+        report_problems(&commit_ref, &commit);
+        let stats = memory_db.stats();
+        eprintln!("dry run, nothing was stored. the snapshot contains");
+        eprintln!(
+            "    {} files, {}",
+            commit.snapshot.files,
+            format_bytes(commit.snapshot.bytes)
+        );
+        eprintln!(
+            "    {} distinct file contents, {}",
+            stats.blobs,
+            format_bytes(stats.blob_bytes)
+        );
+        eprintln!(
+            "    {} directories, {} of metadata",
+            stats.trees,
+            format_bytes(stats.tree_bytes)
+        );
+        eprintln!("content already in the repository is not taken into account");
+        return Ok(());
+    }
+    let db = db.unwrap();
+    let commit_ref =
+        commits::commit_current_state_to_head(source, &db, &walk_options, &mut *observer)?;
+    report_problems(&commit_ref, &db.load_commit(&commit_ref)?);
     Ok(())
 }
+
+fn report_problems(commit_ref: &GittyCommitRef, commit: &GittyCommit) {
+    if !commit.snapshot.errors.is_empty() {
+        eprintln!(
+            "snapshot {} is incomplete, {} paths could not be backed up:",
+            commit_ref.hash,
+            commit.snapshot.errors.len()
+        );
+        for error in &commit.snapshot.errors {
+            eprintln!("    {}: {}", error.path.to_string_lossy(), error.message);
+        }
+    }
+    if !commit.snapshot.unstable.is_empty() {
+        eprintln!(
+            "{} files changed while they were backed up and may be inconsistent:",
+            commit.snapshot.unstable.len()
+        );
+        for path in &commit.snapshot.unstable {
+            eprintln!("    {}", path.to_string_lossy());
+        }
+    }
+}
@@ -4,18 +4,23 @@ use chrono::FixedOffset;
 use chrono::TimeZone;
 use database::GittyDatabase;
 use fs_walk;
+use fs_walk::SnapshotSource;
+use fs_walk::WalkOptions;
 use model::*;
-use std::path::Path;
+use progress::WalkObserver;
+use std::collections::HashMap;
 use whoami;
 
 pub fn write_commit(
-    db: &mut GittyDatabase,
+    db: &dyn GittyDatabase,
     root: GittyTreeRef,
     parent_ref: GittyCommitRef,
+    snapshot: GittySnapshotInfo,
 ) -> Result<GittyCommitRef, GittyError> {
     let parent = db.load_commit(&parent_ref)?;
-    db.store_commit(create_commit(root, vec![parent_ref.hash], parent.depth + 1))
-        .map_err(|e| GittyError::from(e))
+    let mut commit = create_commit(root, vec![parent_ref.hash], parent.depth + 1);
+    commit.snapshot = snapshot;
+    db.store_commit(commit).map_err(GittyError::from)
 }
 
 pub fn create_commit(root: GittyTreeRef, parents: Vec<GittyHash>, depth: u64) -> GittyCommit {
@@ -30,19 +35,19 @@ pub fn create_commit(root: GittyTreeRef, parents: Vec<GittyHash>, depth: u64) ->
         commit_time,
         author_time: commit_time,
         root: root.hash,
+        snapshot: GittySnapshotInfo::default(),
     }
 }
 
 pub fn commit_current_state_to_head(
-    path: &Path,
-    db: &mut impl GittyDatabase,
-    ignorepath: &Path,
+    source: SnapshotSource,
+    db: &(impl GittyDatabase + Sync),
+    options: &WalkOptions,
+    observer: &mut dyn WalkObserver,
 ) -> Result<GittyCommitRef, GittyError> {
-    let root = fs_walk::recursive_write_tree_to_db(path, db, &ignorepath)
-        .map_err(|p| format!("{}", p))
-        .unwrap();
+    let result = fs_walk::write_source_to_db(source, db, options, observer)?;
     let old_head = db.get_head_commit()?;
-    let commit_ref = write_commit(db, root, old_head)?;
+    let commit_ref = write_commit(db, result.root, old_head, result.info)?;
     db.update_head_commit(&commit_ref)?;
     Ok(commit_ref)
 }
@@ -92,3 +97,110 @@ impl<'a> Iterator for CommitWalker<'a> {
 pub fn walk_commits<'a>(db: &'a GittyDatabase, start: GittyCommitRef) -> CommitWalker<'a> {
     CommitWalker { db, current: start }
 }
+
+pub struct ReencodedHistory {
+    pub head: GittyCommitRef,
+    // objects that got a new hash, they are no longer referenced
+    pub replaced_trees: Vec<GittyTreeRef>,
+    pub replaced_commits: Vec<GittyCommitRef>,
+}
+
+/// store every tree and commit reachable from head again, in the current encoding.
+///
+/// hashes change for objects that were stored differently, so the commits get new hashes too.
+/// the head is not updated
+pub fn reencode_history(db: &dyn GittyDatabase) -> Result<ReencodedHistory, GittyError> {
+    let mut commits = vec![];
+    let mut current = db.get_head_commit()?;
+    loop {
+        let commit = db.load_commit(&current)?;
+        let parent = match commit.parents.len() {
+            0 => None,
+            1 => Some(GittyCommitRef {
+                hash: commit.parents[0].clone(),
+            }),
+            _ => {
+                return Err(GittyError::new(
+                    "reencode_history".to_string(),
+                    Box::new("multiple parents not supported"),
+                ))
+            }
+        };
+        commits.push((current, commit));
+        match parent {
+            Some(parent) => current = parent,
+            None => break,
+        }
+    }
+
+    let mut trees = HashMap::new();
+    let mut new_commits: HashMap<GittyHash, GittyHash> = HashMap::new();
+    let mut replaced_commits = vec![];
+    let mut head = None;
+    // oldest first, so the new hashes of the parents are known
+    for (commit_ref, mut commit) in commits.into_iter().rev() {
+        commit.root = reencode_tree(db, &commit.root, &mut trees)?;
+        commit.parents = commit
+            .parents
+            .iter()
+            .map(|p| new_commits[p].clone())
+            .collect();
+        let new_ref = db.store_commit(commit)?;
+        if new_ref != commit_ref {
+            replaced_commits.push(commit_ref.clone());
+        }
+        new_commits.insert(commit_ref.hash, new_ref.hash.clone());
+        head = Some(new_ref);
+    }
+    Ok(ReencodedHistory {
+        head: head.unwrap(),
+        replaced_trees: trees
+            .into_iter()
+            .filter(|(old, new)| old != new)
+            .map(|(hash, _)| GittyTreeRef { hash })
+            .collect(),
+        replaced_commits,
+    })
+}
+
+// returns the new hash, trees that were already reencoded are in done.
+// uses its own stack since trees can be nested deeper than the call stack allows
+fn reencode_tree(
+    db: &dyn GittyDatabase,
+    hash: &GittyHash,
+    done: &mut HashMap<GittyHash, GittyHash>,
+) -> Result<GittyHash, GittyError> {
+    if let Some(new_hash) = done.get(hash) {
+        return Ok(new_hash.clone());
+    }
+    let load = |hash: &GittyHash| db.load_tree(&GittyTreeRef { hash: hash.clone() });
+    // trees with the index of the first entry that may still need reencoding
+    let mut stack = vec![(hash.clone(), load(hash)?, 0)];
+    while let Some((old_hash, mut tree, start)) = stack.pop() {
+        let pending = tree.entries[start..].iter().position(|entry| match entry {
+            GittyTreeEntry::Tree(ref t) => !done.contains_key(&t.hash),
+            _ => false,
+        });
+        match pending {
+            Some(offset) => {
+                let child = match tree.entries[start + offset] {
+                    GittyTreeEntry::Tree(ref t) => t.hash.clone(),
+                    _ => unreachable!(),
+                };
+                let child_tree = load(&child)?;
+                stack.push((old_hash, tree, start + offset));
+                stack.push((child, child_tree, 0));
+            }
+            None => {
+                for entry in tree.entries.iter_mut() {
+                    if let GittyTreeEntry::Tree(ref mut t) = entry {
+                        t.hash = done[&t.hash].clone();
+                    }
+                }
+                let new_hash = db.store_tree(tree)?.hash;
+                done.insert(old_hash, new_hash);
+            }
+        }
+    }
+    Ok(done[hash].clone())
+}
@@ -0,0 +1,145 @@
+use chrono::DateTime;
+use chrono::FixedOffset;
+use chrono::TimeZone;
+use database::DBError;
+use database::GittyDatabase;
+use model::*;
+use std::io;
+
+// a graph in any other format is not decoded, so it is built again
+const GRAPH_MAGIC: &[u8] = b"GITTYGRAPH\x01";
+// commit hash and root hash with their algorithms, depth, commit time (seconds, nanoseconds,
+// time zone offset), files and bytes of the snapshot
+const GRAPH_ENTRY_SIZE: usize = 33 + 33 + 8 + 8 + 4 + 4 + 8 + 8;
+
+/// What listing the history needs to know about a commit, kept in one file so the commits
+/// don't have to be loaded one by one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommitGraphEntry {
+    pub commit_ref: GittyCommitRef,
+    pub commit_time: DateTime<FixedOffset>,
+    pub depth: u64,
+    pub root: GittyHash,
+    // see GittySnapshotInfo
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl CommitGraphEntry {
+    pub fn new(commit_ref: GittyCommitRef, commit: &GittyCommit) -> CommitGraphEntry {
+        CommitGraphEntry {
+            commit_ref,
+            commit_time: commit.commit_time,
+            depth: commit.depth,
+            root: commit.root.clone(),
+            files: commit.snapshot.files,
+            bytes: commit.snapshot.bytes,
+        }
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn push_hash(bytes: &mut Vec<u8>, hash: &GittyHash) {
+    bytes.push(hash.algorithm.code());
+    bytes.extend_from_slice(&hash.digest);
+}
+
+fn hash_at(bytes: &[u8], start: usize) -> io::Result<GittyHash> {
+    let algorithm = HashAlgorithm::from_code(bytes[start])
+        .ok_or_else(|| invalid_data(format!("unknown hash algorithm {}", bytes[start])))?;
+    let mut digest = [0u8; 32];
+    digest.copy_from_slice(&bytes[start + 1..start + 33]);
+    Ok(GittyHash { algorithm, digest })
+}
+
+fn u64_at(bytes: &[u8], start: usize) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[start..start + 8]);
+    u64::from_be_bytes(buf)
+}
+
+fn u32_at(bytes: &[u8], start: usize) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&bytes[start..start + 4]);
+    u32::from_be_bytes(buf)
+}
+
+pub fn encode(entries: &[CommitGraphEntry]) -> Vec<u8> {
+    let mut bytes = GRAPH_MAGIC.to_vec();
+    for entry in entries {
+        push_hash(&mut bytes, &entry.commit_ref.hash);
+        push_hash(&mut bytes, &entry.root);
+        bytes.extend_from_slice(&entry.depth.to_be_bytes());
+        bytes.extend_from_slice(&entry.commit_time.timestamp().to_be_bytes());
+        bytes.extend_from_slice(&entry.commit_time.timestamp_subsec_nanos().to_be_bytes());
+        bytes.extend_from_slice(&entry.commit_time.offset().local_minus_utc().to_be_bytes());
+        bytes.extend_from_slice(&entry.files.to_be_bytes());
+        bytes.extend_from_slice(&entry.bytes.to_be_bytes());
+    }
+    bytes
+}
+
+pub fn decode(content: &[u8]) -> io::Result<Vec<CommitGraphEntry>> {
+    if !content.starts_with(GRAPH_MAGIC)
+        || (content.len() - GRAPH_MAGIC.len()) % GRAPH_ENTRY_SIZE != 0
+    {
+        return Err(invalid_data("not a commit graph".to_owned()));
+    }
+    let mut entries = Vec::new();
+    for entry in content[GRAPH_MAGIC.len()..].chunks(GRAPH_ENTRY_SIZE) {
+        let (secs, nanos, offset) = (
+            u64_at(entry, 74) as i64,
+            u32_at(entry, 82),
+            u32_at(entry, 86) as i32,
+        );
+        let commit_time = FixedOffset::east_opt(offset)
+            .and_then(|offset| offset.timestamp_opt(secs, nanos).single())
+            .ok_or_else(|| invalid_data("invalid commit time".to_owned()))?;
+        entries.push(CommitGraphEntry {
+            commit_ref: GittyCommitRef {
+                hash: hash_at(entry, 0)?,
+            },
+            root: hash_at(entry, 33)?,
+            depth: u64_at(entry, 66),
+            commit_time,
+            files: u64_at(entry, 90),
+            bytes: u64_at(entry, 98),
+        });
+    }
+    Ok(entries)
+}
+
+/// the commits reachable from head, newest first and without the initial empty commit, like
+/// walk_commits. only the commits newer than the first one of known are loaded
+pub fn update(
+    db: &dyn GittyDatabase,
+    head: &GittyCommitRef,
+    known: Vec<CommitGraphEntry>,
+) -> Result<Vec<CommitGraphEntry>, DBError> {
+    let mut entries = Vec::new();
+    let mut current = head.clone();
+    loop {
+        if known.first().map(|e| &e.commit_ref) == Some(&current) {
+            entries.extend(known);
+            break;
+        }
+        let commit = db.load_commit(&current)?;
+        if commit.depth == 0 {
+            // known was not part of this history (e.g. rewritten by migrate)
+            break;
+        }
+        if commit.parents.len() != 1 {
+            let e: DBError = Box::new(invalid_data("multiple parents not supported".to_owned()));
+            return Err(e);
+        }
+        let parent = GittyCommitRef {
+            hash: commit.parents[0].clone(),
+        };
+        entries.push(CommitGraphEntry::new(current, &commit));
+        current = parent;
+    }
+    Ok(entries)
+}
@@ -0,0 +1,121 @@
+use database::crypto::EncryptionConfig;
+use model::HashAlgorithm;
+use serde_json;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// version of the repository layout written by this version of gitty.
+///
+/// 0: no config file or no version in it, trees and commits are JSON and stored in their own file
+/// 1: trees and commits are binary (see encoding::FORMAT_VERSION), small objects are in packs,
+///    the hash algorithm is configurable
+///
+/// a repository with an older version is written in its own format until it is migrated, so the
+/// versions that wrote it can still read it
+pub const FORMAT_VERSION: u32 = 1;
+
+pub const FEATURE_BINARY_OBJECTS: &str = "binary-objects";
+pub const FEATURE_PACKS: &str = "packs";
+pub const FEATURE_ENCRYPTION: &str = "encryption";
+pub const FEATURE_BLAKE3: &str = "blake3";
+// a repository using any other feature can't be opened
+const KNOWN_FEATURES: &[&str] = &[
+    FEATURE_BINARY_OBJECTS,
+    FEATURE_PACKS,
+    FEATURE_ENCRYPTION,
+    FEATURE_BLAKE3,
+];
+
+/// settings stored in the repository, written when it is created
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RepoConfig {
+    #[serde(default)]
+    pub format_version: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+    #[serde(default = "legacy_hash_algorithm")]
+    pub hash_algorithm: HashAlgorithm,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<EncryptionConfig>,
+}
+
+// repositories created before the algorithm was configurable
+fn legacy_hash_algorithm() -> HashAlgorithm {
+    HashAlgorithm::Sha256
+}
+
+impl Default for RepoConfig {
+    fn default() -> RepoConfig {
+        RepoConfig {
+            format_version: 0,
+            features: vec![],
+            hash_algorithm: legacy_hash_algorithm(),
+            encryption: None,
+        }
+    }
+}
+
+impl RepoConfig {
+    /// config of a repository in the current format
+    pub fn current(
+        encryption: Option<EncryptionConfig>,
+        hash_algorithm: HashAlgorithm,
+    ) -> RepoConfig {
+        let mut features = vec![FEATURE_BINARY_OBJECTS.to_owned(), FEATURE_PACKS.to_owned()];
+        if encryption.is_some() {
+            features.push(FEATURE_ENCRYPTION.to_owned());
+        }
+        if hash_algorithm == HashAlgorithm::Blake3 {
+            features.push(FEATURE_BLAKE3.to_owned());
+        }
+        RepoConfig {
+            format_version: FORMAT_VERSION,
+            features,
+            hash_algorithm,
+            encryption,
+        }
+    }
+
+    /// repositories created before the config file existed have the default config
+    pub fn load(path: &Path) -> io::Result<RepoConfig> {
+        match fs::read(path) {
+            Ok(content) => serde_json::from_slice(&content).map_err(io::Error::from),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(RepoConfig::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // written to a temporary file first so a crash can't leave a half written key behind
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(tmp_path, path)
+    }
+
+    /// error message if this version of gitty can't use the repository
+    pub fn check_supported(&self) -> Result<(), String> {
+        if self.format_version > FORMAT_VERSION {
+            return Err(format!(
+                "the repository has format version {}, this version of gitty only supports \
+                 up to {}",
+                self.format_version, FORMAT_VERSION
+            ));
+        }
+        match self
+            .features
+            .iter()
+            .find(|f| !KNOWN_FEATURES.contains(&f.as_str()))
+        {
+            Some(feature) => Err(format!(
+                "the repository uses the feature {}, which this version of gitty doesn't support",
+                feature
+            )),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_outdated(&self) -> bool {
+        self.format_version < FORMAT_VERSION
+    }
+}
@@ -0,0 +1,593 @@
+use argon2;
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::aead::Payload;
+use chacha20poly1305::Key;
+use chacha20poly1305::KeyInit;
+use chacha20poly1305::XChaCha20Poly1305;
+use chacha20poly1305::XNonce;
+use hex;
+use hmac::Hmac;
+use hmac::Mac;
+use model::*;
+use rand::OsRng;
+use rand::Rng;
+use sha2::Sha256;
+use std::cmp;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::PathBuf;
+
+// plaintext bytes per encrypted chunk
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const NONCE_PREFIX_SIZE: usize = 19;
+const OBJECT_FORMAT: u8 = 1;
+// format byte and nonce prefix
+const HEADER_SIZE: usize = 1 + NONCE_PREFIX_SIZE;
+const KEY_SIZE: usize = 32;
+const SALT_SIZE: usize = 16;
+const MIN_KEY_FILE_SIZE: usize = 32;
+const MASTER_KEY_AAD: &[u8] = b"gitty master key";
+const KEY_FILE_CONTEXT: &[u8] = b"gitty key file";
+
+pub const CIPHER: &str = "xchacha20poly1305";
+
+// how object ids are computed in encrypted repositories, depends on the hash algorithm
+fn object_ids_name(algorithm: HashAlgorithm) -> &'static str {
+    match algorithm {
+        HashAlgorithm::Sha256 => "hmac-sha256",
+        HashAlgorithm::Blake3 => "keyed-blake3",
+    }
+}
+
+/// where the key protecting the master key comes from
+#[derive(Clone)]
+pub enum KeySource {
+    /// a file with at least 32 random bytes
+    KeyFile(PathBuf),
+    Passphrase(String),
+}
+
+impl KeySource {
+    /// from GITTY_KEY_FILE or GITTY_PASSPHRASE
+    pub fn from_env() -> Option<KeySource> {
+        env::var_os("GITTY_KEY_FILE")
+            .map(|path| KeySource::KeyFile(PathBuf::from(path)))
+            .or_else(|| env::var("GITTY_PASSPHRASE").ok().map(KeySource::Passphrase))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EncryptionConfig {
+    pub cipher: String,
+    pub object_ids: String,
+    pub kdf: Kdf,
+    // hex of nonce and encrypted master key
+    pub wrapped_key: String,
+}
+
+/// how the key encrypting the master key is derived
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Kdf {
+    KeyFile {
+        salt: String,
+    },
+    Argon2id {
+        salt: String,
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ObjectKind {
+    Blob,
+    Tree,
+    Commit,
+}
+
+impl ObjectKind {
+    // authenticated with every chunk so objects can't be swapped between kinds, the last chunk
+    // adds the object id so objects of the same kind can't be swapped either
+    fn aad(self) -> &'static [u8] {
+        match self {
+            ObjectKind::Blob => b"blob",
+            ObjectKind::Tree => b"tree",
+            ObjectKind::Commit => b"commit",
+        }
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn random_bytes(buf: &mut [u8]) -> io::Result<()> {
+    OsRng::new()
+        .map_err(|e| io::Error::other(e.to_string()))?
+        .fill(buf);
+    Ok(())
+}
+
+fn decode_hex(s: &str) -> io::Result<Vec<u8>> {
+    hex::decode(s).map_err(|e| invalid_data(format!("invalid hex in repository config: {}", e)))
+}
+
+/// HMAC-SHA256 (RFC 2104)
+pub struct KeyedHasher(Hmac<Sha256>);
+
+impl KeyedHasher {
+    pub fn new(key: &[u8]) -> KeyedHasher {
+        // keys of any length are accepted
+        KeyedHasher(<Hmac<Sha256> as Mac>::new_from_slice(key).unwrap())
+    }
+
+    pub fn input(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn result(self) -> GittyHash {
+        let mut digest = [0; 32];
+        digest.copy_from_slice(&self.0.finalize().into_bytes());
+        GittyHash {
+            algorithm: HashAlgorithm::Sha256,
+            digest,
+        }
+    }
+}
+
+/// hashes objects to their ids, keyed for encrypted repositories
+pub enum ObjectHasher {
+    // unkeyed, or BLAKE3 in keyed mode
+    Hasher(GittyHasher),
+    // SHA-256 needs HMAC to be keyed
+    Hmac(KeyedHasher),
+}
+
+impl ObjectHasher {
+    pub fn input(&mut self, data: &[u8]) {
+        match self {
+            ObjectHasher::Hasher(h) => h.input(data),
+            ObjectHasher::Hmac(h) => h.input(data),
+        }
+    }
+
+    pub fn result(self) -> GittyHash {
+        match self {
+            ObjectHasher::Hasher(h) => h.result(),
+            ObjectHasher::Hmac(h) => h.result(),
+        }
+    }
+}
+
+/// The master key of an encrypted repository.
+///
+/// It never changes, so changing the passphrase or key file only re-encrypts the master key.
+pub struct RepoKeys {
+    encryption: [u8; KEY_SIZE],
+    object_ids: [u8; KEY_SIZE],
+    hash_algorithm: HashAlgorithm,
+}
+
+impl RepoKeys {
+    pub fn generate(hash_algorithm: HashAlgorithm) -> io::Result<RepoKeys> {
+        let mut keys = RepoKeys {
+            encryption: [0; KEY_SIZE],
+            object_ids: [0; KEY_SIZE],
+            hash_algorithm,
+        };
+        random_bytes(&mut keys.encryption)?;
+        random_bytes(&mut keys.object_ids)?;
+        Ok(keys)
+    }
+
+    /// encrypt the master key with a key derived from source
+    pub fn wrap(&self, source: &KeySource) -> io::Result<EncryptionConfig> {
+        let mut salt = [0u8; SALT_SIZE];
+        random_bytes(&mut salt)?;
+        let salt = hex::encode(salt);
+        let kdf = match source {
+            KeySource::KeyFile(_) => Kdf::KeyFile { salt },
+            KeySource::Passphrase(_) => Kdf::Argon2id {
+                salt,
+                m_cost: 64 * 1024,
+                t_cost: 3,
+                p_cost: 1,
+            },
+        };
+        let kek = derive_key(&kdf, source)?;
+        let mut nonce = [0u8; 24];
+        random_bytes(&mut nonce)?;
+        let mut plaintext = Vec::with_capacity(2 * KEY_SIZE);
+        plaintext.extend_from_slice(&self.encryption);
+        plaintext.extend_from_slice(&self.object_ids);
+        let ciphertext = kek
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: MASTER_KEY_AAD,
+                },
+            )
+            .map_err(|_| invalid_data("could not encrypt master key".to_owned()))?;
+        let mut wrapped = nonce.to_vec();
+        wrapped.extend(ciphertext);
+        Ok(EncryptionConfig {
+            cipher: CIPHER.to_owned(),
+            object_ids: object_ids_name(self.hash_algorithm).to_owned(),
+            kdf,
+            wrapped_key: hex::encode(wrapped),
+        })
+    }
+
+    pub fn unwrap(config: &EncryptionConfig, source: &KeySource) -> io::Result<RepoKeys> {
+        let hash_algorithm = [HashAlgorithm::Sha256, HashAlgorithm::Blake3]
+            .iter()
+            .cloned()
+            .find(|&a| object_ids_name(a) == config.object_ids);
+        let hash_algorithm = match hash_algorithm {
+            Some(hash_algorithm) if config.cipher == CIPHER => hash_algorithm,
+            _ => {
+                return Err(invalid_data(format!(
+                    "unsupported encryption {} with {} ids",
+                    config.cipher, config.object_ids
+                )))
+            }
+        };
+        let kek = derive_key(&config.kdf, source)?;
+        let wrapped = decode_hex(&config.wrapped_key)?;
+        if wrapped.len() < 24 {
+            return Err(invalid_data("master key too short".to_owned()));
+        }
+        let plaintext = kek
+            .decrypt(
+                XNonce::from_slice(&wrapped[..24]),
+                Payload {
+                    msg: &wrapped[24..],
+                    aad: MASTER_KEY_AAD,
+                },
+            )
+            .map_err(|_| invalid_data("wrong key file or passphrase".to_owned()))?;
+        if plaintext.len() != 2 * KEY_SIZE {
+            return Err(invalid_data("master key has the wrong size".to_owned()));
+        }
+        let mut keys = RepoKeys {
+            encryption: [0; KEY_SIZE],
+            object_ids: [0; KEY_SIZE],
+            hash_algorithm,
+        };
+        keys.encryption.copy_from_slice(&plaintext[..KEY_SIZE]);
+        keys.object_ids.copy_from_slice(&plaintext[KEY_SIZE..]);
+        Ok(keys)
+    }
+
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+    }
+
+    pub fn object_hasher(&self) -> ObjectHasher {
+        match self.hash_algorithm {
+            HashAlgorithm::Sha256 => ObjectHasher::Hmac(KeyedHasher::new(&self.object_ids)),
+            HashAlgorithm::Blake3 => {
+                ObjectHasher::Hasher(GittyHasher::keyed_blake3(&self.object_ids))
+            }
+        }
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(Key::from_slice(&self.encryption))
+    }
+
+    pub fn encrypting_writer<W: Write>(
+        &self,
+        kind: ObjectKind,
+        mut inner: W,
+    ) -> io::Result<EncryptingWriter<W>> {
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+        random_bytes(&mut nonce_prefix)?;
+        inner.write_all(&[OBJECT_FORMAT])?;
+        inner.write_all(&nonce_prefix)?;
+        Ok(EncryptingWriter {
+            chunks: Chunks {
+                cipher: self.cipher(),
+                kind,
+                id: Vec::new(),
+                nonce_prefix,
+            },
+            inner,
+            buf: Vec::with_capacity(CHUNK_SIZE + 1),
+            index: 0,
+        })
+    }
+
+    /// id is the one passed to EncryptingWriter::finish, checked before anything is read
+    pub fn decrypting_reader<R: Read + Seek>(
+        &self,
+        kind: ObjectKind,
+        id: Option<&GittyHash>,
+        mut inner: R,
+    ) -> io::Result<DecryptingReader<R>> {
+        let mut header = [0u8; HEADER_SIZE];
+        inner.read_exact(&mut header)?;
+        if header[0] != OBJECT_FORMAT {
+            return Err(invalid_data(format!("unknown object format {}", header[0])));
+        }
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+        nonce_prefix.copy_from_slice(&header[1..]);
+        let encrypted_len = inner.seek(SeekFrom::End(0))? - HEADER_SIZE as u64;
+        let stored_chunk = (CHUNK_SIZE + TAG_SIZE) as u64;
+        // even an empty object has one (final) chunk
+        let chunk_count = cmp::max(1, encrypted_len.div_ceil(stored_chunk));
+        let last_len = encrypted_len - (chunk_count - 1) * stored_chunk;
+        if last_len < TAG_SIZE as u64 || chunk_count > u32::MAX as u64 {
+            return Err(invalid_data("truncated object".to_owned()));
+        }
+        let mut reader = DecryptingReader {
+            chunks: Chunks {
+                cipher: self.cipher(),
+                kind,
+                id: id_bytes(id),
+                nonce_prefix,
+            },
+            inner,
+            len: encrypted_len - chunk_count * TAG_SIZE as u64,
+            chunk_count: chunk_count as u32,
+            pos: 0,
+            current: None,
+        };
+        reader.load_chunk(reader.chunk_count - 1)?;
+        Ok(reader)
+    }
+
+    pub fn seal(
+        &self,
+        kind: ObjectKind,
+        id: Option<&GittyHash>,
+        plaintext: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let mut writer = self.encrypting_writer(kind, Vec::new())?;
+        writer.write_all(plaintext)?;
+        writer.finish(id)
+    }
+
+    pub fn open(
+        &self,
+        kind: ObjectKind,
+        id: Option<&GittyHash>,
+        data: Vec<u8>,
+    ) -> io::Result<Vec<u8>> {
+        let mut plaintext = Vec::new();
+        self.decrypting_reader(kind, id, io::Cursor::new(data))?
+            .read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    }
+}
+
+fn derive_key(kdf: &Kdf, source: &KeySource) -> io::Result<XChaCha20Poly1305> {
+    let mut key = [0u8; KEY_SIZE];
+    match (kdf, source) {
+        (Kdf::KeyFile { salt }, KeySource::KeyFile(path)) => {
+            let content = fs::read(path)?;
+            if content.len() < MIN_KEY_FILE_SIZE {
+                return Err(invalid_data(format!(
+                    "key file {} must contain at least {} bytes",
+                    path.display(),
+                    MIN_KEY_FILE_SIZE
+                )));
+            }
+            let mut hasher = KeyedHasher::new(&content);
+            hasher.input(KEY_FILE_CONTEXT);
+            hasher.input(&decode_hex(salt)?);
+            key.copy_from_slice(&hasher.result().digest);
+        }
+        (
+            Kdf::Argon2id {
+                salt,
+                m_cost,
+                t_cost,
+                p_cost,
+            },
+            KeySource::Passphrase(passphrase),
+        ) => {
+            let params = argon2::Params::new(*m_cost, *t_cost, *p_cost, Some(KEY_SIZE))
+                .map_err(|e| invalid_data(format!("invalid argon2 parameters: {}", e)))?;
+            argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                .hash_password_into(passphrase.as_bytes(), &decode_hex(salt)?, &mut key)
+                .map_err(|e| invalid_data(format!("could not derive key: {}", e)))?;
+        }
+        (Kdf::KeyFile { .. }, _) => {
+            return Err(invalid_data(
+                "the repository is protected by a key file".to_owned(),
+            ))
+        }
+        (Kdf::Argon2id { .. }, _) => {
+            return Err(invalid_data(
+                "the repository is protected by a passphrase".to_owned(),
+            ))
+        }
+    }
+    Ok(XChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+fn id_bytes(id: Option<&GittyHash>) -> Vec<u8> {
+    match id {
+        Some(id) => {
+            let mut bytes = vec![id.algorithm.code()];
+            bytes.extend_from_slice(&id.digest);
+            bytes
+        }
+        None => Vec::new(),
+    }
+}
+
+// chunk i is encrypted with the nonce prefix, i and whether it is the last chunk (STREAM),
+// so chunks can't be reordered and truncation is detected
+struct Chunks {
+    cipher: XChaCha20Poly1305,
+    kind: ObjectKind,
+    // object id authenticated with the last chunk, empty if the object has none
+    id: Vec<u8>,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+}
+
+impl Chunks {
+    fn nonce(&self, index: u32, last: bool) -> [u8; 24] {
+        let mut nonce = [0u8; 24];
+        nonce[..NONCE_PREFIX_SIZE].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_SIZE..23].copy_from_slice(&index.to_be_bytes());
+        nonce[23] = last as u8;
+        nonce
+    }
+
+    fn aad(&self, last: bool) -> Vec<u8> {
+        let mut aad = self.kind.aad().to_vec();
+        if last {
+            aad.extend_from_slice(&self.id);
+        }
+        aad
+    }
+
+    fn seal(&self, index: u32, last: bool, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        self.cipher
+            .encrypt(
+                XNonce::from_slice(&self.nonce(index, last)),
+                Payload {
+                    msg: plaintext,
+                    aad: &self.aad(last),
+                },
+            )
+            .map_err(|_| invalid_data("could not encrypt".to_owned()))
+    }
+
+    fn open(&self, index: u32, last: bool, ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        self.cipher
+            .decrypt(
+                XNonce::from_slice(&self.nonce(index, last)),
+                Payload {
+                    msg: ciphertext,
+                    aad: &self.aad(last),
+                },
+            )
+            .map_err(|_| invalid_data("object was modified or has the wrong key".to_owned()))
+    }
+}
+
+/// encrypts everything written to it, finish must be called to write the last chunk.
+/// the id of the object is only known then, it is usually the hash of what was written
+pub struct EncryptingWriter<W: Write> {
+    chunks: Chunks,
+    inner: W,
+    buf: Vec<u8>,
+    index: u32,
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    pub fn finish(mut self, id: Option<&GittyHash>) -> io::Result<W> {
+        self.chunks.id = id_bytes(id);
+        let sealed = self.chunks.seal(self.index, true, &self.buf)?;
+        self.inner.write_all(&sealed)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        // only a full chunk followed by more data is known not to be the last one
+        let len = cmp::min(data.len(), CHUNK_SIZE + 1 - self.buf.len());
+        self.buf.extend_from_slice(&data[..len]);
+        if self.buf.len() > CHUNK_SIZE {
+            let sealed = self
+                .chunks
+                .seal(self.index, false, &self.buf[..CHUNK_SIZE])?;
+            self.inner.write_all(&sealed)?;
+            self.buf.drain(..CHUNK_SIZE);
+            self.index = self
+                .index
+                .checked_add(1)
+                .ok_or_else(|| invalid_data("object too large".to_owned()))?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// decrypts an object written by EncryptingWriter, with random access
+pub struct DecryptingReader<R: Read + Seek> {
+    chunks: Chunks,
+    inner: R,
+    // plaintext length
+    len: u64,
+    chunk_count: u32,
+    pos: u64,
+    current: Option<(u32, Vec<u8>)>,
+}
+
+impl<R: Read + Seek> DecryptingReader<R> {
+    fn load_chunk(&mut self, index: u32) -> io::Result<()> {
+        if let Some((current, _)) = self.current {
+            if current == index {
+                return Ok(());
+            }
+        }
+        let stored_chunk = (CHUNK_SIZE + TAG_SIZE) as u64;
+        let last = index + 1 == self.chunk_count;
+        let size = if last {
+            self.len - index as u64 * CHUNK_SIZE as u64 + TAG_SIZE as u64
+        } else {
+            stored_chunk
+        };
+        self.inner.seek(SeekFrom::Start(
+            HEADER_SIZE as u64 + index as u64 * stored_chunk,
+        ))?;
+        let mut ciphertext = vec![0u8; size as usize];
+        self.inner.read_exact(&mut ciphertext)?;
+        let plaintext = self.chunks.open(index, last, &ciphertext)?;
+        self.current = Some((index, plaintext));
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = (self.pos / CHUNK_SIZE as u64) as u32;
+        self.load_chunk(index)?;
+        let chunk = &self.current.as_ref().unwrap().1;
+        let offset = (self.pos % CHUNK_SIZE as u64) as usize;
+        let len = cmp::min(buf.len(), chunk.len() - offset);
+        buf[..len].copy_from_slice(&chunk[offset..offset + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for DecryptingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => p as i64,
+            SeekFrom::End(p) => self.len as i64 + p,
+            SeekFrom::Current(p) => self.pos as i64 + p,
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the object",
+            ));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
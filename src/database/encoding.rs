@@ -0,0 +1,442 @@
+use chrono::prelude::*;
+use database::_DBError;
+use database::DBError;
+use model::*;
+use serde_cbor;
+use serde_cbor::Value;
+use serde_json;
+use std::ffi::OsString;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::ffi::OsStringExt;
+use std::vec;
+
+/// Version of the binary encoding of trees and commits.
+///
+/// Objects are CBOR arrays starting with the version, followed by the fields in a fixed order.
+/// There are no maps and integers use the shortest form, so an object has exactly one encoding
+/// and its hash doesn't depend on the serialization library. Adding or changing a field needs a
+/// new version. Trees and commits written by older versions of gitty are JSON.
+///
+/// 1: hashes are the multihash code of their algorithm followed by 32 bytes
+pub const FORMAT_VERSION: u64 = 1;
+
+const TREE_ENTRY: u64 = 0;
+const BLOB_ENTRY: u64 = 1;
+
+#[derive(Debug)]
+pub struct DecodeError(String);
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid object: {}", self.0)
+    }
+}
+
+impl _DBError for DecodeError {
+    fn as_up(&self) -> Box<dyn fmt::Display> {
+        Box::new(self.to_string())
+    }
+}
+
+impl From<DecodeError> for DBError {
+    fn from(e: DecodeError) -> DBError {
+        Box::new(e)
+    }
+}
+
+fn invalid(message: &str) -> DecodeError {
+    DecodeError(message.to_owned())
+}
+
+pub fn encode_tree(tree: &GittyTree) -> Vec<u8> {
+    let entries = tree
+        .entries
+        .iter()
+        .map(|entry| match entry {
+            GittyTreeEntry::Tree(t) => Value::Array(vec![
+                uint(TREE_ENTRY),
+                os_string(&t.name),
+                utc_time(&t.modified),
+                permissions(&t.permissions),
+                hash(&t.hash),
+            ]),
+            GittyTreeEntry::Blob(b) => Value::Array(vec![
+                uint(BLOB_ENTRY),
+                os_string(&b.name),
+                utc_time(&b.modified),
+                permissions(&b.permissions),
+                uint(b.size),
+                Value::Bool(b.is_symlink),
+                Value::Bool(b.unstable),
+                hash(&b.hash),
+            ]),
+        })
+        .collect();
+    to_bytes(Value::Array(vec![
+        uint(FORMAT_VERSION),
+        Value::Array(entries),
+    ]))
+}
+
+pub fn encode_commit(commit: &GittyCommit) -> Vec<u8> {
+    let snapshot = &commit.snapshot;
+    to_bytes(Value::Array(vec![
+        uint(FORMAT_VERSION),
+        author(&commit.author),
+        author(&commit.committer),
+        local_time(&commit.author_time),
+        local_time(&commit.commit_time),
+        Value::Text(commit.message.clone()),
+        uint(commit.depth),
+        Value::Array(commit.parents.iter().map(hash).collect()),
+        hash(&commit.root),
+        Value::Array(vec![
+            Value::Array(
+                snapshot
+                    .skipped_mounts
+                    .iter()
+                    .map(|m| {
+                        Value::Array(vec![
+                            os_string(&m.path),
+                            m.fs_type.clone().map_or(Value::Null, Value::Text),
+                        ])
+                    })
+                    .collect(),
+            ),
+            Value::Array(
+                snapshot
+                    .errors
+                    .iter()
+                    .map(|e| Value::Array(vec![os_string(&e.path), Value::Text(e.message.clone())]))
+                    .collect(),
+            ),
+            Value::Array(snapshot.unstable.iter().map(os_string).collect()),
+            uint(snapshot.files),
+            uint(snapshot.bytes),
+        ]),
+    ]))
+}
+
+pub fn decode_tree(stored: &[u8]) -> Result<GittyTree, DecodeError> {
+    if is_json(stored) {
+        return serde_json::from_slice(stored).map_err(|e| DecodeError(e.to_string()));
+    }
+    let mut fields = from_bytes(stored)?;
+    let mut entries = Vec::new();
+    for entry in fields.array()? {
+        let mut entry = Fields::new(entry)?;
+        let kind = entry.uint()?;
+        let name = entry.os_string()?;
+        let modified = entry.utc_time()?;
+        let permissions = entry.permissions()?;
+        entries.push(match kind {
+            TREE_ENTRY => GittyTreeEntry::Tree(GittyTreeMetadata {
+                name,
+                modified,
+                permissions,
+                hash: entry.hash()?,
+            }),
+            BLOB_ENTRY => GittyTreeEntry::Blob(GittyBlobMetadata {
+                name,
+                modified,
+                permissions,
+                size: entry.uint()?,
+                is_symlink: entry.bool()?,
+                unstable: entry.bool()?,
+                hash: entry.hash()?,
+            }),
+            _ => return Err(invalid("unknown tree entry type")),
+        });
+        entry.end()?;
+    }
+    fields.end()?;
+    Ok(GittyTree { entries })
+}
+
+pub fn decode_commit(stored: &[u8]) -> Result<GittyCommit, DecodeError> {
+    if is_json(stored) {
+        return serde_json::from_slice(stored).map_err(|e| DecodeError(e.to_string()));
+    }
+    let mut fields = from_bytes(stored)?;
+    let author = fields.author()?;
+    let committer = fields.author()?;
+    let author_time = fields.local_time()?;
+    let commit_time = fields.local_time()?;
+    let message = fields.text()?;
+    let depth = fields.uint()?;
+    let parents = fields
+        .array()?
+        .into_iter()
+        .map(|v| Fields::single(v).hash())
+        .collect::<Result<_, _>>()?;
+    let root = fields.hash()?;
+    let mut snapshot_fields = fields.nested()?;
+    let mut snapshot = GittySnapshotInfo::default();
+    for mount in snapshot_fields.array()? {
+        let mut mount = Fields::new(mount)?;
+        snapshot.skipped_mounts.push(GittySkippedMount {
+            path: mount.os_string()?,
+            fs_type: match mount.next()? {
+                Value::Null => None,
+                Value::Text(t) => Some(t),
+                _ => return Err(invalid("expected a filesystem type")),
+            },
+        });
+        mount.end()?;
+    }
+    for error in snapshot_fields.array()? {
+        let mut error = Fields::new(error)?;
+        snapshot.errors.push(GittyWalkError {
+            path: error.os_string()?,
+            message: error.text()?,
+        });
+        error.end()?;
+    }
+    for path in snapshot_fields.array()? {
+        snapshot.unstable.push(Fields::single(path).os_string()?);
+    }
+    snapshot.files = snapshot_fields.uint()?;
+    snapshot.bytes = snapshot_fields.uint()?;
+    snapshot_fields.end()?;
+    fields.end()?;
+    Ok(GittyCommit {
+        author,
+        committer,
+        author_time,
+        commit_time,
+        message,
+        depth,
+        parents,
+        root,
+        snapshot,
+    })
+}
+
+// serde_json always writes an object, the binary format an array
+fn is_json(stored: &[u8]) -> bool {
+    stored.first() == Some(&b'{')
+}
+
+fn to_bytes(value: Value) -> Vec<u8> {
+    // only fails for integers outside of the 64 bit range
+    serde_cbor::to_vec(&value).unwrap_or_else(|_| unreachable!())
+}
+
+fn from_bytes(stored: &[u8]) -> Result<Fields, DecodeError> {
+    let value: Value = serde_cbor::from_slice(stored).map_err(|e| DecodeError(e.to_string()))?;
+    let mut fields = Fields::new(value)?;
+    let version = fields.uint()?;
+    if version != FORMAT_VERSION {
+        return Err(DecodeError(format!(
+            "unsupported format version {}, this version of gitty reads {}",
+            version, FORMAT_VERSION
+        )));
+    }
+    Ok(fields)
+}
+
+fn uint(n: u64) -> Value {
+    Value::Integer(i128::from(n))
+}
+
+fn os_string(s: &OsString) -> Value {
+    Value::Bytes(s.as_bytes().to_vec())
+}
+
+fn hash(hash: &GittyHash) -> Value {
+    let mut bytes = Vec::with_capacity(1 + hash.digest.len());
+    bytes.push(hash.algorithm.code());
+    bytes.extend_from_slice(&hash.digest);
+    Value::Bytes(bytes)
+}
+
+fn utc_time(time: &DateTime<Utc>) -> Value {
+    Value::Array(vec![
+        Value::Integer(i128::from(time.timestamp())),
+        uint(u64::from(time.timestamp_subsec_nanos())),
+    ])
+}
+
+fn local_time(time: &DateTime<FixedOffset>) -> Value {
+    Value::Array(vec![
+        Value::Integer(i128::from(time.timestamp())),
+        uint(u64::from(time.timestamp_subsec_nanos())),
+        Value::Integer(i128::from(time.offset().local_minus_utc())),
+    ])
+}
+
+fn permissions(permissions: &Permissions) -> Value {
+    Value::Array(vec![
+        Value::Text(permissions.kind.clone()),
+        uint(u64::from(permissions.mode)),
+        uint(u64::from(permissions.uid)),
+        uint(u64::from(permissions.gid)),
+    ])
+}
+
+fn author(author: &GittyAuthor) -> Value {
+    Value::Array(vec![
+        Value::Text(author.name.clone()),
+        Value::Text(author.email.clone()),
+    ])
+}
+
+// the elements of an array, read in order
+struct Fields {
+    values: vec::IntoIter<Value>,
+}
+
+impl Fields {
+    fn new(value: Value) -> Result<Fields, DecodeError> {
+        match value {
+            Value::Array(values) => Ok(Fields {
+                values: values.into_iter(),
+            }),
+            _ => Err(invalid("expected an array")),
+        }
+    }
+
+    // a value that is not an array, e.g. an element of an array field, read as one field
+    fn single(value: Value) -> Fields {
+        Fields {
+            values: vec![value].into_iter(),
+        }
+    }
+
+    // the next field, which is an array
+    fn nested(&mut self) -> Result<Fields, DecodeError> {
+        Fields::new(self.next()?)
+    }
+
+    fn next(&mut self) -> Result<Value, DecodeError> {
+        self.values.next().ok_or_else(|| invalid("missing field"))
+    }
+
+    fn end(&mut self) -> Result<(), DecodeError> {
+        match self.values.next() {
+            Some(_) => Err(invalid("unexpected field")),
+            None => Ok(()),
+        }
+    }
+
+    fn int(&mut self) -> Result<i64, DecodeError> {
+        match self.next()? {
+            Value::Integer(n) if n >= i128::from(i64::MIN) && n <= i128::from(i64::MAX) => {
+                Ok(n as i64)
+            }
+            _ => Err(invalid("expected an integer")),
+        }
+    }
+
+    fn uint(&mut self) -> Result<u64, DecodeError> {
+        match self.next()? {
+            Value::Integer(n) if n >= 0 && n <= i128::from(u64::MAX) => Ok(n as u64),
+            _ => Err(invalid("expected an unsigned integer")),
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        let n = self.uint()?;
+        if n > u64::from(u32::MAX) {
+            return Err(invalid("integer out of range"));
+        }
+        Ok(n as u32)
+    }
+
+    fn bool(&mut self) -> Result<bool, DecodeError> {
+        match self.next()? {
+            Value::Bool(b) => Ok(b),
+            _ => Err(invalid("expected a boolean")),
+        }
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, DecodeError> {
+        match self.next()? {
+            Value::Bytes(b) => Ok(b),
+            _ => Err(invalid("expected bytes")),
+        }
+    }
+
+    fn text(&mut self) -> Result<String, DecodeError> {
+        match self.next()? {
+            Value::Text(t) => Ok(t),
+            _ => Err(invalid("expected a string")),
+        }
+    }
+
+    fn array(&mut self) -> Result<Vec<Value>, DecodeError> {
+        match self.next()? {
+            Value::Array(values) => Ok(values),
+            _ => Err(invalid("expected an array")),
+        }
+    }
+
+    fn os_string(&mut self) -> Result<OsString, DecodeError> {
+        Ok(OsString::from_vec(self.bytes()?))
+    }
+
+    fn hash(&mut self) -> Result<GittyHash, DecodeError> {
+        let bytes = self.bytes()?;
+        let (algorithm, digest) = match bytes.split_first() {
+            Some((&code, digest)) => (
+                HashAlgorithm::from_code(code).ok_or_else(|| invalid("unknown hash algorithm"))?,
+                digest,
+            ),
+            None => return Err(invalid("empty hash")),
+        };
+        if digest.len() != 32 {
+            return Err(invalid("invalid hash length"));
+        }
+        let mut result = GittyHash {
+            algorithm,
+            digest: [0; 32],
+        };
+        result.digest.copy_from_slice(digest);
+        Ok(result)
+    }
+
+    fn utc_time(&mut self) -> Result<DateTime<Utc>, DecodeError> {
+        let mut time = self.nested()?;
+        let (secs, nanos) = (time.int()?, time.u32()?);
+        time.end()?;
+        Utc.timestamp_opt(secs, nanos)
+            .single()
+            .ok_or_else(|| invalid("invalid time"))
+    }
+
+    fn local_time(&mut self) -> Result<DateTime<FixedOffset>, DecodeError> {
+        let mut time = self.nested()?;
+        let (secs, nanos, offset) = (time.int()?, time.u32()?, time.int()?);
+        time.end()?;
+        if offset.abs() >= 86_400 {
+            return Err(invalid("invalid time zone"));
+        }
+        FixedOffset::east(offset as i32)
+            .timestamp_opt(secs, nanos)
+            .single()
+            .ok_or_else(|| invalid("invalid time"))
+    }
+
+    fn permissions(&mut self) -> Result<Permissions, DecodeError> {
+        let mut permissions = self.nested()?;
+        let result = Permissions {
+            kind: permissions.text()?,
+            mode: permissions.u32()?,
+            uid: permissions.u32()?,
+            gid: permissions.u32()?,
+        };
+        permissions.end()?;
+        Ok(result)
+    }
+
+    fn author(&mut self) -> Result<GittyAuthor, DecodeError> {
+        let mut author = self.nested()?;
+        let result = GittyAuthor {
+            name: author.text()?,
+            email: author.text()?,
+        };
+        author.end()?;
+        Ok(result)
+    }
+}
@@ -1,6 +1,16 @@
+use commits;
 use commits::create_commit;
+use database::commit_graph;
+use database::commit_graph::CommitGraphEntry;
+use database::config::RepoConfig;
+use database::crypto::KeySource;
+use database::crypto::ObjectHasher;
+use database::crypto::ObjectKind;
+use database::crypto::RepoKeys;
+use database::encoding;
+use database::pack::Packs;
+use database::pack::SMALL_OBJECT_SIZE;
 use database::*;
-use digest::Digest;
 use hex;
 use model::GittyObjectRef::*;
 use rand::OsRng;
@@ -12,25 +22,103 @@ use std::io::Read;
 use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 
 #[derive(Clone)]
 pub struct FSDatabaseConfig {
     pub root: PathBuf,
     pub object_prefix_length: usize,
-    // hasher: GittyHasher
+    // needed for encrypted repositories, a new repository is encrypted if given
+    pub key: Option<KeySource>,
+    // hash function of a new repository, existing ones keep theirs
+    pub hash_algorithm: HashAlgorithm,
 }
 pub struct FSDatabase {
     config: FSDatabaseConfig,
+    // None if the repository is not encrypted
+    keys: Option<RepoKeys>,
+    hash_algorithm: HashAlgorithm,
+    // small objects, the others are stored as one file each (loose)
+    packs: Packs,
+    // written by a version without a format version, objects are written in its layout until
+    // the repository is migrated so that version can still read it
+    legacy: AtomicBool,
+}
+
+/// what repack moved from loose files into packs
+#[derive(Clone, Debug, Default)]
+pub struct RepackStats {
+    pub objects: u64,
+    pub bytes: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MigrateStats {
+    pub from_version: u32,
+    // trees and commits stored in the current encoding
+    pub trees: u64,
+    pub commits: u64,
+    pub repacked: RepackStats,
+}
+
+fn repo_error(e: impl Display + 'static) -> GittyError {
+    GittyError::new(String::from("Repository"), Box::new(e))
+}
+
+fn add_stats(stats: &mut StorageStats, kind: ObjectKind, objects: u64, bytes: u64) {
+    let (count, size) = match kind {
+        ObjectKind::Blob => (&mut stats.blobs, &mut stats.blob_bytes),
+        ObjectKind::Tree => (&mut stats.trees, &mut stats.tree_bytes),
+        ObjectKind::Commit => (&mut stats.commits, &mut stats.commit_bytes),
+    };
+    *count += objects;
+    *size += bytes;
 }
 
 impl FSDatabase {
-    pub fn open(config: FSDatabaseConfig) -> Option<FSDatabase> {
-        let db = FSDatabase { config };
-        if db.head_path().exists() {
-            Some(db)
-        } else {
-            None
+    pub fn open(config: FSDatabaseConfig) -> Result<Option<FSDatabase>, GittyError> {
+        let packs = Packs::open(config.root.join("pack")).map_err(repo_error)?;
+        let mut db = FSDatabase {
+            config,
+            keys: None,
+            hash_algorithm: HashAlgorithm::Sha256,
+            packs,
+            legacy: AtomicBool::new(false),
+        };
+        if !db.head_path().exists() {
+            return Ok(None);
+        }
+        let repo_config = RepoConfig::load(&db.config_path()).map_err(repo_error)?;
+        repo_config.check_supported().map_err(repo_error)?;
+        if repo_config.is_outdated() {
+            warn!(
+                "{} has an old repository format, run gitty migrate to upgrade it",
+                db.config.root.display()
+            );
+            db.legacy = AtomicBool::new(true);
         }
+        db.keys = match (repo_config.encryption, &db.config.key) {
+            (Some(encryption), Some(key)) => {
+                Some(RepoKeys::unwrap(&encryption, key).map_err(repo_error)?)
+            }
+            (Some(_), None) => {
+                return Err(repo_error(
+                    "the repository is encrypted, a key file or passphrase is needed",
+                ))
+            }
+            (None, Some(_)) => return Err(repo_error("the repository is not encrypted")),
+            (None, None) => None,
+        };
+        db.hash_algorithm = repo_config.hash_algorithm;
+        if let Some(ref keys) = db.keys {
+            if keys.hash_algorithm() != db.hash_algorithm {
+                return Err(repo_error(
+                    "the object ids of the encryption don't match the hash algorithm",
+                ));
+            }
+        }
+        Ok(Some(db))
     }
     pub fn create(config: FSDatabaseConfig) -> Result<FSDatabase, GittyError> {
         if config.root.exists() {
@@ -39,7 +127,25 @@ impl FSDatabase {
                 Box::new(format!("{} already exists", config.root.display())),
             ))
         } else {
-            let mut db = FSDatabase { config };
+            let packs = Packs::open(config.root.join("pack")).map_err(repo_error)?;
+            let hash_algorithm = config.hash_algorithm;
+            let mut db = FSDatabase {
+                config,
+                keys: None,
+                hash_algorithm,
+                packs,
+                legacy: AtomicBool::new(false),
+            };
+            let mut encryption = None;
+            if let Some(ref key) = db.config.key {
+                let keys = RepoKeys::generate(hash_algorithm).map_err(repo_error)?;
+                encryption = Some(keys.wrap(key).map_err(repo_error)?);
+                db.keys = Some(keys);
+            }
+            fs::create_dir_all(&db.config.root).map_err(repo_error)?;
+            RepoConfig::current(encryption, hash_algorithm)
+                .save(&db.config_path())
+                .map_err(repo_error)?;
             let empty_tree = db.store_tree(GittyTree { entries: vec![] })?;
             let first_commit = create_commit(empty_tree, vec![], 0);
             let commit_ref = db.store_commit(first_commit)?;
@@ -48,27 +154,265 @@ impl FSDatabase {
         }
     }
 
-    pub fn create_or_open(dbdir: &Path) -> Result<impl GittyDatabase, impl Display> {
-        let config = FSDatabaseConfig {
+    pub fn create_or_open(dbdir: &Path, key: Option<KeySource>) -> Result<FSDatabase, GittyError> {
+        FSDatabase::open_or_create(FSDatabaseConfig {
             root: dbdir.to_path_buf(),
             object_prefix_length: 3,
-        };
-        FSDatabase::open(config.clone()).ok_or("no").or_else(|_| {
-            info!("Creating new database in {}", dbdir.to_path_buf().display());
-            FSDatabase::create(config)
+            key,
+            hash_algorithm: HashAlgorithm::default(),
         })
     }
 
-    fn store_symlink(&mut self, in_path: &Path) -> Result<GittyBlobRef, DBError> {
-        debug!("DB: store blob {} NOOP", in_path.to_string_lossy());
-        Ok(GittyBlobRef {
-            hash: PLACEHOLDER_HASH,
-        })
+    pub fn open_or_create(config: FSDatabaseConfig) -> Result<FSDatabase, GittyError> {
+        match FSDatabase::open(config.clone())? {
+            Some(db) => Ok(db),
+            None => {
+                info!("Creating new database in {}", config.root.display());
+                FSDatabase::create(config)
+            }
+        }
+    }
+
+    /// protect the master key with a new key file or passphrase. objects are not re-encrypted
+    pub fn change_key(&self, new_key: &KeySource) -> Result<(), GittyError> {
+        let keys = self
+            .keys
+            .as_ref()
+            .ok_or_else(|| repo_error("the repository is not encrypted"))?;
+        let mut repo_config = RepoConfig::load(&self.config_path()).map_err(repo_error)?;
+        repo_config.encryption = Some(keys.wrap(new_key).map_err(repo_error)?);
+        repo_config.save(&self.config_path()).map_err(repo_error)
+    }
+
+    /// move small loose objects into packs
+    pub fn repack(&self) -> Result<RepackStats, GittyError> {
+        if self.is_legacy() {
+            // the versions that wrote it don't know packs
+            return Err(repo_error(format!(
+                "{} has an old repository format, run gitty migrate before repacking it",
+                self.config.root.display()
+            )));
+        }
+        let mut stats = RepackStats::default();
+        let mut packed = Vec::new();
+        for (kind, dir) in &[
+            (ObjectKind::Blob, "file"),
+            (ObjectKind::Tree, "tree"),
+            (ObjectKind::Commit, "commit"),
+        ] {
+            let dir = self.config.root.join(dir);
+            if !dir.exists() {
+                continue;
+            }
+            for prefix_dir in fs::read_dir(&dir).map_err(repo_error)? {
+                let prefix_dir = prefix_dir.map_err(repo_error)?;
+                if !prefix_dir.file_type().map_err(repo_error)?.is_dir() {
+                    warn!("ignoring {}", prefix_dir.path().display());
+                    continue;
+                }
+                let prefix_dir = prefix_dir.path();
+                for entry in fs::read_dir(&prefix_dir).map_err(repo_error)? {
+                    let entry = entry.map_err(repo_error)?;
+                    if entry.metadata().map_err(repo_error)?.len() >= SMALL_OBJECT_SIZE {
+                        continue;
+                    }
+                    let hash =
+                        match parse_object_path(&prefix_dir, &entry.path(), self.hash_algorithm) {
+                            Some(hash) => hash,
+                            None => {
+                                warn!("ignoring {}", entry.path().display());
+                                continue;
+                            }
+                        };
+                    let stored = fs::read(entry.path()).map_err(repo_error)?;
+                    self.packs.add(*kind, &hash, &stored).map_err(repo_error)?;
+                    stats.objects += 1;
+                    stats.bytes += stored.len() as u64;
+                    packed.push(entry.path());
+                }
+            }
+        }
+        // the loose files may only go once the index refers to their copies
+        self.packs.flush().map_err(repo_error)?;
+        for path in packed {
+            fs::remove_file(&path).map_err(repo_error)?;
+            // fails if the directory is not empty yet
+            let _ = fs::remove_dir(path.parent().unwrap());
+        }
+        Ok(stats)
+    }
+
+    /// upgrade the repository to the current format. can be run again if it was interrupted
+    /// before the config was saved, later steps only clean up
+    pub fn migrate(&self) -> Result<MigrateStats, GittyError> {
+        let repo_config = RepoConfig::load(&self.config_path()).map_err(repo_error)?;
+        let mut stats = MigrateStats {
+            from_version: repo_config.format_version,
+            ..MigrateStats::default()
+        };
+        if !repo_config.is_outdated() {
+            return Ok(stats);
+        }
+        // everything written from here on is in the current format
+        self.legacy.store(false, Ordering::SeqCst);
+        // JSON trees and commits to binary
+        let history = commits::reencode_history(self)?;
+        stats.trees = history.replaced_trees.len() as u64;
+        stats.commits = history.replaced_commits.len() as u64;
+        // before HEAD points to objects that versions reading the old format would not understand
+        RepoConfig::current(repo_config.encryption, repo_config.hash_algorithm)
+            .save(&self.config_path())
+            .map_err(repo_error)?;
+        self.update_head_commit(&history.head)?;
+        let replaced_trees = history.replaced_trees.iter().map(GittyObjectRef::Tree);
+        let replaced_commits = history.replaced_commits.iter().map(GittyObjectRef::Commit);
+        for object_ref in replaced_trees.chain(replaced_commits) {
+            // objects in packs stay, only old versions have loose trees and commits
+            let path = get_object_path(&self.config, &object_ref);
+            if path.exists() {
+                fs::remove_file(&path).map_err(repo_error)?;
+                let _ = fs::remove_dir(path.parent().unwrap());
+            }
+        }
+        stats.repacked = self.repack()?;
+        Ok(stats)
+    }
+
+    // trees and commits are JSON and every object has its own file
+    fn is_legacy(&self) -> bool {
+        self.legacy.load(Ordering::SeqCst)
+    }
+
+    fn object_hasher(&self) -> ObjectHasher {
+        match self.keys {
+            Some(ref keys) => keys.object_hasher(),
+            None => ObjectHasher::Hasher(GittyHasher::new(self.hash_algorithm)),
+        }
+    }
+
+    // id and stored form of a serialized tree or commit
+    fn encode_object(
+        &self,
+        kind: ObjectKind,
+        serialized: Vec<u8>,
+    ) -> Result<(GittyHash, Vec<u8>), DBError> {
+        let mut hasher = self.object_hasher();
+        hasher.input(&serialized);
+        let hash = hasher.result();
+        match self.keys {
+            Some(ref keys) => {
+                let stored = keys.seal(kind, Some(&hash), &serialized)?;
+                Ok((hash, stored))
+            }
+            None => Ok((hash, serialized)),
+        }
+    }
+
+    fn decode_object(
+        &self,
+        kind: ObjectKind,
+        hash: &GittyHash,
+        stored: Vec<u8>,
+    ) -> Result<Vec<u8>, DBError> {
+        match self.keys {
+            // fails if this is not the object that was asked for
+            Some(ref keys) => Ok(keys.open(kind, Some(hash), stored)?),
+            None => Ok(stored),
+        }
+    }
+
+    // small objects go into a pack, the others into their own file
+    fn write_object(
+        &self,
+        kind: ObjectKind,
+        object_ref: GittyObjectRef,
+        stored: &[u8],
+    ) -> Result<(), DBError> {
+        let hash = match object_ref {
+            Blob(b) => &b.hash,
+            Tree(t) => &t.hash,
+            Commit(c) => &c.hash,
+        };
+        if (stored.len() as u64) < SMALL_OBJECT_SIZE && !self.is_legacy() {
+            self.packs.add(kind, hash, stored)?;
+        } else {
+            let out_path = get_object_path(&self.config, &object_ref);
+            fs::create_dir_all(out_path.parent().unwrap())?;
+            fs::write(out_path, stored)?;
+        }
+        Ok(())
+    }
+
+    fn read_object(
+        &self,
+        kind: ObjectKind,
+        object_ref: GittyObjectRef,
+    ) -> Result<Vec<u8>, DBError> {
+        let hash = match object_ref {
+            Blob(b) => &b.hash,
+            Tree(t) => &t.hash,
+            Commit(c) => &c.hash,
+        };
+        match self.packs.read(kind, hash)? {
+            Some(stored) => Ok(stored),
+            None => Ok(fs::read(get_object_path(&self.config, &object_ref))?),
+        }
+    }
+
+    fn config_path(&self) -> PathBuf {
+        self.config.root.join("config")
     }
 
     fn head_path(&self) -> PathBuf {
         self.config.root.join("HEAD")
     }
+
+    fn commit_graph_path(&self) -> PathBuf {
+        self.config.root.join("commit-graph")
+    }
+
+    // empty if there is none yet, it can always be built again from the commits
+    fn read_commit_graph(&self) -> Vec<CommitGraphEntry> {
+        let path = self.commit_graph_path();
+        if !path.exists() {
+            return vec![];
+        }
+        let read = fs::read(&path).and_then(|stored| match self.keys {
+            Some(ref keys) => keys.open(ObjectKind::Commit, None, stored),
+            None => Ok(stored),
+        });
+        match read.and_then(|content| commit_graph::decode(&content)) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("ignoring {}: {}", path.display(), e);
+                vec![]
+            }
+        }
+    }
+
+    fn write_commit_graph(&self, entries: &[CommitGraphEntry]) -> Result<(), DBError> {
+        let content = commit_graph::encode(entries);
+        // commit times are not revealed by an encrypted repository
+        let stored = match self.keys {
+            Some(ref keys) => keys.seal(ObjectKind::Commit, None, &content)?,
+            None => content,
+        };
+        // a unique name, a snapshot and a mount can write the graph at the same time
+        let tmp_path = get_temp_path(&self.config);
+        fs::create_dir_all(tmp_path.parent().unwrap())?;
+        let written = fs::write(&tmp_path, stored)
+            .and_then(|_| fs::rename(&tmp_path, self.commit_graph_path()));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(DBError::from(e));
+        }
+        Ok(())
+    }
+
+    /// repository level exclude patterns, applied to every snapshot
+    pub fn exclude_path(&self) -> PathBuf {
+        self.config.root.join("exclude")
+    }
 }
 
 struct SerializeError {
@@ -100,13 +444,33 @@ fn get_object_path(config: &FSDatabaseConfig, object_ref: &GittyObjectRef) -> Pa
         Commit(c) => (&c.hash, "commit"),
     };
     p.push(parent);
-    let mut hash_str = hex::encode(hash.sha256);
+    let mut hash_str = hex::encode(hash.digest);
     let suffix = hash_str.split_off(config.object_prefix_length);
     p.push(hash_str);
     p.push(suffix);
     return p;
 }
 
+// inverse of get_object_path
+fn parse_object_path(
+    prefix_dir: &Path,
+    path: &Path,
+    algorithm: HashAlgorithm,
+) -> Option<GittyHash> {
+    let hash_str = format!(
+        "{}{}",
+        prefix_dir.file_name()?.to_str()?,
+        path.file_name()?.to_str()?
+    );
+    let bytes = hex::decode(hash_str).ok()?;
+    if bytes.len() != 32 {
+        return None;
+    }
+    let mut digest = [0u8; 32];
+    digest.copy_from_slice(&bytes);
+    Some(GittyHash { algorithm, digest })
+}
+
 fn get_temp_path(config: &FSDatabaseConfig) -> PathBuf {
     let mut p = config.root.clone();
     p.push("temp");
@@ -120,9 +484,9 @@ fn get_temp_path(config: &FSDatabaseConfig) -> PathBuf {
 const COPY_BUF_SIZE: usize = 1024 * 1024;
 // https://doc.rust-lang.org/src/std/io/util.rs.html#48-68
 pub fn hashing_copy(
-    reader: &mut impl Read,
+    reader: &mut (impl Read + ?Sized),
     writer: &mut impl Write,
-    hasher: &mut impl Digest,
+    hasher: &mut dyn FnMut(&[u8]),
 ) -> std::io::Result<u64> {
     let mut buf = Box::new([0u8; COPY_BUF_SIZE]);
 
@@ -135,7 +499,7 @@ pub fn hashing_copy(
             Err(e) => return Err(e),
         };
         let buf_part = &buf[..len];
-        hasher.input(buf_part);
+        hasher(buf_part);
         writer.write_all(buf_part)?;
         written += len as u64;
     }
@@ -147,82 +511,187 @@ fn wrap_serde_err(e: serde_json::Error) -> DBError {
     return b;
 }
 impl GittyDatabase for FSDatabase {
-    fn store_blob(&mut self, in_path: &Path, is_symlink: bool) -> Result<GittyBlobRef, DBError> {
+    fn store_blob(&self, in_path: &Path, is_symlink: bool) -> Result<StoredBlob, DBError> {
         if is_symlink {
-            return self.store_symlink(in_path);
+            let target = fs::read_link(in_path)?;
+            debug!("storing symlink {:?} -> {:?}", in_path, target);
+            return self.store_symlink_target(&target);
         }
-        debug!("DB: store blob {} NOOP", in_path.to_string_lossy());
+        debug!("copying {:?} while hashing", in_path);
+        self.store_blob_from_reader(&mut File::open(in_path)?)
+    }
 
+    fn store_blob_from_reader(&self, reader: &mut dyn Read) -> Result<StoredBlob, DBError> {
         let tmp_out_path = get_temp_path(&self.config);
         fs::create_dir_all(tmp_out_path.parent().unwrap())?;
 
-        debug!("copying {:?} to {:?} while hashing", in_path, &tmp_out_path);
-        let mut reader = File::open(in_path)?;
         let mut writer = File::create(&tmp_out_path)?;
-        let mut hasher = get_hasher();
-        hashing_copy(&mut reader, &mut writer, &mut hasher)?;
+        let mut hasher = self.object_hasher();
+        let copied = match self.keys {
+            Some(ref keys) => {
+                keys.encrypting_writer(ObjectKind::Blob, writer)
+                    .and_then(|mut writer| {
+                        let size = hashing_copy(reader, &mut writer, &mut |b| hasher.input(b))?;
+                        let hash = hasher.result();
+                        writer.finish(Some(&hash)).map(|_| (size, hash))
+                    })
+            }
+            None => hashing_copy(reader, &mut writer, &mut |b| hasher.input(b))
+                .map(|size| (size, hasher.result())),
+        };
+        let (size, hash) = match copied {
+            Ok(copied) => copied,
+            Err(e) => {
+                let _ = fs::remove_file(&tmp_out_path);
+                return Err(DBError::from(e));
+            }
+        };
 
-        let hash = hasher_output(hasher);
         let blob_ref = GittyBlobRef { hash };
 
         let out_path = get_object_path(&self.config, &GittyObjectRef::Blob(&blob_ref));
-        debug!("moving {:?} to {:?}", tmp_out_path, out_path);
-        fs::create_dir_all(out_path.parent().unwrap())?;
-
-        fs::rename(tmp_out_path, out_path)?;
-        Ok(blob_ref)
+        let deduplicated =
+            self.packs.contains(ObjectKind::Blob, &blob_ref.hash) || out_path.exists();
+        if deduplicated {
+            debug!("{:?} already exists", out_path);
+            fs::remove_file(tmp_out_path)?;
+        } else if fs::metadata(&tmp_out_path)?.len() < SMALL_OBJECT_SIZE && !self.is_legacy() {
+            debug!("packing {:?}", tmp_out_path);
+            let stored = fs::read(&tmp_out_path)?;
+            self.packs.add(ObjectKind::Blob, &blob_ref.hash, &stored)?;
+            fs::remove_file(tmp_out_path)?;
+        } else {
+            debug!("moving {:?} to {:?}", tmp_out_path, out_path);
+            fs::create_dir_all(out_path.parent().unwrap())?;
+            fs::rename(tmp_out_path, out_path)?;
+        }
+        Ok(StoredBlob {
+            blob_ref,
+            deduplicated,
+            size,
+        })
     }
 
-    fn store_tree(&mut self, tree: GittyTree) -> Result<GittyTreeRef, DBError> {
-        let serialized = serde_json::to_string(&tree).map_err(wrap_serde_err)?;
-        let mut hasher = get_hasher();
-        hasher.input(serialized.as_bytes());
-        let hash = hasher_output(hasher);
+    fn store_tree(&self, tree: GittyTree) -> Result<GittyTreeRef, DBError> {
+        let serialized = if self.is_legacy() {
+            serde_json::to_vec(&tree).map_err(wrap_serde_err)?
+        } else {
+            encoding::encode_tree(&tree)
+        };
+        let (hash, stored) = self.encode_object(ObjectKind::Tree, serialized)?;
         let tree_ref = GittyTreeRef { hash };
-        let out_path = get_object_path(&self.config, &GittyObjectRef::Tree(&tree_ref));
-        debug!(
-            "DB: stored tree {} as {}",
-            out_path.to_string_lossy(),
-            serde_json::to_string_pretty(&tree).unwrap(),
-        );
-        fs::create_dir_all(out_path.parent().unwrap())?;
-        fs::write(out_path, serialized)?;
+        if self.keys.is_some() {
+            // the names are what the encryption hides
+            debug!("DB: stored tree {}", tree_ref.hash);
+        } else {
+            debug!(
+                "DB: stored tree {} as {}",
+                tree_ref.hash,
+                serde_json::to_string_pretty(&tree).unwrap(),
+            );
+        }
+        self.write_object(ObjectKind::Tree, GittyObjectRef::Tree(&tree_ref), &stored)?;
         Ok(tree_ref)
     }
 
     // TODO: code duplication with store_tree
-    fn store_commit(&mut self, commit: GittyCommit) -> Result<GittyCommitRef, DBError> {
-        let serialized = serde_json::to_string(&commit).map_err(wrap_serde_err)?;
-        let mut hasher = get_hasher();
-        hasher.input(serialized.as_bytes());
-        let hash = hasher_output(hasher);
+    fn store_commit(&self, commit: GittyCommit) -> Result<GittyCommitRef, DBError> {
+        let serialized = if self.is_legacy() {
+            serde_json::to_vec(&commit).map_err(wrap_serde_err)?
+        } else {
+            encoding::encode_commit(&commit)
+        };
+        let (hash, stored) = self.encode_object(ObjectKind::Commit, serialized)?;
         let commit_ref = GittyCommitRef { hash };
-        let out_path = get_object_path(&self.config, &GittyObjectRef::Commit(&commit_ref));
-        debug!(
-            "DB: stored commit {} as {}",
-            out_path.to_string_lossy(),
-            serde_json::to_string_pretty(&commit).unwrap(),
-        );
-        fs::create_dir_all(out_path.parent().unwrap())?;
-        fs::write(out_path, serialized)?;
+        if self.keys.is_some() {
+            debug!("DB: stored commit {}", commit_ref.hash);
+        } else {
+            debug!(
+                "DB: stored commit {} as {}",
+                commit_ref.hash,
+                serde_json::to_string_pretty(&commit).unwrap(),
+            );
+        }
+        self.write_object(
+            ObjectKind::Commit,
+            GittyObjectRef::Commit(&commit_ref),
+            &stored,
+        )?;
         Ok(commit_ref)
     }
 
-    fn load_blob(&self, blob_ref: &GittyBlobRef) -> Result<PathBuf, DBError> {
+    fn load_blob(&self, blob_ref: &GittyBlobRef) -> Result<BlobReader, DBError> {
+        if let Some(range) = self.packs.open_range(ObjectKind::Blob, &blob_ref.hash)? {
+            return match self.keys {
+                Some(ref keys) => Ok(Box::new(keys.decrypting_reader(
+                    ObjectKind::Blob,
+                    Some(&blob_ref.hash),
+                    range,
+                )?)),
+                None => Ok(Box::new(range)),
+            };
+        }
         let path = get_object_path(&self.config, &GittyObjectRef::Blob(blob_ref));
-        Ok(path)
+        let file = File::open(path)?;
+        match self.keys {
+            Some(ref keys) => Ok(Box::new(keys.decrypting_reader(
+                ObjectKind::Blob,
+                Some(&blob_ref.hash),
+                file,
+            )?)),
+            None => Ok(Box::new(file)),
+        }
     }
     fn load_tree(&self, tree_ref: &GittyTreeRef) -> Result<GittyTree, DBError> {
-        let path = get_object_path(&self.config, &GittyObjectRef::Tree(tree_ref));
-        let reader = File::open(path)?;
-        let res = serde_json::from_reader(reader).map_err(wrap_serde_err)?;
-        Ok(res)
+        let stored = self.read_object(ObjectKind::Tree, GittyObjectRef::Tree(tree_ref))?;
+        let serialized = self.decode_object(ObjectKind::Tree, &tree_ref.hash, stored)?;
+        Ok(encoding::decode_tree(&serialized)?)
     }
     fn load_commit(&self, commit_ref: &GittyCommitRef) -> Result<GittyCommit, DBError> {
-        let path = get_object_path(&self.config, &GittyObjectRef::Commit(commit_ref));
-        let reader = File::open(path)?;
-        let res = serde_json::from_reader(reader).map_err(wrap_serde_err)?;
-        Ok(res)
+        let stored = self.read_object(ObjectKind::Commit, GittyObjectRef::Commit(commit_ref))?;
+        let serialized = self.decode_object(ObjectKind::Commit, &commit_ref.hash, stored)?;
+        Ok(encoding::decode_commit(&serialized)?)
+    }
+    fn commit_graph(&self) -> Result<Vec<CommitGraphEntry>, DBError> {
+        let head = self.get_head_commit()?;
+        let known = self.read_commit_graph();
+        if known.first().map(|e| &e.commit_ref) == Some(&head) {
+            return Ok(known);
+        }
+        // HEAD was moved by an older version or the graph was lost
+        let entries = commit_graph::update(self, &head, known)?;
+        if let Err(e) = self.write_commit_graph(&entries) {
+            // e.g. a read-only repository, the graph is built again next time
+            warn!("could not update the commit graph: {}", e.as_up());
+        }
+        Ok(entries)
+    }
+    fn storage_stats(&self) -> Result<StorageStats, DBError> {
+        let mut stats = StorageStats::default();
+        for (kind, (objects, bytes)) in self.packs.stats()? {
+            add_stats(&mut stats, kind, objects, bytes);
+        }
+        // loose objects
+        for (kind, dir) in &[
+            (ObjectKind::Blob, "file"),
+            (ObjectKind::Tree, "tree"),
+            (ObjectKind::Commit, "commit"),
+        ] {
+            let dir = self.config.root.join(dir);
+            if !dir.exists() {
+                continue;
+            }
+            for prefix_dir in fs::read_dir(&dir)? {
+                let prefix_dir = prefix_dir?;
+                if !prefix_dir.file_type()?.is_dir() {
+                    continue;
+                }
+                for entry in fs::read_dir(prefix_dir.path())? {
+                    add_stats(&mut stats, *kind, 1, entry?.metadata()?.len());
+                }
+            }
+        }
+        Ok(stats)
     }
 
     fn get_head_commit(&self) -> Result<GittyCommitRef, DBError> {
@@ -230,8 +699,26 @@ impl GittyDatabase for FSDatabase {
         Ok(serde_json::from_reader(File::open(head_path)?).map_err(wrap_serde_err)?)
     }
     fn update_head_commit(&self, commit_ref: &GittyCommitRef) -> Result<(), DBError> {
+        // the new head must not refer to objects in an unfinished pack
+        self.packs.flush()?;
         let head_path = self.head_path();
         serde_json::to_writer(File::create(head_path)?, commit_ref).map_err(wrap_serde_err)?;
+        // the snapshot is stored by now, the graph is only a cache
+        let updated = commit_graph::update(self, commit_ref, self.read_commit_graph())
+            .and_then(|entries| self.write_commit_graph(&entries));
+        if let Err(e) = updated {
+            warn!("could not update the commit graph: {}", e.as_up());
+            // it's built again from the commits next time
+            let _ = fs::remove_file(self.commit_graph_path());
+        }
         Ok(())
     }
 }
+
+impl Drop for FSDatabase {
+    fn drop(&mut self) {
+        if let Err(e) = self.packs.flush() {
+            error!("could not finish pack: {}", e);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use commits::create_commit;
+use database::commit_graph;
+use database::commit_graph::CommitGraphEntry;
+use database::encoding;
+use database::*;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Cursor;
+use std::io::Read;
+use std::sync::Mutex;
+
+// size and content (None if only the hashes are kept)
+type BlobContent = (u64, Option<Vec<u8>>);
+
+/// Database that keeps everything in memory, for tests and dry runs.
+///
+/// Objects get the same hashes as in an unencrypted FSDatabase that uses the default hash
+/// algorithm. Encrypted repositories key their hashes, so their ids differ.
+pub struct MemoryDatabase {
+    blobs: Mutex<HashMap<GittyBlobRef, BlobContent>>,
+    trees: Mutex<HashMap<GittyTreeRef, (GittyTree, u64)>>,
+    commits: Mutex<HashMap<GittyCommitRef, GittyCommit>>,
+    head: Mutex<GittyCommitRef>,
+    keep_content: bool,
+    hash_algorithm: HashAlgorithm,
+}
+
+struct NotFound(String);
+impl _DBError for NotFound {
+    fn as_up(&self) -> Box<dyn Display> {
+        Box::new(format!("{} not found", self.0))
+    }
+}
+fn not_found(what: String) -> DBError {
+    Box::new(NotFound(what))
+}
+
+impl Default for MemoryDatabase {
+    fn default() -> MemoryDatabase {
+        MemoryDatabase::new()
+    }
+}
+
+impl MemoryDatabase {
+    pub fn new() -> MemoryDatabase {
+        MemoryDatabase::create(true)
+    }
+
+    /// only hash blobs instead of storing them, load_blob will fail
+    pub fn without_content() -> MemoryDatabase {
+        MemoryDatabase::create(false)
+    }
+
+    fn create(keep_content: bool) -> MemoryDatabase {
+        let db = MemoryDatabase {
+            blobs: Mutex::new(HashMap::new()),
+            trees: Mutex::new(HashMap::new()),
+            commits: Mutex::new(HashMap::new()),
+            head: Mutex::new(GittyCommitRef {
+                hash: PLACEHOLDER_HASH,
+            }),
+            keep_content,
+            // always unkeyed
+            hash_algorithm: HashAlgorithm::default(),
+        };
+        // same initial state as a new FSDatabase
+        let empty_tree = db
+            .store_tree(GittyTree { entries: vec![] })
+            .unwrap_or_else(|_| unreachable!());
+        let first_commit = create_commit(empty_tree, vec![], 0);
+        let commit_ref = db
+            .store_commit(first_commit)
+            .unwrap_or_else(|_| unreachable!());
+        *db.head.lock().unwrap() = commit_ref;
+        db
+    }
+
+    fn hash_encoded(&self, encoded: &[u8]) -> (GittyHash, u64) {
+        let mut hasher = GittyHasher::new(self.hash_algorithm);
+        hasher.input(encoded);
+        (hasher.result(), encoded.len() as u64)
+    }
+
+    pub fn stats(&self) -> StorageStats {
+        let blobs = self.blobs.lock().unwrap();
+        let trees = self.trees.lock().unwrap();
+        let commits = self.commits.lock().unwrap();
+        StorageStats {
+            blobs: blobs.len() as u64,
+            blob_bytes: blobs.values().map(|(size, _)| size).sum(),
+            trees: trees.len() as u64,
+            tree_bytes: trees.values().map(|(_, size)| size).sum(),
+            commits: commits.len() as u64,
+            commit_bytes: commits
+                .values()
+                .map(|c| encoding::encode_commit(c).len() as u64)
+                .sum(),
+        }
+    }
+}
+
+impl GittyDatabase for MemoryDatabase {
+    fn get_head_commit(&self) -> Result<GittyCommitRef, DBError> {
+        Ok(self.head.lock().unwrap().clone())
+    }
+    fn update_head_commit(&self, commit_ref: &GittyCommitRef) -> Result<(), DBError> {
+        *self.head.lock().unwrap() = commit_ref.clone();
+        Ok(())
+    }
+    fn load_blob(&self, blob_ref: &GittyBlobRef) -> Result<BlobReader, DBError> {
+        match self.blobs.lock().unwrap().get(blob_ref) {
+            Some((_, Some(content))) => Ok(Box::new(Cursor::new(content.clone()))),
+            _ => Err(not_found(format!("blob {}", blob_ref.hash))),
+        }
+    }
+    fn load_tree(&self, tree_ref: &GittyTreeRef) -> Result<GittyTree, DBError> {
+        self.trees
+            .lock()
+            .unwrap()
+            .get(tree_ref)
+            .map(|(tree, _)| tree.clone())
+            .ok_or_else(|| not_found(format!("tree {}", tree_ref.hash)))
+    }
+    fn load_commit(&self, commit_ref: &GittyCommitRef) -> Result<GittyCommit, DBError> {
+        self.commits
+            .lock()
+            .unwrap()
+            .get(commit_ref)
+            .cloned()
+            .ok_or_else(|| not_found(format!("commit {}", commit_ref.hash)))
+    }
+    fn commit_graph(&self) -> Result<Vec<CommitGraphEntry>, DBError> {
+        // everything is in memory already
+        commit_graph::update(self, &self.get_head_commit()?, vec![])
+    }
+    fn storage_stats(&self) -> Result<StorageStats, DBError> {
+        Ok(self.stats())
+    }
+
+    fn store_blob(&self, path: &Path, is_symlink: bool) -> Result<StoredBlob, DBError> {
+        if is_symlink {
+            return self.store_symlink_target(&fs::read_link(path)?);
+        }
+        self.store_blob_from_reader(&mut File::open(path)?)
+    }
+
+    fn store_blob_from_reader(&self, reader: &mut dyn Read) -> Result<StoredBlob, DBError> {
+        let mut content = Vec::new();
+        let mut hasher = GittyHasher::new(self.hash_algorithm);
+        let size = if self.keep_content {
+            fs_database::hashing_copy(reader, &mut content, &mut |b| hasher.input(b))?
+        } else {
+            fs_database::hashing_copy(reader, &mut io::sink(), &mut |b| hasher.input(b))?
+        };
+        let blob_ref = GittyBlobRef {
+            hash: hasher.result(),
+        };
+        let mut blobs = self.blobs.lock().unwrap();
+        let deduplicated = blobs.contains_key(&blob_ref);
+        if !deduplicated {
+            let content = if self.keep_content {
+                Some(content)
+            } else {
+                None
+            };
+            blobs.insert(blob_ref.clone(), (size, content));
+        }
+        Ok(StoredBlob {
+            blob_ref,
+            deduplicated,
+            size,
+        })
+    }
+
+    fn store_tree(&self, tree: GittyTree) -> Result<GittyTreeRef, DBError> {
+        let (hash, size) = self.hash_encoded(&encoding::encode_tree(&tree));
+        let tree_ref = GittyTreeRef { hash };
+        self.trees
+            .lock()
+            .unwrap()
+            .insert(tree_ref.clone(), (tree, size));
+        Ok(tree_ref)
+    }
+
+    fn store_commit(&self, commit: GittyCommit) -> Result<GittyCommitRef, DBError> {
+        let (hash, _) = self.hash_encoded(&encoding::encode_commit(&commit));
+        let commit_ref = GittyCommitRef { hash };
+        self.commits
+            .lock()
+            .unwrap()
+            .insert(commit_ref.clone(), commit);
+        Ok(commit_ref)
+    }
+}
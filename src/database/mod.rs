@@ -1,22 +1,86 @@
+use database::commit_graph::CommitGraphEntry;
 use model::*;
 use std;
 use std::fmt::Display;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
-use std::path::PathBuf;
 
 pub trait GittyDatabase {
     fn get_head_commit(&self) -> Result<GittyCommitRef, DBError>;
     fn update_head_commit(&self, commit_ref: &GittyCommitRef) -> Result<(), DBError>;
-    fn load_blob(&self, blob_ref: &GittyBlobRef) -> Result<PathBuf, DBError>;
+    fn load_blob(&self, blob_ref: &GittyBlobRef) -> Result<BlobReader, DBError>;
     fn load_tree(&self, tree_ref: &GittyTreeRef) -> Result<GittyTree, DBError>;
     fn load_commit(&self, commit_ref: &GittyCommitRef) -> Result<GittyCommit, DBError>;
+    // the commits reachable from HEAD, see commit_graph::update
+    fn commit_graph(&self) -> Result<Vec<CommitGraphEntry>, DBError>;
+    fn storage_stats(&self) -> Result<StorageStats, DBError>;
 
-    fn store_blob(&mut self, path: &Path, is_symlink: bool) -> Result<GittyBlobRef, DBError>;
-    fn store_tree(&mut self, tree: GittyTree) -> Result<GittyTreeRef, DBError>;
-    fn store_commit(&mut self, commit: GittyCommit) -> Result<GittyCommitRef, DBError>;
+    fn store_blob(&self, path: &Path, is_symlink: bool) -> Result<StoredBlob, DBError>;
+    // store everything that can be read from reader as a file
+    fn store_blob_from_reader(&self, reader: &mut dyn Read) -> Result<StoredBlob, DBError>;
+    // the blob of a symlink is its target
+    fn store_symlink_target(&self, target: &Path) -> Result<StoredBlob, DBError> {
+        self.store_blob_from_reader(&mut target.as_os_str().as_bytes())
+    }
+    fn store_tree(&self, tree: GittyTree) -> Result<GittyTreeRef, DBError>;
+    fn store_commit(&self, commit: GittyCommit) -> Result<GittyCommitRef, DBError>;
+}
+
+pub struct StoredBlob {
+    pub blob_ref: GittyBlobRef,
+    // an identical blob was already stored
+    pub deduplicated: bool,
+    // number of bytes stored
+    pub size: u64,
+}
+
+/// number of objects in a database and the bytes they take as stored
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct StorageStats {
+    pub blobs: u64,
+    pub blob_bytes: u64,
+    pub trees: u64,
+    pub tree_bytes: u64,
+    pub commits: u64,
+    pub commit_bytes: u64,
+}
+
+impl StorageStats {
+    pub fn objects(&self) -> u64 {
+        self.blobs + self.trees + self.commits
+    }
+    pub fn bytes(&self) -> u64 {
+        self.blob_bytes + self.tree_bytes + self.commit_bytes
+    }
 }
 
-pub type DBError = Box<dyn _DBError>;
+/// the content of a stored blob
+pub trait BlobRead: Read + Seek + Send {
+    /// read up to buf.len() bytes starting at offset, less only at the end of the blob
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.seek(SeekFrom::Start(offset))?;
+        let mut read = 0;
+        while read < buf.len() {
+            match self.read(&mut buf[read..]) {
+                Ok(0) => break,
+                Ok(len) => read += len,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(read)
+    }
+}
+
+impl<T: Read + Seek + Send> BlobRead for T {}
+
+pub type BlobReader = Box<dyn BlobRead>;
+
+pub type DBError = Box<dyn _DBError + Send>;
 
 pub trait _DBError {
     // TODO: why is this needed? https://stackoverflow.com/questions/28632968/why-doesnt-rust-support-trait-object-upcasting
@@ -31,4 +95,10 @@ impl std::convert::From<DBError> for GittyError {
 }
 //impl<T: DBError> std::fmt::Debug for T {}
 
+pub mod commit_graph;
+pub mod config;
+pub mod crypto;
+pub mod encoding;
 pub mod fs_database;
+pub mod memory_database;
+pub mod pack;
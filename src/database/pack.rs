@@ -0,0 +1,362 @@
+use database::crypto::ObjectKind;
+use hex;
+use model::GittyHash;
+use model::HashAlgorithm;
+use rand::OsRng;
+use rand::Rng;
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::time::Duration;
+use std::time::SystemTime;
+
+/// objects smaller than this (as stored) go into packs instead of their own file
+pub const SMALL_OBJECT_SIZE: u64 = 64 * 1024;
+// a new pack is started when the current one is this large
+const PACK_SIZE: u64 = 32 * 1024 * 1024;
+const PACK_MAGIC: &[u8] = b"GITTYPACK\x01";
+const INDEX_MAGIC: &[u8] = b"GITTYIDX\x01";
+// kind, hash algorithm, hash, offset, length
+const INDEX_ENTRY_SIZE: usize = 1 + 1 + 32 + 8 + 8;
+// a directory changed this soon after it was listed may still have the same mtime
+const MTIME_RESOLUTION: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy)]
+struct PackLocation {
+    pack: usize,
+    offset: u64,
+    length: u64,
+}
+
+struct OpenPack {
+    id: usize,
+    file: File,
+    size: u64,
+    entries: Vec<(ObjectKind, GittyHash, PackLocation)>,
+}
+
+/// Pack files bundling many small objects, each with an index (kind, hash -> offset, length).
+///
+/// A pack without an index was not finished and is ignored, so the index of a pack must be
+/// written (flush) before anything refers to its objects. Packs finished by another process
+/// are found when an object is not in the known ones and the directory changed.
+pub struct Packs {
+    dir: PathBuf,
+    paths: RwLock<Vec<PathBuf>>,
+    index: RwLock<HashMap<(ObjectKind, GittyHash), PackLocation>>,
+    // index files already in index
+    loaded: Mutex<HashSet<PathBuf>>,
+    // mtime of the directory when it was listed, unless it could change without a new mtime
+    listed_mtime: Mutex<Option<SystemTime>>,
+    current: Mutex<Option<OpenPack>>,
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn kind_to_byte(kind: ObjectKind) -> u8 {
+    match kind {
+        ObjectKind::Blob => 0,
+        ObjectKind::Tree => 1,
+        ObjectKind::Commit => 2,
+    }
+}
+
+fn kind_from_byte(byte: u8) -> io::Result<ObjectKind> {
+    match byte {
+        0 => Ok(ObjectKind::Blob),
+        1 => Ok(ObjectKind::Tree),
+        2 => Ok(ObjectKind::Commit),
+        _ => Err(invalid_data(format!("unknown object kind {}", byte))),
+    }
+}
+
+fn u64_at(bytes: &[u8], start: usize) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[start..start + 8]);
+    u64::from_be_bytes(buf)
+}
+
+impl Packs {
+    pub fn open(dir: PathBuf) -> io::Result<Packs> {
+        let packs = Packs {
+            dir,
+            paths: RwLock::new(Vec::new()),
+            index: RwLock::new(HashMap::new()),
+            loaded: Mutex::new(HashSet::new()),
+            listed_mtime: Mutex::new(None),
+            current: Mutex::new(None),
+        };
+        packs.load_new_indexes()?;
+        Ok(packs)
+    }
+
+    // the indexes not loaded yet, e.g. written by a snapshot while the repository is mounted
+    fn load_new_indexes(&self) -> io::Result<()> {
+        let mut loaded = self.loaded.lock().unwrap();
+        let mtime = match fs::metadata(&self.dir) {
+            Ok(metadata) => metadata.modified()?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut listed_mtime = self.listed_mtime.lock().unwrap();
+        // nothing was added since the last time
+        if *listed_mtime == Some(mtime) {
+            return Ok(());
+        }
+        let mut index_paths = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "idx") && !loaded.contains(&path) {
+                index_paths.push(path);
+            }
+        }
+        index_paths.sort();
+        for index_path in index_paths {
+            self.load_index(&index_path)?;
+            loaded.insert(index_path);
+        }
+        let settled = SystemTime::now()
+            .duration_since(mtime)
+            .is_ok_and(|age| age > MTIME_RESOLUTION);
+        *listed_mtime = if settled { Some(mtime) } else { None };
+        Ok(())
+    }
+
+    fn load_index(&self, index_path: &Path) -> io::Result<()> {
+        let content = fs::read(index_path)?;
+        if !content.starts_with(INDEX_MAGIC)
+            || (content.len() - INDEX_MAGIC.len()) % INDEX_ENTRY_SIZE != 0
+        {
+            return Err(invalid_data(format!(
+                "{} is not a pack index",
+                index_path.display()
+            )));
+        }
+        let mut paths = self.paths.write().unwrap();
+        let pack = paths.len();
+        paths.push(index_path.with_extension("pack"));
+        let mut index = self.index.write().unwrap();
+        for entry in content[INDEX_MAGIC.len()..].chunks(INDEX_ENTRY_SIZE) {
+            let kind = kind_from_byte(entry[0])?;
+            let algorithm = HashAlgorithm::from_code(entry[1])
+                .ok_or_else(|| invalid_data(format!("unknown hash algorithm {}", entry[1])))?;
+            let entry = &entry[2..];
+            let mut digest = [0u8; 32];
+            digest.copy_from_slice(&entry[..32]);
+            index.insert(
+                (kind, GittyHash { algorithm, digest }),
+                PackLocation {
+                    pack,
+                    offset: u64_at(entry, 32),
+                    length: u64_at(entry, 40),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, kind: ObjectKind, hash: &GittyHash) -> bool {
+        self.index
+            .read()
+            .unwrap()
+            .contains_key(&(kind, hash.clone()))
+    }
+
+    /// number and stored size of the packed objects of each kind
+    pub fn stats(&self) -> io::Result<HashMap<ObjectKind, (u64, u64)>> {
+        self.load_new_indexes()?;
+        let mut stats = HashMap::new();
+        for ((kind, _), location) in self.index.read().unwrap().iter() {
+            let entry = stats.entry(*kind).or_insert((0, 0));
+            entry.0 += 1;
+            entry.1 += location.length;
+        }
+        Ok(stats)
+    }
+
+    fn find(&self, kind: ObjectKind, hash: &GittyHash) -> Option<(PathBuf, PackLocation)> {
+        let location = *self.index.read().unwrap().get(&(kind, hash.clone()))?;
+        let path = self.paths.read().unwrap()[location.pack].clone();
+        Some((path, location))
+    }
+
+    fn locate(
+        &self,
+        kind: ObjectKind,
+        hash: &GittyHash,
+    ) -> io::Result<Option<(PathBuf, PackLocation)>> {
+        if let Some(found) = self.find(kind, hash) {
+            return Ok(Some(found));
+        }
+        self.load_new_indexes()?;
+        Ok(self.find(kind, hash))
+    }
+
+    /// the stored bytes of an object, None if it is not in a pack
+    pub fn read(&self, kind: ObjectKind, hash: &GittyHash) -> io::Result<Option<Vec<u8>>> {
+        match self.open_range(kind, hash)? {
+            Some(mut range) => {
+                let mut data = Vec::with_capacity(range.len as usize);
+                range.read_to_end(&mut data)?;
+                Ok(Some(data))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn open_range(&self, kind: ObjectKind, hash: &GittyHash) -> io::Result<Option<FileRange>> {
+        match self.locate(kind, hash)? {
+            Some((path, location)) => Ok(Some(FileRange {
+                file: File::open(path)?,
+                start: location.offset,
+                len: location.length,
+                pos: 0,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// append an object to the current pack. it can be read immediately,
+    /// but only survives a restart after the next flush
+    pub fn add(&self, kind: ObjectKind, hash: &GittyHash, data: &[u8]) -> io::Result<()> {
+        let mut current = self.current.lock().unwrap();
+        if self.contains(kind, hash) {
+            return Ok(());
+        }
+        if current.is_none() {
+            *current = Some(self.new_pack()?);
+        }
+        let full = {
+            let pack = current.as_mut().unwrap();
+            if let Err(e) = pack.file.write_all(data) {
+                // part of data may have been written, the next object must start where it
+                // would have
+                let truncated = pack
+                    .file
+                    .set_len(pack.size)
+                    .and_then(|_| pack.file.seek(SeekFrom::Start(pack.size)));
+                if truncated.is_err() {
+                    // its index only refers to the complete objects
+                    self.finish(current.take().unwrap())?;
+                }
+                return Err(e);
+            }
+            let location = PackLocation {
+                pack: pack.id,
+                offset: pack.size,
+                length: data.len() as u64,
+            };
+            pack.size += data.len() as u64;
+            pack.entries.push((kind, hash.clone(), location));
+            self.index
+                .write()
+                .unwrap()
+                .insert((kind, hash.clone()), location);
+            pack.size >= PACK_SIZE
+        };
+        if full {
+            self.finish(current.take().unwrap())?;
+        }
+        Ok(())
+    }
+
+    fn new_pack(&self) -> io::Result<OpenPack> {
+        fs::create_dir_all(&self.dir)?;
+        let mut name = [0u8; 16];
+        OsRng::new()
+            .map_err(|e| io::Error::other(e.to_string()))?
+            .fill(&mut name);
+        let path = self.dir.join(format!("pack-{}.pack", hex::encode(name)));
+        let mut file = File::create(&path)?;
+        file.write_all(PACK_MAGIC)?;
+        let mut paths = self.paths.write().unwrap();
+        paths.push(path);
+        Ok(OpenPack {
+            id: paths.len() - 1,
+            file,
+            size: PACK_MAGIC.len() as u64,
+            entries: Vec::new(),
+        })
+    }
+
+    fn finish(&self, pack: OpenPack) -> io::Result<()> {
+        pack.file.sync_all()?;
+        let mut index = INDEX_MAGIC.to_vec();
+        for (kind, hash, location) in &pack.entries {
+            index.push(kind_to_byte(*kind));
+            index.push(hash.algorithm.code());
+            index.extend_from_slice(&hash.digest);
+            index.extend_from_slice(&location.offset.to_be_bytes());
+            index.extend_from_slice(&location.length.to_be_bytes());
+        }
+        let index_path = self.paths.read().unwrap()[pack.id].with_extension("idx");
+        let tmp_path = index_path.with_extension("idx.tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&index)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, &index_path)?;
+        self.loaded.lock().unwrap().insert(index_path);
+        Ok(())
+    }
+
+    /// write the index of the current pack, making its objects permanent
+    pub fn flush(&self) -> io::Result<()> {
+        let mut current = self.current.lock().unwrap();
+        match current.take() {
+            Some(pack) => self.finish(pack),
+            None => Ok(()),
+        }
+    }
+}
+
+/// part of a file, read like a file of its own
+pub struct FileRange {
+    file: File,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl Read for FileRange {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len {
+            return Ok(0);
+        }
+        let len = cmp::min(buf.len() as u64, self.len - self.pos) as usize;
+        self.file.seek(SeekFrom::Start(self.start + self.pos))?;
+        let read = self.file.read(&mut buf[..len])?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for FileRange {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => p as i64,
+            SeekFrom::End(p) => self.len as i64 + p,
+            SeekFrom::Current(p) => self.pos as i64 + p,
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the object",
+            ));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
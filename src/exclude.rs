@@ -0,0 +1,209 @@
+use ignore;
+use ignore::gitignore::Gitignore;
+use ignore::gitignore::GitignoreBuilder;
+use model::GittyError;
+use std;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+
+pub const IGNORE_FILENAME: &str = ".gittyignore";
+pub const CACHEDIR_TAG_FILENAME: &str = "CACHEDIR.TAG";
+// see http://www.brynosaurus.com/cachedir/spec.html
+const CACHEDIR_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+impl std::convert::From<ignore::Error> for GittyError {
+    fn from(i: ignore::Error) -> GittyError {
+        GittyError::new("invalid ignore file".to_owned(), Box::new(i))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ExcludeOptions {
+    // repository level exclude file (same syntax as .gittyignore)
+    pub exclude_file: Option<PathBuf>,
+    // additional patterns, relative to the snapshot root (--exclude)
+    pub patterns: Vec<String>,
+    // skip directories tagged with CACHEDIR.TAG
+    pub exclude_caches: bool,
+}
+
+#[derive(Clone, Debug)]
+pub enum ExcludeReason {
+    Pattern {
+        // None if the pattern was given on the command line
+        source: Option<PathBuf>,
+        pattern: String,
+        whitelist: bool,
+    },
+    CacheDirTag,
+}
+
+impl ExcludeReason {
+    pub fn is_exclude(&self) -> bool {
+        match self {
+            ExcludeReason::Pattern { whitelist, .. } => !whitelist,
+            ExcludeReason::CacheDirTag => true,
+        }
+    }
+    fn from_match(m: ignore::Match<&ignore::gitignore::Glob>) -> Option<ExcludeReason> {
+        m.inner().map(|glob| ExcludeReason::Pattern {
+            source: glob.from().map(|p| p.to_path_buf()),
+            pattern: glob.original().to_owned(),
+            whitelist: glob.is_whitelist(),
+        })
+    }
+}
+
+impl fmt::Display for ExcludeReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExcludeReason::Pattern {
+                source: Some(source),
+                pattern,
+                ..
+            } => write!(f, "{}:{}", source.display(), pattern),
+            ExcludeReason::Pattern {
+                source: None,
+                pattern,
+                ..
+            } => write!(f, "--exclude:{}", pattern),
+            ExcludeReason::CacheDirTag => write!(f, "{}", CACHEDIR_TAG_FILENAME),
+        }
+    }
+}
+
+/// Exclude rules for one snapshot root.
+///
+/// Like git, every directory can contain a .gittyignore whose patterns are relative to that
+/// directory and take precedence over the ones in its parents. The repository exclude file and
+/// the command line patterns have the lowest precedence.
+pub struct ExcludeRules {
+    root: PathBuf,
+    global: Gitignore,
+    exclude_caches: bool,
+    // .gittyignore of the directories on the path to the last directory entered (None if the
+    // directory has none)
+    per_dir: HashMap<PathBuf, Option<Gitignore>>,
+}
+
+impl ExcludeRules {
+    pub fn new(root: &Path, options: &ExcludeOptions) -> Result<ExcludeRules, GittyError> {
+        let mut builder = GitignoreBuilder::new(root);
+        if let Some(ref exclude_file) = options.exclude_file {
+            if exclude_file.exists() {
+                if let Some(e) = builder.add(exclude_file) {
+                    return Err(GittyError::from(e));
+                }
+            }
+        }
+        for pattern in &options.patterns {
+            builder.add_line(None, pattern)?;
+        }
+        Ok(ExcludeRules {
+            root: root.to_path_buf(),
+            global: builder.build()?,
+            exclude_caches: options.exclude_caches,
+            per_dir: HashMap::new(),
+        })
+    }
+
+    /// load the .gittyignore of a directory. must be called before matching its children,
+    /// starting with the root.
+    ///
+    /// Only the rules of dir and its parents are kept, so the directories have to be entered in
+    /// the order of a depth first walk. If the ignore file can not be read completely, the
+    /// patterns that could be read are still used and the error is returned.
+    pub fn enter_dir(&mut self, dir: &Path) -> Result<(), GittyError> {
+        self.per_dir.retain(|parent, _| dir.starts_with(parent));
+        if self.per_dir.contains_key(dir) {
+            return Ok(());
+        }
+        let ignorefile = dir.join(IGNORE_FILENAME);
+        if !ignorefile.is_file() {
+            self.per_dir.insert(dir.to_path_buf(), None);
+            return Ok(());
+        }
+        let mut builder = GitignoreBuilder::new(dir);
+        let error = builder.add(&ignorefile);
+        let (matcher, error) = match builder.build() {
+            Ok(matcher) => (Some(matcher), error),
+            Err(e) => (None, Some(e)),
+        };
+        self.per_dir.insert(dir.to_path_buf(), matcher);
+        match error {
+            Some(e) => Err(GittyError::from(e)),
+            None => Ok(()),
+        }
+    }
+
+    /// the rule deciding whether path is excluded, if any.
+    /// only looks at the path itself, not at its parent directories
+    pub fn matched(&self, path: &Path, is_dir: bool) -> Option<ExcludeReason> {
+        let from_dirs = path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(&self.root))
+            .filter_map(|dir| self.per_dir.get(dir).and_then(|m| m.as_ref()))
+            .filter_map(|m| ExcludeReason::from_match(m.matched(path, is_dir)))
+            .next();
+        from_dirs
+            .or_else(|| ExcludeReason::from_match(self.global.matched(path, is_dir)))
+            .or_else(|| {
+                if is_dir && self.exclude_caches && has_cachedir_tag(path) {
+                    Some(ExcludeReason::CacheDirTag)
+                } else {
+                    None
+                }
+            })
+    }
+
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        self.matched(path, is_dir)
+            .map(|r| r.is_exclude())
+            .unwrap_or(false)
+    }
+
+    /// explain why a path below the root is (not) excluded, also considering its parents.
+    /// returns the path the deciding rule matched on (the path itself or one of its parents)
+    pub fn explain(&mut self, path: &Path) -> Result<Option<(PathBuf, ExcludeReason)>, GittyError> {
+        let relative = path.strip_prefix(&self.root).map_err(|_| {
+            GittyError::new(
+                "check-ignore".to_owned(),
+                Box::new(format!(
+                    "{} is not inside {}",
+                    path.display(),
+                    self.root.display()
+                )),
+            )
+        })?;
+        let mut current = self.root.clone();
+        self.enter_dir(&current)?;
+        let mut components = relative.components().peekable();
+        while let Some(component) = components.next() {
+            current.push(component);
+            let is_last = components.peek().is_none();
+            let is_dir = !is_last || current.is_dir();
+            if let Some(reason) = self.matched(&current, is_dir) {
+                if is_last || reason.is_exclude() {
+                    return Ok(Some((current, reason)));
+                }
+            }
+            if is_dir {
+                self.enter_dir(&current)?;
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn has_cachedir_tag(dir: &Path) -> bool {
+    let mut buf = [0u8; 43];
+    File::open(dir.join(CACHEDIR_TAG_FILENAME))
+        .and_then(|mut f| f.read_exact(&mut buf))
+        .map(|_| &buf[..] == CACHEDIR_TAG_SIGNATURE)
+        .unwrap_or(false)
+}
@@ -1,12 +1,32 @@
 use chrono::prelude::*;
 use database as db;
-use ignore;
+use exclude::ExcludeOptions;
+use exclude::ExcludeRules;
+use exclude::IGNORE_FILENAME;
+use libc;
 use model::*;
+use mounts::MountTable;
+use progress::WalkEvent;
+use progress::WalkObserver;
 use std;
-use std::cmp::Ordering;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::ffi::OsString;
-use std::path::Component as PathComponent;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::Component;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::sync::mpsc::SyncSender;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
 use walkdir;
 use walkdir::DirEntry;
 
@@ -21,156 +41,1107 @@ impl std::convert::From<std::io::Error> for GittyError {
     }
 }
 
+// the walker's own state is inconsistent, the snapshot can not be finished
+fn internal_error(message: String) -> GittyError {
+    GittyError::new("snapshot".to_owned(), Box::new(message))
+}
+
+// a tree entry whose hash is not known until its blob or tree is stored
+struct PendingEntry {
+    entry: GittyTreeEntry,
+    hash: PendingHash,
+}
+
+enum PendingHash {
+    Blob(usize),
+    Tree(usize),
+}
+
 struct StackPart {
+    path: PathBuf,
     name: OsString,
     metadata: std::fs::Metadata,
-    entries: Vec<GittyTreeEntry>,
-}
-
-fn create_tree_entry(
-    database: &mut impl db::GittyDatabase,
-    StackPart {
-        name,
-        metadata,
-        entries,
-    }: StackPart,
-) -> Result<GittyTreeEntry, GittyError> {
-    Ok(GittyTreeEntry::Tree(GittyTreeMetadata {
-        name,
-        modified: DateTime::from(metadata.modified()?),
-        permissions: Permissions::new(&metadata),
-        hash: database.store_tree(GittyTree { entries })?.hash,
-    }))
+    entries: Vec<PendingEntry>,
+}
+
+struct FinishedDir {
+    id: usize,
+    path: PathBuf,
+    entries: Vec<PendingEntry>,
+}
+
+// the channels to and from the threads storing blobs
+struct BlobWorkers<'a> {
+    jobs: &'a SyncSender<BlobJob>,
+    results: &'a Receiver<BlobResult>,
+}
+
+struct BlobJob {
+    id: usize,
+    path: PathBuf,
+    is_symlink: bool,
+    // stat before reading the file
+    metadata: std::fs::Metadata,
+}
+
+struct CheckedBlob {
+    hash: GittyHash,
+    deduplicated: bool,
+    // new stat if the file changed while reading it
+    changed: Option<std::fs::Metadata>,
+    // still changing after all retries, content may be inconsistent
+    unstable: bool,
+}
+
+impl CheckedBlob {
+    fn update_entry(&self, entry: &mut GittyBlobMetadata) {
+        entry.hash = self.hash.clone();
+        entry.unstable = self.unstable;
+        if let Some(ref m) = self.changed {
+            entry.size = m.len();
+            entry.permissions = Permissions::new(m);
+            if let Ok(modified) = m.modified() {
+                entry.modified = DateTime::from(modified);
+            }
+        }
+    }
+}
+
+// only a file that can't be read is left out, a repository that can't be written to fails the
+// snapshot
+enum BlobError {
+    Source(db::DBError),
+    Repository(db::DBError),
+}
+
+type BlobResult = (BlobJob, Result<CheckedBlob, BlobError>);
+
+// remembers whether reading failed, the error itself is returned by the database.
+// at the end the file is checked for changes while it was read
+struct SourceReader<'a, R: Read> {
+    inner: R,
+    failed: bool,
+    path: &'a Path,
+    before: &'a std::fs::Metadata,
+    // stat at the end, if it differs from before
+    changed: Option<std::fs::Metadata>,
+    // fail the copy when it changed, so the database doesn't keep the content
+    discard_changed: bool,
 }
+
+impl<'a, R: Read> Read for SourceReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf);
+        match read {
+            Err(ref e) => self.failed |= e.kind() != io::ErrorKind::Interrupted,
+            Ok(0) if !buf.is_empty() => {
+                let path = self.path;
+                let after = path
+                    .symlink_metadata()
+                    .inspect_err(|_| self.failed = true)?;
+                if file_changed(self.before, &after) {
+                    self.changed = Some(after);
+                    if self.discard_changed {
+                        return Err(io::Error::other("changed while reading"));
+                    }
+                }
+            }
+            Ok(_) => {}
+        }
+        read
+    }
+}
+
+enum BlobState {
+    Pending,
+    Stored(Box<CheckedBlob>),
+    // could not be read, left out of its tree
+    Failed,
+}
+
+/// What to do when a path can not be backed up
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ErrorPolicy {
+    // fail the whole snapshot
+    Abort,
+    // leave the path out of the snapshot and record it in the commit
+    #[default]
+    Skip,
+    // retry the given number of times, then skip
+    Retry(u32),
+}
+
+const RETRY_DELAY: Duration = Duration::from_millis(200);
+
+fn with_retries<T, E>(policy: ErrorPolicy, f: impl FnMut() -> Result<T, E>) -> Result<T, E> {
+    with_retries_if(policy, |_| true, f)
+}
+
+// only retries the errors for which retry returns true
+fn with_retries_if<T, E>(
+    policy: ErrorPolicy,
+    retry: impl Fn(&E) -> bool,
+    mut f: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(e) => match policy {
+                ErrorPolicy::Retry(n) if attempt < n && retry(&e) => {
+                    attempt += 1;
+                    thread::sleep(RETRY_DELAY * attempt);
+                }
+                _ => return Err(e),
+            },
+            ok => return ok,
+        }
+    }
+}
+
+// collects everything noteworthy about the snapshot and reports it to the observer
+struct WalkLog<'o> {
+    policy: ErrorPolicy,
+    observer: &'o mut dyn WalkObserver,
+    info: GittySnapshotInfo,
+}
+
+impl<'o> WalkLog<'o> {
+    fn handle(&mut self, path: &Path, error: GittyError) -> Result<(), GittyError> {
+        if self.policy == ErrorPolicy::Abort {
+            return Err(error);
+        }
+        warn!("not backing up {}: {}", path.display(), error);
+        let message = error.to_string();
+        self.observer.event(&WalkEvent::Error {
+            path,
+            message: &message,
+        });
+        self.info.errors.push(GittyWalkError {
+            path: path.as_os_str().to_owned(),
+            message,
+        });
+        Ok(())
+    }
+}
+
+/// Stores trees as soon as all blobs in them have been stored by the workers.
+///
+/// Directories are finished in post-order, so when storing trees in that order, all child trees
+/// of a tree are already stored. The resulting trees do not depend on the order in which the
+/// workers finish.
+struct TreeAssembler<'a, 'o, D: 'a> {
+    database: &'a D,
+    log: WalkLog<'o>,
+    // blobs whose tree is not stored yet, removed when it is
+    blobs: HashMap<usize, BlobState>,
+    next_blob_id: usize,
+    tree_hashes: Vec<Option<GittyHash>>,
+    finished: VecDeque<FinishedDir>,
+}
+
+impl<'a, 'o, D: db::GittyDatabase> TreeAssembler<'a, 'o, D> {
+    fn new(database: &'a D, log: WalkLog<'o>) -> TreeAssembler<'a, 'o, D> {
+        TreeAssembler {
+            database,
+            log,
+            blobs: HashMap::new(),
+            next_blob_id: 0,
+            tree_hashes: Vec::new(),
+            finished: VecDeque::new(),
+        }
+    }
+
+    fn add_blob(&mut self) -> usize {
+        let id = self.next_blob_id;
+        self.next_blob_id += 1;
+        self.blobs.insert(id, BlobState::Pending);
+        id
+    }
+
+    fn blob_stored(&mut self, (job, result): BlobResult) -> Result<(), GittyError> {
+        let state = match result {
+            Ok(stored) => {
+                let bytes = stored.changed.as_ref().unwrap_or(&job.metadata).len();
+                let log = &mut self.log;
+                log.observer.event(&WalkEvent::BytesHashed {
+                    path: &job.path,
+                    bytes,
+                });
+                if stored.deduplicated {
+                    log.observer.event(&WalkEvent::Deduplicated {
+                        path: &job.path,
+                        bytes,
+                        hash: &stored.hash,
+                    });
+                }
+                if stored.unstable {
+                    warn!("{} kept changing while reading it", job.path.display());
+                    log.observer.event(&WalkEvent::Unstable { path: &job.path });
+                    log.info.unstable.push(job.path.as_os_str().to_owned());
+                }
+                log.info.files += 1;
+                log.info.bytes += bytes;
+                BlobState::Stored(Box::new(stored))
+            }
+            Err(BlobError::Source(e)) => {
+                self.log.handle(&job.path, GittyError::from(e))?;
+                BlobState::Failed
+            }
+            Err(BlobError::Repository(e)) => return Err(GittyError::from(e)),
+        };
+        self.blobs.insert(job.id, state);
+        Ok(())
+    }
+
+    fn finish_dir(
+        &mut self,
+        StackPart {
+            path,
+            name,
+            metadata,
+            entries,
+        }: StackPart,
+    ) -> Result<PendingEntry, GittyError> {
+        let id = self.tree_hashes.len();
+        self.tree_hashes.push(None);
+        self.finished.push_back(FinishedDir { id, path, entries });
+        Ok(PendingEntry {
+            entry: GittyTreeEntry::Tree(GittyTreeMetadata {
+                name,
+                modified: DateTime::from(metadata.modified()?),
+                permissions: Permissions::new(&metadata),
+                hash: PLACEHOLDER_HASH,
+            }),
+            hash: PendingHash::Tree(id),
+        })
+    }
+
+    fn is_pending(&self, hash: &PendingHash) -> bool {
+        match hash {
+            PendingHash::Blob(id) => matches!(self.blobs.get(id), Some(BlobState::Pending)),
+            PendingHash::Tree(id) => self.tree_hashes[*id].is_none(),
+        }
+    }
+
+    // None if pending or failed
+    fn resolve(&self, hash: &PendingHash) -> Option<&GittyHash> {
+        match hash {
+            PendingHash::Blob(id) => match self.blobs.get(id) {
+                Some(BlobState::Stored(stored)) => Some(&stored.hash),
+                _ => None,
+            },
+            PendingHash::Tree(id) => self.tree_hashes[*id].as_ref(),
+        }
+    }
+
+    fn is_ready(&self, dir: &FinishedDir) -> bool {
+        dir.entries.iter().all(|e| !self.is_pending(&e.hash))
+    }
+
+    fn store_ready_trees(&mut self) -> Result<(), GittyError> {
+        while self.finished.front().is_some_and(|d| self.is_ready(d)) {
+            let dir = self.finished.pop_front().unwrap();
+            let mut entries = Vec::with_capacity(dir.entries.len());
+            for PendingEntry { mut entry, hash } in dir.entries {
+                match (&mut entry, &hash) {
+                    (GittyTreeEntry::Tree(ref mut t), _) => match self.resolve(&hash) {
+                        Some(resolved) => t.hash = resolved.clone(),
+                        None => continue,
+                    },
+                    (GittyTreeEntry::Blob(ref mut b), PendingHash::Blob(id)) => {
+                        match self.blobs.remove(id) {
+                            Some(BlobState::Stored(stored)) => stored.update_entry(b),
+                            _ => continue,
+                        }
+                    }
+                    (GittyTreeEntry::Blob(b), PendingHash::Tree(_)) => {
+                        return Err(internal_error(format!(
+                            "blob entry {:?} with tree hash",
+                            b.name
+                        )))
+                    }
+                }
+                entries.push(entry);
+            }
+            let tree_ref = self.database.store_tree(GittyTree { entries })?;
+            self.log.observer.event(&WalkEvent::TreeStored {
+                path: &dir.path,
+                hash: &tree_ref.hash,
+            });
+            self.tree_hashes[dir.id] = Some(tree_ref.hash);
+        }
+        Ok(())
+    }
+}
+
 fn ascend_path_stack(
-    database: &mut impl db::GittyDatabase,
+    assembler: &mut TreeAssembler<impl db::GittyDatabase>,
     path_stack: &mut Vec<StackPart>,
     i: usize,
 ) -> Result<(), GittyError> {
     while path_stack.len() > i {
-        println!(
-            "ascending push {}",
-            path_stack.last().unwrap().name.to_string_lossy()
-        );
-        let new_entry = create_tree_entry(database, path_stack.pop().unwrap())?;
-        path_stack.last_mut().unwrap().entries.push(new_entry);
+        let new_entry = assembler.finish_dir(path_stack.pop().unwrap())?;
+        parent_dir(path_stack)?.entries.push(new_entry);
     }
     Ok(())
 }
+
+fn parent_dir(path_stack: &mut [StackPart]) -> Result<&mut StackPart, GittyError> {
+    path_stack
+        .last_mut()
+        .ok_or_else(|| internal_error("entry without a parent directory".to_owned()))
+}
+
 fn dirent_to_gitty_tree_entry(
-    database: &mut impl db::GittyDatabase,
+    assembler: &mut TreeAssembler<impl db::GittyDatabase>,
     path_stack: &mut Vec<StackPart>,
+    jobs: &SyncSender<BlobJob>,
     dirent: DirEntry,
+    depth: usize,
     metadata: std::fs::Metadata,
 ) -> Result<(), GittyError> {
-    // TODO: this is kinda ugly, use zip / functional stuff?
-    let current_path: Vec<PathComponent> = dirent.path().components().collect();
-    let first_diff = path_stack
-        .into_iter()
-        .enumerate()
-        .find(|(i, StackPart { name: seg_a, .. })| {
-            let seg_b = current_path.get(*i);
-            Some(&PathComponent::Normal(seg_a)) != seg_b
-        })
-        .map(|(i, _)| i);
-    if let Some(i) = first_diff {
-        ascend_path_stack(database, path_stack, i)?;
-    }
-
-    if current_path.len() != path_stack.len() + 1 {
-        panic!(
-            "cannot descend multiple {:?} -> {:?}",
-            "?", //path_stack.iter().map(|e| e.name),
-            current_path
-        );
+    // the stack contains all parent directories of the entry
+    ascend_path_stack(assembler, path_stack, depth)?;
+    if depth != path_stack.len() {
+        return Err(internal_error(format!(
+            "cannot descend multiple {} -> {:?}",
+            path_stack.len(),
+            dirent.path()
+        )));
     }
     let is_symlink = metadata.file_type().is_symlink();
     if metadata.is_dir() {
-        let name: OsString = dirent.file_name().to_os_string();
-        assert!(current_path.last().unwrap().clone() == PathComponent::Normal(&name));
-        println!("descending into {}", name.to_string_lossy());
-        let entries: Vec<GittyTreeEntry> = Vec::new();
         path_stack.push(StackPart {
-            name,
+            path: dirent.path().to_path_buf(),
+            name: dirent.file_name().to_os_string(),
             metadata,
-            entries,
+            entries: Vec::new(),
         });
     } else if metadata.is_file() || is_symlink {
+        let id = assembler.add_blob();
         let new_entry = GittyTreeEntry::Blob(GittyBlobMetadata {
             name: dirent.file_name().to_os_string(),
             modified: DateTime::from(metadata.modified()?),
             permissions: Permissions::new(&metadata),
             size: metadata.len(),
             is_symlink,
-            hash: database.store_blob(dirent.path(), is_symlink)?.hash,
+            unstable: false,
+            hash: PLACEHOLDER_HASH,
+        });
+        assembler.log.observer.event(&WalkEvent::FileStarted {
+            path: dirent.path(),
+            size: metadata.len(),
+        });
+        jobs.send(BlobJob {
+            id,
+            path: dirent.path().to_path_buf(),
+            is_symlink,
+            metadata,
+        })
+        .map_err(|_| internal_error("blob workers died".to_owned()))?;
+        parent_dir(path_stack)?.entries.push(PendingEntry {
+            entry: new_entry,
+            hash: PendingHash::Blob(id),
         });
-        path_stack.last_mut().unwrap().entries.push(new_entry);
     } else {
-        panic!("Unknown file type: {:?}", metadata.file_type());
+        // sockets, fifos and devices have no content to back up, so this is not an error
+        debug!(
+            "skipping {} of type {:?}",
+            dirent.path().display(),
+            metadata.file_type()
+        );
     }
     Ok(())
 }
 
+fn file_changed(before: &std::fs::Metadata, after: &std::fs::Metadata) -> bool {
+    before.len() != after.len()
+        || before.mtime() != after.mtime()
+        || before.mtime_nsec() != after.mtime_nsec()
+        || before.ctime() != after.ctime()
+        || before.ctime_nsec() != after.ctime_nsec()
+}
+
+// result of reading a file once
+struct StoreAttempt {
+    // None if the file changed and its content was not kept
+    stored: Option<db::StoredBlob>,
+    // stat at the end, if it differs from the one before reading
+    changed: Option<std::fs::Metadata>,
+}
+
+// the blob of a symlink is its target
+fn store_blob(
+    database: &impl db::GittyDatabase,
+    job: &BlobJob,
+    before: &std::fs::Metadata,
+    keep_changed: bool,
+) -> Result<StoreAttempt, BlobError> {
+    let source_error = |e: io::Error| BlobError::Source(db::DBError::from(e));
+    if job.is_symlink {
+        let target = job.path.read_link().map_err(source_error)?;
+        let after = job.path.symlink_metadata().map_err(source_error)?;
+        let changed = if file_changed(before, &after) {
+            Some(after)
+        } else {
+            None
+        };
+        if changed.is_some() && !keep_changed {
+            return Ok(StoreAttempt {
+                stored: None,
+                changed,
+            });
+        }
+        debug!("storing symlink {:?} -> {:?}", job.path, target);
+        let stored = database
+            .store_symlink_target(&target)
+            .map_err(BlobError::Repository)?;
+        return Ok(StoreAttempt {
+            stored: Some(stored),
+            changed,
+        });
+    }
+    debug!("copying {:?} while hashing", job.path);
+    let mut reader = SourceReader {
+        inner: File::open(&job.path).map_err(source_error)?,
+        failed: false,
+        path: &job.path,
+        before,
+        changed: None,
+        discard_changed: !keep_changed,
+    };
+    match database.store_blob_from_reader(&mut reader) {
+        Ok(stored) => Ok(StoreAttempt {
+            stored: Some(stored),
+            changed: reader.changed,
+        }),
+        Err(_) if reader.changed.is_some() && !keep_changed => Ok(StoreAttempt {
+            stored: None,
+            changed: reader.changed,
+        }),
+        Err(e) if reader.failed => Err(BlobError::Source(e)),
+        Err(e) => Err(BlobError::Repository(e)),
+    }
+}
+
+// store a blob, re-reading it if it changed while reading. only the content of the read that
+// passed the check is stored, unless it is still changing after all retries
+fn store_blob_checked(
+    database: &impl db::GittyDatabase,
+    options: &WalkOptions,
+    job: &BlobJob,
+) -> Result<CheckedBlob, BlobError> {
+    let mut attempt = 0;
+    loop {
+        // the file may have changed since the walk or the last attempt
+        let before = with_retries(options.on_error, || job.path.symlink_metadata())
+            .map_err(|e| BlobError::Source(db::DBError::from(e)))?;
+        let keep_changed = attempt >= options.unstable_retries;
+        // a repository that can't be written to fails the snapshot right away
+        let result = with_retries_if(
+            options.on_error,
+            |e| matches!(e, BlobError::Source(_)),
+            || store_blob(database, job, &before, keep_changed),
+        )?;
+        if let Some(stored) = result.stored {
+            let unstable = result.changed.is_some();
+            let changed = match result.changed {
+                Some(after) => Some(after),
+                None if file_changed(&job.metadata, &before) => Some(before),
+                None => None,
+            };
+            return Ok(CheckedBlob {
+                hash: stored.blob_ref.hash,
+                deduplicated: stored.deduplicated,
+                changed,
+                unstable,
+            });
+        }
+        attempt += 1;
+        debug!("{} changed while reading, retrying", job.path.display());
+    }
+}
+
+fn store_blobs(
+    database: &impl db::GittyDatabase,
+    options: &WalkOptions,
+    jobs: &Mutex<Receiver<BlobJob>>,
+    results: Sender<BlobResult>,
+) {
+    loop {
+        let job = match jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return, // walk finished
+        };
+        let result = store_blob_checked(database, options, &job);
+        if results.send((job, result)).is_err() {
+            return; // walk aborted
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct WalkOptions {
+    pub exclude: ExcludeOptions,
+    // don't descend into mount points of other filesystems
+    pub one_file_system: bool,
+    // don't descend into mount points with these filesystem types (e.g. "proc")
+    pub skip_fs_types: Vec<String>,
+    // number of threads hashing and storing blobs, 0 for one per cpu
+    pub threads: usize,
+    pub on_error: ErrorPolicy,
+    // how often to re-read a file that changed while reading it
+    pub unstable_retries: u32,
+    // mount points to check instead of the ones in /proc/self/mounts
+    pub mount_table: Option<MountTable>,
+}
+
+pub struct WalkResult {
+    pub root: GittyTreeRef,
+    pub info: GittySnapshotInfo,
+}
+
+// returns the mount point if entry is one that should not be descended into
+fn skipped_mount(
+    entry: &DirEntry,
+    root_dev: u64,
+    options: &WalkOptions,
+    mounts: &MountTable,
+) -> Option<GittySkippedMount> {
+    if !entry.file_type().is_dir() {
+        return None;
+    }
+    let dev = entry.metadata().ok()?.dev();
+    let other_device = dev != root_dev
+        && entry
+            .path()
+            .parent()
+            .and_then(|p| p.symlink_metadata().ok())
+            .map(|m| m.dev())
+            != Some(dev);
+    // bind mounts can be on the same device
+    if !other_device && !mounts.is_mount_point(entry.path()) {
+        return None;
+    }
+    let fs_type = mounts.fs_type(entry.path()).map(|t| t.to_owned());
+    let skip_type = fs_type
+        .as_ref()
+        .map(|t| options.skip_fs_types.contains(t))
+        .unwrap_or(false);
+    if (options.one_file_system && other_device) || skip_type {
+        Some(GittySkippedMount {
+            path: entry.path().as_os_str().to_owned(),
+            fs_type,
+        })
+    } else {
+        None
+    }
+}
+
+/// what to store in a snapshot
+pub enum SnapshotSource {
+    /// the directory becomes the root tree of the snapshot
+    Dir(PathBuf),
+    /// every path is stored at its absolute location below a synthetic root directory,
+    /// e.g. /home/user/Documents and /etc/nginx both end up in one tree
+    Paths(Vec<PathBuf>),
+    /// the content of reader is stored as a file at the given path in the previous snapshot,
+    /// which is kept otherwise
+    Stream {
+        path: PathBuf,
+        reader: Box<dyn Read>,
+    },
+}
+
 pub fn recursive_write_tree_to_db(
     dir: &Path,
-    db: &mut impl db::GittyDatabase,
-    ignorefile: &Path,
-) -> Result<GittyTreeRef, GittyError> {
-    let mut ignore = ignore::gitignore::GitignoreBuilder::new(dir);
-    ignore.add(ignorefile);
-    let ignorer = ignore.build().unwrap();
+    db: &(impl db::GittyDatabase + Sync),
+    options: &WalkOptions,
+    observer: &mut dyn WalkObserver,
+) -> Result<WalkResult, GittyError> {
+    write_source_to_db(
+        SnapshotSource::Dir(dir.to_path_buf()),
+        db,
+        options,
+        observer,
+    )
+}
+
+pub fn write_source_to_db(
+    source: SnapshotSource,
+    db: &(impl db::GittyDatabase + Sync),
+    options: &WalkOptions,
+    observer: &mut dyn WalkObserver,
+) -> Result<WalkResult, GittyError> {
+    let (roots, absolute) = match source {
+        SnapshotSource::Dir(dir) => (vec![dir], false),
+        SnapshotSource::Stream { path, mut reader } => {
+            return write_stream_to_db(&path, &mut *reader, db, observer)
+        }
+        SnapshotSource::Paths(paths) => (paths, true),
+    };
+    let threads = if options.threads > 0 {
+        options.threads
+    } else {
+        thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    };
+    let (job_sender, job_receiver) = mpsc::sync_channel(threads * 4);
+    let job_receiver = Mutex::new(job_receiver);
+    let (result_sender, result_receiver) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let job_receiver = &job_receiver;
+            let result_sender = result_sender.clone();
+            scope.spawn(move || store_blobs(db, options, job_receiver, result_sender));
+        }
+        drop(result_sender);
+        walk(
+            &roots,
+            absolute,
+            db,
+            options,
+            observer,
+            job_sender,
+            result_receiver,
+        )
+    })
+}
+
+fn entry_name(entry: &GittyTreeEntry) -> &OsString {
+    match entry {
+        GittyTreeEntry::Blob(b) => &b.name,
+        GittyTreeEntry::Tree(t) => &t.name,
+    }
+}
+
+fn stream_error(path: &Path, message: &str) -> GittyError {
+    GittyError::new(
+        "snapshot".to_owned(),
+        Box::new(format!("{}: {}", path.display(), message)),
+    )
+}
+
+// store tree with entry at names below it (its parent directories and its own name), returns
+// the file that was replaced
+fn insert_stream_entry(
+    db: &impl db::GittyDatabase,
+    mut tree: GittyTree,
+    dir: &Path,
+    names: &[OsString],
+    entry: GittyTreeEntry,
+    new_dir: &dyn Fn(OsString, GittyHash) -> GittyTreeEntry,
+    observer: &mut dyn WalkObserver,
+) -> Result<(GittyTreeRef, Option<GittyBlobMetadata>), GittyError> {
+    let path = dir.join(&names[0]);
+    let existing = tree
+        .entries
+        .iter()
+        .position(|e| *entry_name(e) == names[0])
+        .map(|i| tree.entries.remove(i));
+    let (new_entry, replaced) = match (existing, names.len()) {
+        (Some(GittyTreeEntry::Tree(_)), 1) => {
+            return Err(stream_error(
+                &path,
+                "is a directory in the previous snapshot",
+            ))
+        }
+        (Some(GittyTreeEntry::Blob(_)), n) if n > 1 => {
+            return Err(stream_error(&path, "is a file in the previous snapshot"))
+        }
+        (Some(GittyTreeEntry::Blob(old)), _) => (entry, Some(old)),
+        (None, 1) => (entry, None),
+        (Some(GittyTreeEntry::Tree(mut parent)), _) => {
+            let subtree = db.load_tree(&GittyTreeRef {
+                hash: parent.hash.clone(),
+            })?;
+            let (tree_ref, replaced) =
+                insert_stream_entry(db, subtree, &path, &names[1..], entry, new_dir, observer)?;
+            parent.hash = tree_ref.hash;
+            (GittyTreeEntry::Tree(parent), replaced)
+        }
+        (None, _) => {
+            let empty = GittyTree { entries: vec![] };
+            let (tree_ref, replaced) =
+                insert_stream_entry(db, empty, &path, &names[1..], entry, new_dir, observer)?;
+            (new_dir(names[0].clone(), tree_ref.hash), replaced)
+        }
+    };
+    tree.entries.push(new_entry);
+    // like the walker: directories first, then by name
+    tree.entries.sort_by(|a, b| {
+        let is_dir = |e: &GittyTreeEntry| matches!(e, GittyTreeEntry::Tree(_));
+        is_dir(a)
+            .cmp(&is_dir(b))
+            .reverse()
+            .then_with(|| entry_name(a).cmp(entry_name(b)))
+    });
+    let tree_ref = db.store_tree(tree)?;
+    observer.event(&WalkEvent::TreeStored {
+        path: dir,
+        hash: &tree_ref.hash,
+    });
+    Ok((tree_ref, replaced))
+}
+
+// store the content of reader as a file with the given path in the tree of HEAD, so the rest of
+// the previous snapshot is kept
+fn write_stream_to_db(
+    path: &Path,
+    reader: &mut dyn Read,
+    db: &impl db::GittyDatabase,
+    observer: &mut dyn WalkObserver,
+) -> Result<WalkResult, GittyError> {
+    let names: Vec<OsString> = path
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_os_string()),
+            _ => None,
+        })
+        .collect();
+    if names.is_empty() || path.components().any(|c| c == Component::ParentDir) {
+        return Err(stream_error(path, "invalid path for a stream"));
+    }
+    let head = db.load_commit(&db.get_head_commit()?)?;
+    let base = db.load_tree(&GittyTreeRef { hash: head.root })?;
+    observer.event(&WalkEvent::FileStarted { path, size: 0 });
+    let stored = db.store_blob_from_reader(reader)?;
+    observer.event(&WalkEvent::BytesHashed {
+        path,
+        bytes: stored.size,
+    });
+    if stored.deduplicated {
+        observer.event(&WalkEvent::Deduplicated {
+            path,
+            bytes: stored.size,
+            hash: &stored.blob_ref.hash,
+        });
+    }
+    // there is no file to take the metadata from
+    let modified = Utc::now();
+    let permissions = |mode| Permissions {
+        kind: "unix".to_owned(),
+        mode,
+        uid: unsafe { libc::getuid() },
+        gid: unsafe { libc::getgid() },
+    };
+    let entry = GittyTreeEntry::Blob(GittyBlobMetadata {
+        name: names[names.len() - 1].clone(),
+        modified,
+        permissions: permissions(libc::S_IFREG | 0o600),
+        size: stored.size,
+        is_symlink: false,
+        unstable: false,
+        hash: stored.blob_ref.hash,
+    });
+    let new_dir = |name, hash| {
+        GittyTreeEntry::Tree(GittyTreeMetadata {
+            name,
+            modified,
+            permissions: permissions(libc::S_IFDIR | 0o700),
+            hash,
+        })
+    };
+    let (root, replaced) =
+        insert_stream_entry(db, base, Path::new(""), &names, entry, &new_dir, observer)?;
+    // the problems of the previous snapshot were not seen by this one, only the totals carry over
+    let mut info = GittySnapshotInfo {
+        files: head.snapshot.files,
+        bytes: head.snapshot.bytes,
+        ..GittySnapshotInfo::default()
+    };
+    if let Some(old) = replaced {
+        info.files = info.files.saturating_sub(1);
+        info.bytes = info.bytes.saturating_sub(old.size);
+    }
+    info.files += 1;
+    info.bytes += stored.size;
+    observer.event(&WalkEvent::Finished {
+        files: info.files,
+        bytes: info.bytes,
+    });
+    Ok(WalkResult { root, info })
+}
+
+// absolute path of the entry itself, a symlink is not resolved
+fn absolute_path(path: &Path) -> Result<PathBuf, GittyError> {
+    let absolute = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if parent != Path::new("") => {
+            parent.canonicalize().map(|p| p.join(name))
+        }
+        (_, Some(name)) => Path::new(".").canonicalize().map(|p| p.join(name)),
+        // "/", "." or ending in ".."
+        (_, None) => path.canonicalize(),
+    };
+    absolute
+        .and_then(|p| p.symlink_metadata().map(|_| p))
+        .map_err(|e| GittyError::new(format!("{}", path.display()), Box::new(e)))
+}
+
+// absolute paths without the ones contained in others, in the order the walker visits them.
+// paths that can not be found are handled like any other unreadable path
+fn normalize_roots(paths: &[PathBuf], log: &mut WalkLog) -> Result<Vec<PathBuf>, GittyError> {
+    let mut absolute = Vec::new();
+    for path in paths {
+        match with_retries(log.policy, || absolute_path(path)) {
+            Ok(p) => absolute.push(p),
+            Err(e) => log.handle(path, e)?,
+        }
+    }
+    // a path sorts directly after its parent directories
+    absolute.sort();
+    let mut roots: Vec<(PathBuf, bool)> = Vec::new();
+    for path in absolute {
+        if let Some((last, _)) = roots.last() {
+            if path.starts_with(last) {
+                continue;
+            }
+        }
+        match with_retries(log.policy, || path.symlink_metadata()) {
+            Ok(metadata) => roots.push((path, metadata.is_dir())),
+            Err(e) => log.handle(&path, GittyError::from(e))?,
+        }
+    }
+    if roots.is_empty() {
+        return Err(GittyError::new(
+            "snapshot".to_owned(),
+            Box::new("no paths given"),
+        ));
+    }
+    // like the walker: directories first, then by name
+    roots.sort_by(|(a, a_is_dir), (b, b_is_dir)| {
+        let (a_len, b_len) = (a.iter().count(), b.iter().count());
+        for (i, (a_name, b_name)) in a.iter().zip(b.iter()).enumerate() {
+            if a_name != b_name {
+                let a_dir = i + 1 < a_len || *a_is_dir;
+                let b_dir = i + 1 < b_len || *b_is_dir;
+                return a_dir.cmp(&b_dir).reverse().then_with(|| a_name.cmp(b_name));
+            }
+        }
+        a_len.cmp(&b_len)
+    });
+    Ok(roots.into_iter().map(|(path, _)| path).collect())
+}
+
+// make the stack contain the synthetic root and all parent directories of root.
+// returns the depth of root in the snapshot tree, None if a parent can't be read and root is
+// skipped
+fn enter_parent_dirs(
+    assembler: &mut TreeAssembler<impl db::GittyDatabase>,
+    path_stack: &mut Vec<StackPart>,
+    root: &Path,
+) -> Result<Option<usize>, GittyError> {
+    let mut parents: Vec<&Path> = root.ancestors().skip(1).collect();
+    parents.reverse();
+    // the synthetic root is always on the stack
+    let parents = &parents[1..];
+    let common = parents
+        .iter()
+        .zip(&path_stack[1..])
+        .take_while(|(parent, part)| **parent == part.path)
+        .count();
+    ascend_path_stack(assembler, path_stack, common + 1)?;
+    let mut entered = Vec::new();
+    for parent in &parents[common..] {
+        match with_retries(assembler.log.policy, || parent.symlink_metadata()) {
+            Ok(metadata) => entered.push(StackPart {
+                path: parent.to_path_buf(),
+                name: parent.file_name().unwrap().to_os_string(),
+                metadata,
+                entries: Vec::new(),
+            }),
+            Err(e) => {
+                assembler.log.handle(parent, GittyError::from(e))?;
+                return Ok(None);
+            }
+        }
+    }
+    path_stack.extend(entered);
+    Ok(Some(path_stack.len()))
+}
+
+// roots are stored at their absolute location if absolute is set, otherwise the single root
+// becomes the root tree
+fn walk(
+    roots: &[PathBuf],
+    absolute: bool,
+    db: &impl db::GittyDatabase,
+    options: &WalkOptions,
+    observer: &mut dyn WalkObserver,
+    jobs: SyncSender<BlobJob>,
+    results: Receiver<BlobResult>,
+) -> Result<WalkResult, GittyError> {
+    let check_mounts = options.one_file_system || !options.skip_fs_types.is_empty();
+    let mounts = if check_mounts {
+        Some(options.mount_table.clone().unwrap_or_else(MountTable::load))
+    } else {
+        None
+    };
+    let mut log = WalkLog {
+        policy: options.on_error,
+        observer,
+        info: GittySnapshotInfo::default(),
+    };
+    let roots = if absolute {
+        normalize_roots(roots, &mut log)?
+    } else {
+        roots.to_vec()
+    };
+    // everything is below /, no need for a synthetic root
+    let synthetic_root = absolute && roots[0] != Path::new("/");
+    let mut assembler = TreeAssembler::new(db, log);
+    let mut path_stack: Vec<StackPart> = Vec::new();
+    let workers = BlobWorkers {
+        jobs: &jobs,
+        results: &results,
+    };
+    if synthetic_root {
+        path_stack.push(StackPart {
+            path: PathBuf::from("/"),
+            name: OsString::new(),
+            metadata: Path::new("/").symlink_metadata()?,
+            entries: Vec::new(),
+        });
+    }
+    for root in &roots {
+        let depth = if synthetic_root {
+            match enter_parent_dirs(&mut assembler, &mut path_stack, root)? {
+                Some(depth) => depth,
+                None => continue,
+            }
+        } else {
+            0
+        };
+        walk_root(
+            root,
+            depth,
+            options,
+            mounts.as_ref(),
+            &mut assembler,
+            &mut path_stack,
+            &workers,
+        )?;
+    }
+    ascend_path_stack(&mut assembler, &mut path_stack, 1)?;
+    let root_entry = match (path_stack.pop(), path_stack.is_empty()) {
+        (Some(root_entry), true) => root_entry,
+        _ => return Err(internal_error("root invalid".to_owned())),
+    };
+    let root = assembler.finish_dir(root_entry)?;
+    // wait for the remaining blobs
+    drop(jobs);
+    for result in results.iter() {
+        assembler.blob_stored(result)?;
+    }
+    assembler.store_ready_trees()?;
+    let hash = match assembler.resolve(&root.hash) {
+        Some(hash) if assembler.finished.is_empty() => hash.clone(),
+        _ => return Err(internal_error("root tree was not stored".to_owned())),
+    };
+    let log = assembler.log;
+    log.observer.event(&WalkEvent::Finished {
+        files: log.info.files,
+        bytes: log.info.bytes,
+    });
+    Ok(WalkResult {
+        root: GittyTreeRef { hash },
+        info: log.info,
+    })
+}
+
+// walk one root, which is placed at the given depth of the snapshot tree
+fn walk_root(
+    dir: &Path,
+    depth: usize,
+    options: &WalkOptions,
+    mounts: Option<&MountTable>,
+    assembler: &mut TreeAssembler<impl db::GittyDatabase>,
+    path_stack: &mut Vec<StackPart>,
+    workers: &BlobWorkers,
+) -> Result<(), GittyError> {
+    let rules = RefCell::new(ExcludeRules::new(dir, &options.exclude)?);
+    let root_dev = dir.symlink_metadata()?.dev();
+    let skipped_mounts = RefCell::new(Vec::new());
 
     let walker = walkdir::WalkDir::new(dir)
         .follow_links(false)
         .sort_by(|a, b| {
-            // TODO: prevent calling stat mutliple times (prob important for performance!)
-
             // 1. directories first
             // 2. sort files by name (TODO: OSStr sort consistency?)
-            let mut ord = Ordering::Equal;
-            if let (Ok(ma), Ok(mb)) = (a.metadata(), b.metadata()) {
-                ord = ord.then(ma.is_dir().cmp(&mb.is_dir()).reverse());
-            }
-            ord.then_with(|| a.file_name().cmp(b.file_name()))
+            // file_type() does not need a stat call since links are not followed
+            a.file_type()
+                .is_dir()
+                .cmp(&b.file_type().is_dir())
+                .reverse()
+                .then_with(|| a.file_name().cmp(b.file_name()))
         });
-    let mut path_stack: Vec<StackPart> = Vec::new();
-    for (entry, metadata) in walker
-        .into_iter()
-        .filter_entry(|e: &DirEntry| {
-            let i = ignorer.matched(e.path(), e.file_type().is_dir());
-            match i {
-                ignore::Match::None => true,
-                ignore::Match::Whitelist(_) => true,
-                _ => false,
+    let mut it = walker.into_iter().filter_entry(|e: &DirEntry| {
+        if e.depth() == 0 {
+            return true;
+        }
+        if rules.borrow().is_excluded(e.path(), e.file_type().is_dir()) {
+            return false;
+        }
+        if let Some(mounts) = mounts {
+            if let Some(skipped) = skipped_mount(e, root_dev, options, mounts) {
+                info!("not descending into mount point {:?}", skipped);
+                skipped_mounts.borrow_mut().push(skipped);
+                return false;
             }
-        })
-        .filter_map(|entry| match entry {
-            Err(p) => {
-                warn!("error accessing: {:?}, ignoring", p);
-                return None;
+        }
+        true
+    });
+    while let Some(entry) = it.next() {
+        let (entry, metadata) = match entry {
+            Err(e) => {
+                let path = e.path().unwrap_or(dir).to_path_buf();
+                if e.depth() == 0 {
+                    return Err(GittyError::from(e));
+                }
+                assembler.log.handle(&path, GittyError::from(e))?;
+                continue;
             }
-            Ok(dirent) => {
-                println!("{}", dirent.path().to_string_lossy());
-                match dirent.metadata() {
-                    Ok(m) => Some((dirent, m)),
-                    Err(e) => {
-                        warn!("error accessing: {:?}, ignoring ({:?})", dirent, e);
-                        None
+            Ok(dirent) => match with_retries(options.on_error, || dirent.metadata()) {
+                Ok(m) => (dirent, m),
+                Err(e) if dirent.depth() > 0 => {
+                    assembler.log.handle(dirent.path(), GittyError::from(e))?;
+                    // its contents would have no tree to go into
+                    if dirent.file_type().is_dir() {
+                        it.skip_current_dir();
                     }
+                    continue;
                 }
+                Err(e) => return Err(GittyError::from(e)),
+            },
+        };
+        if metadata.is_dir() {
+            if let Err(e) = rules.borrow_mut().enter_dir(entry.path()) {
+                // the directory is still backed up, with the patterns that could be read
+                assembler
+                    .log
+                    .handle(&entry.path().join(IGNORE_FILENAME), e)?;
             }
-        }) {
-        dirent_to_gitty_tree_entry(db, &mut path_stack, entry, metadata)?;
-    }
-    ascend_path_stack(db, &mut path_stack, 1)?;
-    let root_entry = path_stack.pop().unwrap();
-    if path_stack.len() != 0 {
-        panic!("root invalid");
-    }
-    let root = create_tree_entry(db, root_entry)?;
-    if let GittyTreeEntry::Tree(t) = root {
-        return Ok(GittyTreeRef { hash: t.hash });
-    } else {
-        panic!("root is blob?");
+        }
+        let entry_depth = depth + entry.depth();
+        dirent_to_gitty_tree_entry(
+            assembler,
+            path_stack,
+            workers.jobs,
+            entry,
+            entry_depth,
+            metadata,
+        )?;
+        for result in workers.results.try_iter() {
+            assembler.blob_stored(result)?;
+        }
+        for skipped in skipped_mounts.borrow_mut().drain(..) {
+            assembler.log.observer.event(&WalkEvent::SkippedMount {
+                path: Path::new(&skipped.path),
+            });
+            assembler.log.info.skipped_mounts.push(skipped);
+        }
+        assembler.store_ready_trees()?;
     }
+    Ok(())
 }
@@ -1,8 +1,12 @@
+extern crate argon2;
 extern crate bk_tree;
+extern crate blake3;
+extern crate chacha20poly1305;
 extern crate chrono;
 extern crate env_logger;
 extern crate ignore;
 extern crate serde;
+extern crate serde_cbor;
 extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
@@ -11,12 +15,17 @@ extern crate walkdir;
 extern crate log;
 extern crate digest;
 extern crate hex;
+extern crate hmac;
+extern crate libc;
 extern crate rand;
 extern crate sha2;
 extern crate whoami;
 
 pub mod commits;
 pub mod database;
+pub mod exclude;
 pub mod fs_walk;
 pub mod model;
+pub mod mounts;
+pub mod progress;
 pub mod util;
@@ -1,3 +1,4 @@
+use blake3;
 use chrono::prelude::*;
 use digest::Digest;
 use hex;
@@ -6,33 +7,111 @@ use serde::Deserialize;
 use serde::Deserializer;
 use serde::Serialize;
 use serde::Serializer;
+use sha2::Sha256;
 use std::ffi::OsString;
 use std::fmt;
 use std::fmt::Debug;
 use std::fmt::Display;
 use std::fs;
 use std::os::unix::fs::MetadataExt;
+use util::is_false;
 use util::serde_compact_osstr;
-// TODO: generic hash fn
-#[derive(Clone, PartialEq, Eq, Hash)]
+use util::serde_compact_osstr_vec;
+/// hash function of a repository, chosen when it is created
+#[derive(
+    Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Default, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Sha256,
+    // used for new repositories, it is several times faster than SHA-256
+    #[default]
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<HashAlgorithm> {
+        match name {
+            "sha256" => Some(HashAlgorithm::Sha256),
+            "blake3" => Some(HashAlgorithm::Blake3),
+            _ => None,
+        }
+    }
+
+    /// identifies the algorithm in binary formats, the multihash code
+    pub fn code(self) -> u8 {
+        match self {
+            HashAlgorithm::Sha256 => 0x12,
+            HashAlgorithm::Blake3 => 0x1e,
+        }
+    }
 
+    pub fn from_code(code: u8) -> Option<HashAlgorithm> {
+        match code {
+            0x12 => Some(HashAlgorithm::Sha256),
+            0x1e => Some(HashAlgorithm::Blake3),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct GittyHash {
-    // #[serde(serialize_with = "buffer_to_hex")]
-    pub sha256: [u8; 32],
+    pub algorithm: HashAlgorithm,
+    pub digest: [u8; 32],
 }
 
-/* pub trait GittyHasher {
-    fn get_hasher() -> Digest<BlockSize=Any, OutputSize=Any>;
-    fn convert_output(dig: impl Digest) -> GittyHash;
-}*/
-pub fn get_hasher() -> impl Digest {
-    use sha2::Sha256;
-    return Sha256::default();
+pub enum GittyHasher {
+    Sha256(Sha256),
+    // boxed because it is much larger
+    Blake3(Box<blake3::Hasher>),
 }
-pub fn hasher_output(dig: impl Digest) -> GittyHash {
-    let mut sha256 = [0; 32];
-    sha256.copy_from_slice(&dig.result()[0..32]);
-    GittyHash { sha256 }
+
+impl GittyHasher {
+    pub fn new(algorithm: HashAlgorithm) -> GittyHasher {
+        match algorithm {
+            HashAlgorithm::Sha256 => GittyHasher::Sha256(Sha256::default()),
+            HashAlgorithm::Blake3 => GittyHasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    /// BLAKE3 in keyed mode, a MAC with the key
+    pub fn keyed_blake3(key: &[u8; 32]) -> GittyHasher {
+        GittyHasher::Blake3(Box::new(blake3::Hasher::new_keyed(key)))
+    }
+
+    pub fn input(&mut self, data: &[u8]) {
+        match self {
+            GittyHasher::Sha256(h) => h.update(data),
+            GittyHasher::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    pub fn result(self) -> GittyHash {
+        let mut digest = [0; 32];
+        match self {
+            GittyHasher::Sha256(h) => {
+                digest.copy_from_slice(&h.finalize());
+                GittyHash {
+                    algorithm: HashAlgorithm::Sha256,
+                    digest,
+                }
+            }
+            GittyHasher::Blake3(h) => GittyHash {
+                algorithm: HashAlgorithm::Blake3,
+                digest: *h.finalize().as_bytes(),
+            },
+        }
+    }
 }
 
 impl Serialize for GittyHash {
@@ -40,7 +119,7 @@ impl Serialize for GittyHash {
     where
         S: Serializer,
     {
-        serializer.serialize_str(&format!("sha256:{}", &hex::encode(&self.sha256)))
+        serializer.serialize_str(&self.to_string())
     }
 }
 impl<'de> Deserialize<'de> for GittyHash {
@@ -50,51 +129,53 @@ impl<'de> Deserialize<'de> for GittyHash {
     {
         use serde::de::Error;
         String::deserialize(deserializer).and_then(|string| {
-            let mut string = string.clone();
-            let hash = string.split_off(7);
-            if string != "sha256:" {
-                return Err(Error::custom("not a sha256 hash"));
-            }
-            let v = Vec::from_hex(&hash).map_err(|err| Error::custom(err.to_string()))?;
+            let mut parts = string.splitn(2, ':');
+            let algorithm = parts
+                .next()
+                .and_then(HashAlgorithm::from_name)
+                .ok_or_else(|| Error::custom("unknown hash algorithm"))?;
+            let v = Vec::from_hex(parts.next().unwrap_or(""))
+                .map_err(|err| Error::custom(err.to_string()))?;
             if v.len() != 32 {
-                return Err(Error::custom("invalid sha256 hash"));
+                return Err(Error::custom("invalid hash length"));
             }
-            let mut sha256 = [0; 32];
-            sha256.copy_from_slice(&v[0..32]);
-            Ok(GittyHash { sha256 })
+            let mut digest = [0; 32];
+            digest.copy_from_slice(&v[0..32]);
+            Ok(GittyHash { algorithm, digest })
         })
     }
 }
 
 impl fmt::Display for GittyHash {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "sha256:{}", hex::encode(self.sha256))
+        write!(f, "{}:{}", self.algorithm.name(), hex::encode(self.digest))
     }
 }
 impl fmt::Debug for GittyHash {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "sha256:{}", hex::encode(self.sha256))
+        write!(f, "{}:{}", self.algorithm.name(), hex::encode(self.digest))
     }
 }
 
 pub const PLACEHOLDER_HASH: GittyHash = GittyHash {
-    sha256: [
+    algorithm: HashAlgorithm::Sha256,
+    digest: [
         0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
         0, 0,
     ],
 };
 
-#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Serialize, Deserialize)]
 pub struct GittyTreeRef {
     pub hash: GittyHash,
 }
 
-#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Serialize, Deserialize)]
 pub struct GittyBlobRef {
     pub hash: GittyHash,
 }
 
-#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Serialize, Deserialize)]
 pub struct GittyCommitRef {
     pub hash: GittyHash,
 }
@@ -112,7 +193,7 @@ impl<'a> GittyObjectRef<'a> {
     }
 }
 
-#[derive(PartialEq, Eq, Hash)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub enum OwnedGittyObjectRef {
     Tree(GittyTreeRef),
     Blob(GittyBlobRef),
@@ -162,6 +243,53 @@ pub struct GittyCommit {
     // should be Vec<GittyCommitRef> and root: GittyTreeRef but then serialization looks ugly
     pub parents: Vec<GittyHash>,
     pub root: GittyHash,
+    #[serde(default, skip_serializing_if = "GittySnapshotInfo::is_empty")]
+    pub snapshot: GittySnapshotInfo,
+}
+
+// information about what was (not) captured when creating a snapshot
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct GittySnapshotInfo {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skipped_mounts: Vec<GittySkippedMount>,
+    // paths that could not be backed up
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<GittyWalkError>,
+    // files that kept changing while they were read
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        with = "serde_compact_osstr_vec"
+    )]
+    pub unstable: Vec<OsString>,
+    // number and total size of the files in the snapshot
+    #[serde(default)]
+    pub files: u64,
+    #[serde(default)]
+    pub bytes: u64,
+}
+
+impl GittySnapshotInfo {
+    pub fn is_empty(&self) -> bool {
+        self.skipped_mounts.is_empty()
+            && self.errors.is_empty()
+            && self.unstable.is_empty()
+            && self.files == 0
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GittySkippedMount {
+    #[serde(with = "serde_compact_osstr")]
+    pub path: OsString,
+    pub fs_type: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GittyWalkError {
+    #[serde(with = "serde_compact_osstr")]
+    pub path: OsString,
+    pub message: String,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -181,6 +309,9 @@ pub struct GittyBlobMetadata {
     pub permissions: Permissions,
     pub size: u64,
     pub is_symlink: bool, // blob contains symlink target as text
+    // file changed while it was read, content may be inconsistent
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub unstable: bool,
     pub hash: GittyHash,
 }
 
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::ffi::OsStringExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+const MOUNTS_PATH: &str = "/proc/self/mounts";
+
+/// pseudo filesystems that never contain user data
+pub const VIRTUAL_FS_TYPES: &[&str] = &[
+    "autofs",
+    "binfmt_misc",
+    "bpf",
+    "cgroup",
+    "cgroup2",
+    "configfs",
+    "debugfs",
+    "devpts",
+    "devtmpfs",
+    "efivarfs",
+    "fusectl",
+    "hugetlbfs",
+    "mqueue",
+    "proc",
+    "pstore",
+    "securityfs",
+    "sysfs",
+    "tracefs",
+];
+
+/// mount point -> filesystem type
+#[derive(Clone, Debug, Default)]
+pub struct MountTable {
+    fs_types: HashMap<PathBuf, String>,
+}
+
+impl MountTable {
+    pub fn load() -> MountTable {
+        match fs::read(MOUNTS_PATH) {
+            Ok(content) => MountTable::parse(&content),
+            Err(e) => {
+                warn!("could not read {}: {}", MOUNTS_PATH, e);
+                MountTable::default()
+            }
+        }
+    }
+
+    /// parse a mount table in the format of /proc/self/mounts
+    pub fn parse(content: &[u8]) -> MountTable {
+        MountTable {
+            fs_types: parse_mounts(content),
+        }
+    }
+
+    /// whether path is listed as a mount point. unlike fs_type, path is not canonicalized
+    pub fn is_mount_point(&self, path: &Path) -> bool {
+        self.fs_types.contains_key(path)
+    }
+
+    /// filesystem type of the mount point at path, None if path is not a mount point
+    pub fn fs_type(&self, path: &Path) -> Option<&str> {
+        path.canonicalize()
+            .ok()
+            .and_then(|p| self.fs_types.get(&p))
+            .map(|s| s.as_str())
+    }
+}
+
+fn parse_mounts(content: &[u8]) -> HashMap<PathBuf, String> {
+    let mut fs_types = HashMap::new();
+    for line in content.split(|&c| c == b'\n') {
+        let mut fields = line.split(|&c| c == b' ');
+        if let (Some(_), Some(mount_point), Some(fs_type)) =
+            (fields.next(), fields.next(), fields.next())
+        {
+            // later mounts shadow earlier ones on the same mount point
+            fs_types.insert(
+                PathBuf::from(OsString::from_vec(unescape_octal(mount_point))),
+                String::from_utf8_lossy(fs_type).into_owned(),
+            );
+        }
+    }
+    fs_types
+}
+
+// spaces, tabs, newlines and backslashes are escaped as \ooo
+fn unescape_octal(s: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        if s[i] == b'\\'
+            && i + 3 < s.len()
+            && s[i + 1..i + 4].iter().all(|c| b'0' <= *c && *c <= b'7')
+        {
+            out.push((s[i + 1] - b'0') * 64 + (s[i + 2] - b'0') * 8 + (s[i + 3] - b'0'));
+            i += 4;
+        } else {
+            out.push(s[i]);
+            i += 1;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTS: &[u8] = b"proc /proc proc rw,relatime 0 0
+/dev/sda1 / ext4 rw,relatime 0 0
+/dev/sdb1 /mnt/my\\040disk ext4 rw,relatime 0 0
+server:/export /mnt/tab\\011and\\134backslash nfs4 rw,relatime 0 0
+tmpfs /tmp tmpfs rw 0 0
+/dev/sdc1 /tmp ext4 rw 0 0
+";
+
+    #[test]
+    fn parses_mounts() {
+        let fs_types = parse_mounts(MOUNTS);
+        assert_eq!(fs_types.len(), 5);
+        assert_eq!(fs_types[Path::new("/proc")], "proc");
+        assert_eq!(fs_types[Path::new("/")], "ext4");
+        assert_eq!(fs_types[Path::new("/mnt/my disk")], "ext4");
+        assert_eq!(fs_types[Path::new("/mnt/tab\tand\\backslash")], "nfs4");
+        // the last mount on a mount point wins
+        assert_eq!(fs_types[Path::new("/tmp")], "ext4");
+    }
+
+    #[test]
+    fn unescapes_octal() {
+        assert_eq!(unescape_octal(b"a\\040b"), b"a b");
+        assert_eq!(unescape_octal(b"\\011\\012\\134"), b"\t\n\\");
+        // not a complete octal escape
+        assert_eq!(unescape_octal(b"a\\04"), b"a\\04");
+        assert_eq!(unescape_octal(b"a\\089"), b"a\\089");
+        assert_eq!(unescape_octal(b"a\\"), b"a\\");
+    }
+
+    #[test]
+    fn mount_points_are_matched_exactly() {
+        let table = MountTable::parse(MOUNTS);
+        assert!(table.is_mount_point(Path::new("/mnt/my disk")));
+        assert!(!table.is_mount_point(Path::new("/mnt")));
+    }
+}
@@ -0,0 +1,197 @@
+use model::GittyHash;
+use serde::Serializer;
+use serde_json;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Events emitted while creating a snapshot, all from the walking thread
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WalkEvent<'a> {
+    FileStarted {
+        #[serde(serialize_with = "serialize_path")]
+        path: &'a Path,
+        size: u64,
+    },
+    BytesHashed {
+        #[serde(serialize_with = "serialize_path")]
+        path: &'a Path,
+        bytes: u64,
+    },
+    // the file content was already stored
+    Deduplicated {
+        #[serde(serialize_with = "serialize_path")]
+        path: &'a Path,
+        bytes: u64,
+        hash: &'a GittyHash,
+    },
+    Unstable {
+        #[serde(serialize_with = "serialize_path")]
+        path: &'a Path,
+    },
+    Error {
+        #[serde(serialize_with = "serialize_path")]
+        path: &'a Path,
+        message: &'a str,
+    },
+    SkippedMount {
+        #[serde(serialize_with = "serialize_path")]
+        path: &'a Path,
+    },
+    TreeStored {
+        #[serde(serialize_with = "serialize_path")]
+        path: &'a Path,
+        hash: &'a GittyHash,
+    },
+    Finished {
+        files: u64,
+        bytes: u64,
+    },
+}
+
+fn serialize_path<S>(path: &&Path, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&path.to_string_lossy())
+}
+
+pub trait WalkObserver {
+    fn event(&mut self, event: &WalkEvent);
+}
+
+pub struct NoObserver;
+
+impl WalkObserver for NoObserver {
+    fn event(&mut self, _event: &WalkEvent) {}
+}
+
+/// writes every event as one line of JSON
+pub struct JsonEvents<W: Write> {
+    out: W,
+    // stop writing after the first error (e.g. closed pipe)
+    failed: bool,
+}
+
+impl<W: Write> JsonEvents<W> {
+    pub fn new(out: W) -> JsonEvents<W> {
+        JsonEvents { out, failed: false }
+    }
+}
+
+impl<W: Write> WalkObserver for JsonEvents<W> {
+    fn event(&mut self, event: &WalkEvent) {
+        if self.failed {
+            return;
+        }
+        let res = serde_json::to_writer(&mut self.out, event)
+            .map_err(io::Error::from)
+            .and_then(|_| writeln!(self.out));
+        if let Err(e) = res {
+            warn!("could not write events: {}", e);
+            self.failed = true;
+        }
+    }
+}
+
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+const BAR_WIDTH: usize = 30;
+
+/// progress bar on stderr. the ETA is estimated from the size of the previous snapshot
+pub struct ProgressBar {
+    expected_bytes: Option<u64>,
+    files: u64,
+    bytes: u64,
+    deduplicated_bytes: u64,
+    started: Instant,
+    last_draw: Option<Instant>,
+}
+
+impl ProgressBar {
+    pub fn new(expected_bytes: Option<u64>) -> ProgressBar {
+        ProgressBar {
+            expected_bytes: expected_bytes.filter(|b| *b > 0),
+            files: 0,
+            bytes: 0,
+            deduplicated_bytes: 0,
+            started: Instant::now(),
+            last_draw: None,
+        }
+    }
+
+    fn draw(&mut self) {
+        let mut line = String::new();
+        if let Some(expected) = self.expected_bytes {
+            let fraction = (self.bytes as f64 / expected as f64).min(1.0);
+            let filled = (fraction * BAR_WIDTH as f64) as usize;
+            line += &format!(
+                "[{}{}] {:3.0}% ",
+                "#".repeat(filled),
+                "-".repeat(BAR_WIDTH - filled),
+                fraction * 100.0
+            );
+        }
+        line += &format!(
+            "{} in {} files ({} deduplicated)",
+            format_bytes(self.bytes),
+            self.files,
+            format_bytes(self.deduplicated_bytes)
+        );
+        if let (Some(expected), true) = (self.expected_bytes, self.bytes > 0) {
+            let elapsed = self.started.elapsed().as_secs_f64();
+            let remaining = expected.saturating_sub(self.bytes) as f64;
+            let eta = (elapsed * remaining / self.bytes as f64) as u64;
+            line += &format!(", ETA {}:{:02}", eta / 60, eta % 60);
+        }
+        eprint!("\r\x1b[K{}", line);
+        self.last_draw = Some(Instant::now());
+    }
+}
+
+impl WalkObserver for ProgressBar {
+    fn event(&mut self, event: &WalkEvent) {
+        match event {
+            WalkEvent::BytesHashed { bytes, .. } => {
+                self.files += 1;
+                self.bytes += bytes;
+            }
+            WalkEvent::Deduplicated { bytes, .. } => self.deduplicated_bytes += bytes,
+            WalkEvent::Error { .. } => {
+                // the error itself is logged, don't draw over it
+                eprint!("\r\x1b[K");
+                self.last_draw = None;
+                return;
+            }
+            WalkEvent::Finished { .. } => {
+                self.draw();
+                eprintln!();
+                return;
+            }
+            _ => return,
+        }
+        if self
+            .last_draw
+            .is_none_or(|t| t.elapsed() >= REDRAW_INTERVAL)
+        {
+            self.draw();
+        }
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
@@ -1,3 +1,7 @@
+pub fn is_false(b: &bool) -> bool {
+    !*b
+}
+
 pub mod serde_compact_osstr {
     use serde::Deserialize;
     use serde::Deserializer;
@@ -23,3 +27,38 @@ pub mod serde_compact_osstr {
         String::deserialize(deserializer).and_then(|string| Ok(OsString::from(string)))
     }
 }
+
+pub mod serde_compact_osstr_vec {
+    use serde::ser::SerializeSeq;
+    use serde::Deserialize;
+    use serde::Deserializer;
+    use serde::Serializer;
+    use std::ffi::OsString;
+    struct Compact<'a>(&'a OsString);
+    impl<'a> ::serde::Serialize for Compact<'a> {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            super::serde_compact_osstr::serialize(self.0, serializer)
+        }
+    }
+    pub fn serialize<S>(v: &Vec<OsString>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut seq = serializer.serialize_seq(Some(v.len()))?;
+        for s in v {
+            seq.serialize_element(&Compact(s))?;
+        }
+        seq.end()
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<OsString>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Vec::<String>::deserialize(deserializer)
+            .map(|v| v.into_iter().map(OsString::from).collect())
+    }
+}
@@ -0,0 +1,112 @@
+extern crate gitty_backup_rs;
+extern crate tempfile;
+
+use gitty_backup_rs::commits::commit_current_state_to_head;
+use gitty_backup_rs::commits::walk_commits;
+use gitty_backup_rs::database::commit_graph;
+use gitty_backup_rs::database::commit_graph::CommitGraphEntry;
+use gitty_backup_rs::database::crypto::KeySource;
+use gitty_backup_rs::database::fs_database::FSDatabase;
+use gitty_backup_rs::database::memory_database::MemoryDatabase;
+use gitty_backup_rs::database::GittyDatabase;
+use gitty_backup_rs::fs_walk::SnapshotSource;
+use gitty_backup_rs::fs_walk::WalkOptions;
+use gitty_backup_rs::model::*;
+use gitty_backup_rs::progress::NoObserver;
+use std::fs;
+use std::path::Path;
+
+fn commit(dir: &Path, db: &(impl GittyDatabase + Sync), i: usize) -> GittyCommitRef {
+    fs::write(dir.join("file"), format!("version {}", i)).unwrap();
+    commit_current_state_to_head(
+        SnapshotSource::Dir(dir.to_path_buf()),
+        db,
+        &WalkOptions::default(),
+        &mut NoObserver,
+    )
+    .unwrap_or_else(|e| panic!("{}", e))
+}
+
+fn walked(db: &dyn GittyDatabase) -> Vec<CommitGraphEntry> {
+    let head = db.get_head_commit().unwrap_or_else(|_| panic!());
+    walk_commits(db, head)
+        .map(|c| c.map(|(commit_ref, commit)| CommitGraphEntry::new(commit_ref, &commit)))
+        .collect::<Result<_, _>>()
+        .unwrap_or_else(|e| panic!("{}", e))
+}
+
+fn graph(db: &dyn GittyDatabase) -> Vec<CommitGraphEntry> {
+    db.commit_graph().unwrap_or_else(|_| panic!())
+}
+
+#[test]
+fn graph_is_maintained_on_commit() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    fs::create_dir(&source).unwrap();
+    let repo = dir.path().join("repo");
+    let db = FSDatabase::create_or_open(&repo, None).unwrap_or_else(|e| panic!("{}", e));
+    assert!(graph(&db).is_empty());
+    for i in 0..3 {
+        commit(&source, &db, i);
+        let stored = commit_graph::decode(&fs::read(repo.join("commit-graph")).unwrap()).unwrap();
+        assert_eq!(stored.len(), i + 1);
+        assert_eq!(stored, walked(&db));
+    }
+    let depths: Vec<u64> = graph(&db).iter().map(|e| e.depth).collect();
+    assert_eq!(depths, vec![3, 2, 1]);
+
+    // lost or written by an older version, built again from the commits
+    fs::remove_file(repo.join("commit-graph")).unwrap();
+    assert_eq!(graph(&db), walked(&db));
+    assert!(repo.join("commit-graph").exists());
+    fs::write(repo.join("commit-graph"), b"garbage").unwrap();
+    assert_eq!(graph(&db), walked(&db));
+}
+
+#[test]
+fn encrypted_graph_is_unreadable() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    fs::create_dir(&source).unwrap();
+    let repo = dir.path().join("repo");
+    let key = KeySource::Passphrase("passphrase".to_owned());
+    let db = FSDatabase::create_or_open(&repo, Some(key)).unwrap_or_else(|e| panic!("{}", e));
+    commit(&source, &db, 0);
+    let stored = fs::read(repo.join("commit-graph")).unwrap();
+    assert!(commit_graph::decode(&stored).is_err());
+    let root = &graph(&db)[0].root.digest;
+    assert!(!stored.windows(root.len()).any(|w| w == root));
+    assert_eq!(graph(&db), walked(&db));
+}
+
+#[test]
+fn memory_database_graph() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = MemoryDatabase::new();
+    for i in 0..2 {
+        commit(dir.path(), &db, i);
+    }
+    let entries = graph(&db);
+    assert_eq!(entries.len(), 2);
+    assert_eq!(
+        commit_graph::decode(&commit_graph::encode(&entries)).unwrap(),
+        entries
+    );
+}
+
+#[test]
+fn graph_errors_dont_fail_the_commit() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    fs::create_dir(&source).unwrap();
+    let repo = dir.path().join("repo");
+    let db = FSDatabase::create_or_open(&repo, None).unwrap_or_else(|e| panic!("{}", e));
+    commit(&source, &db, 0);
+    // the graph can't be replaced
+    fs::remove_file(repo.join("commit-graph")).unwrap();
+    fs::create_dir_all(repo.join("commit-graph").join("in-the-way")).unwrap();
+    let head = commit(&source, &db, 1);
+    assert_eq!(db.get_head_commit().unwrap_or_else(|_| panic!()), head);
+    assert_eq!(graph(&db), walked(&db));
+}
@@ -0,0 +1,71 @@
+extern crate gitty_backup_rs;
+extern crate tempfile;
+
+use gitty_backup_rs::commits::commit_current_state_to_head;
+use gitty_backup_rs::commits::walk_commits;
+use gitty_backup_rs::database::memory_database::MemoryDatabase;
+use gitty_backup_rs::database::GittyDatabase;
+use gitty_backup_rs::fs_walk::SnapshotSource;
+use gitty_backup_rs::fs_walk::WalkOptions;
+use gitty_backup_rs::model::*;
+use gitty_backup_rs::progress::NoObserver;
+use std::fs;
+use std::path::Path;
+
+fn commit(dir: &Path, db: &MemoryDatabase) -> GittyCommitRef {
+    commit_current_state_to_head(
+        SnapshotSource::Dir(dir.to_path_buf()),
+        db,
+        &WalkOptions::default(),
+        &mut NoObserver,
+    )
+    .unwrap_or_else(|e| panic!("{}", e))
+}
+
+#[test]
+fn new_database_has_no_commits_to_walk() {
+    let db = MemoryDatabase::new();
+    let head = db.get_head_commit().unwrap_or_else(|_| panic!());
+    assert_eq!(walk_commits(&db, head).count(), 0);
+}
+
+#[test]
+fn walks_commits_newest_first() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = MemoryDatabase::new();
+    let mut refs = Vec::new();
+    for i in 0..3 {
+        fs::write(dir.path().join("file"), format!("version {}", i)).unwrap();
+        refs.push(commit(dir.path(), &db));
+    }
+    let head = db.get_head_commit().unwrap_or_else(|_| panic!());
+    assert_eq!(head, refs[2]);
+    let walked: Vec<(GittyCommitRef, GittyCommit)> = walk_commits(&db, head)
+        .collect::<Result<_, _>>()
+        .unwrap_or_else(|e| panic!("{}", e));
+    refs.reverse();
+    assert_eq!(
+        walked.iter().map(|(r, _)| r.clone()).collect::<Vec<_>>(),
+        refs
+    );
+    let depths: Vec<u64> = walked.iter().map(|(_, c)| c.depth).collect();
+    assert_eq!(depths, vec![3, 2, 1]);
+    // every commit has a different root since the file changed
+    assert_ne!(walked[0].1.root, walked[1].1.root);
+    assert_eq!(walked[0].1.parents, vec![refs[1].hash.clone()]);
+}
+
+#[test]
+fn unchanged_directory_keeps_root() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("file"), "content").unwrap();
+    let db = MemoryDatabase::new();
+    let first = db
+        .load_commit(&commit(dir.path(), &db))
+        .unwrap_or_else(|_| panic!());
+    let second = db
+        .load_commit(&commit(dir.path(), &db))
+        .unwrap_or_else(|_| panic!());
+    assert_eq!(first.root, second.root);
+    assert_eq!(second.snapshot.files, 1);
+}
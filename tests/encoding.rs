@@ -0,0 +1,137 @@
+extern crate chrono;
+extern crate gitty_backup_rs;
+extern crate serde_json;
+
+use chrono::prelude::*;
+use gitty_backup_rs::database::encoding::*;
+use gitty_backup_rs::model::*;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+
+fn hash(algorithm: HashAlgorithm, byte: u8) -> GittyHash {
+    GittyHash {
+        algorithm,
+        digest: [byte; 32],
+    }
+}
+
+fn permissions(mode: u32) -> Permissions {
+    Permissions {
+        kind: "unix".to_owned(),
+        mode,
+        uid: 1000,
+        gid: 100,
+    }
+}
+
+fn example_tree() -> GittyTree {
+    GittyTree {
+        entries: vec![
+            GittyTreeEntry::Tree(GittyTreeMetadata {
+                name: OsString::from("dir"),
+                modified: Utc.timestamp(1_500_000_000, 5),
+                permissions: permissions(0o40755),
+                hash: hash(HashAlgorithm::Sha256, 1),
+            }),
+            GittyTreeEntry::Blob(GittyBlobMetadata {
+                name: OsString::from_vec(vec![b'f', 0xff]),
+                modified: Utc.timestamp(1_500_000_001, 0),
+                permissions: permissions(0o100644),
+                size: 123_456,
+                is_symlink: false,
+                unstable: true,
+                hash: hash(HashAlgorithm::Blake3, 2),
+            }),
+        ],
+    }
+}
+
+fn example_commit() -> GittyCommit {
+    let author = GittyAuthor {
+        name: "user".to_owned(),
+        email: "user@host".to_owned(),
+    };
+    let time = FixedOffset::east(7200).timestamp(1_500_000_000, 999);
+    GittyCommit {
+        author: author.clone(),
+        committer: author,
+        author_time: time,
+        commit_time: time,
+        message: "snapshot".to_owned(),
+        depth: 2,
+        parents: vec![hash(HashAlgorithm::Blake3, 3)],
+        root: hash(HashAlgorithm::Blake3, 4),
+        snapshot: GittySnapshotInfo {
+            skipped_mounts: vec![GittySkippedMount {
+                path: OsString::from("/proc"),
+                fs_type: Some("proc".to_owned()),
+            }],
+            errors: vec![GittyWalkError {
+                path: OsString::from_vec(vec![0xfe]),
+                message: "permission denied".to_owned(),
+            }],
+            unstable: vec![OsString::from("/var/log/syslog")],
+            files: 10,
+            bytes: 1 << 40,
+        },
+    }
+}
+
+// the model types don't implement PartialEq
+fn debug(object: &impl std::fmt::Debug) -> String {
+    format!("{:?}", object)
+}
+
+#[test]
+fn tree_roundtrip() {
+    let tree = example_tree();
+    let decoded = decode_tree(&encode_tree(&tree)).unwrap();
+    assert_eq!(debug(&decoded), debug(&tree));
+}
+
+#[test]
+fn commit_roundtrip() {
+    let commit = example_commit();
+    let decoded = decode_commit(&encode_commit(&commit)).unwrap();
+    assert_eq!(debug(&decoded), debug(&commit));
+}
+
+#[test]
+fn reads_json_objects() {
+    let tree = GittyTree {
+        entries: vec![example_tree().entries.remove(0)],
+    };
+    let json = serde_json::to_vec(&tree).unwrap();
+    assert_eq!(debug(&decode_tree(&json).unwrap()), debug(&tree));
+    let mut commit = example_commit();
+    // JSON can't store paths that aren't UTF-8
+    commit.snapshot.errors[0].path = OsString::from("/unreadable");
+    let json = serde_json::to_vec(&commit).unwrap();
+    assert_eq!(debug(&decode_commit(&json).unwrap()), debug(&commit));
+}
+
+#[test]
+fn encoding_is_stable() {
+    // hashes of stored trees depend on these bytes, they must never change for a version
+    let tree = GittyTree {
+        entries: vec![example_tree().entries.remove(0)],
+    };
+    assert_eq!(
+        hex(&encode_tree(&tree)),
+        "820181850043646972821a59682f00058464756e69781941ed1903e8186458211201010101010101\
+         01010101010101010101010101010101010101010101010101"
+    );
+}
+
+#[test]
+fn rejects_unknown_versions() {
+    let mut encoded = encode_tree(&example_tree());
+    // array of 2, version 1
+    assert_eq!(&encoded[..2], &[0x82, 0x01]);
+    encoded[1] = 0x02;
+    assert!(decode_tree(&encoded).is_err());
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,225 @@
+extern crate gitty_backup_rs;
+extern crate tempfile;
+
+use gitty_backup_rs::database::crypto::KeySource;
+use gitty_backup_rs::database::crypto::KeyedHasher;
+use gitty_backup_rs::database::fs_database::FSDatabase;
+use gitty_backup_rs::database::GittyDatabase;
+use gitty_backup_rs::fs_walk::recursive_write_tree_to_db;
+use gitty_backup_rs::fs_walk::WalkOptions;
+use gitty_backup_rs::model::*;
+use gitty_backup_rs::progress::NoObserver;
+use std::fs;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+const SECRET_NAME: &str = "secret-filename.txt";
+
+fn key_file(dir: &Path, name: &str, byte: u8) -> KeySource {
+    let path = dir.join(name);
+    fs::write(&path, vec![byte; 32]).unwrap();
+    KeySource::KeyFile(path)
+}
+
+fn all_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files.extend(all_files(&path));
+        } else {
+            files.push(path);
+        }
+    }
+    files
+}
+
+struct Fixture {
+    dir: TempDir,
+    repo: PathBuf,
+    key: KeySource,
+    root: GittyTreeRef,
+}
+
+fn encrypted_snapshot() -> Fixture {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    fs::create_dir(&source).unwrap();
+    fs::write(source.join(SECRET_NAME), "secret content").unwrap();
+    let big: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(source.join("big"), big).unwrap();
+    let key = key_file(dir.path(), "key", 1);
+    let repo = dir.path().join("repo");
+    let db =
+        FSDatabase::create_or_open(&repo, Some(key.clone())).unwrap_or_else(|e| panic!("{}", e));
+    let root = recursive_write_tree_to_db(&source, &db, &WalkOptions::default(), &mut NoObserver)
+        .unwrap_or_else(|e| panic!("{}", e))
+        .root;
+    Fixture {
+        dir,
+        repo,
+        key,
+        root,
+    }
+}
+
+fn blob_ref(tree: &GittyTree, name: &str) -> GittyBlobRef {
+    for entry in &tree.entries {
+        if let GittyTreeEntry::Blob(b) = entry {
+            if b.name == name {
+                return GittyBlobRef {
+                    hash: b.hash.clone(),
+                };
+            }
+        }
+    }
+    panic!("no file {}", name)
+}
+
+#[test]
+fn objects_are_unreadable_on_disk() {
+    let fixture = encrypted_snapshot();
+    for path in all_files(&fixture.repo) {
+        let content = fs::read(&path).unwrap();
+        let content = String::from_utf8_lossy(&content);
+        assert!(!content.contains(SECRET_NAME), "{:?} leaks a name", path);
+        assert!(
+            !content.contains("secret content"),
+            "{:?} leaks content",
+            path
+        );
+    }
+}
+
+#[test]
+fn reopened_repository_can_be_read() {
+    let fixture = encrypted_snapshot();
+    let db = FSDatabase::create_or_open(&fixture.repo, Some(fixture.key.clone()))
+        .unwrap_or_else(|e| panic!("{}", e));
+    let tree = db.load_tree(&fixture.root).unwrap_or_else(|_| panic!());
+    let mut content = String::new();
+    db.load_blob(&blob_ref(&tree, SECRET_NAME))
+        .unwrap_or_else(|_| panic!())
+        .read_to_string(&mut content)
+        .unwrap();
+    assert_eq!(content, "secret content");
+}
+
+#[test]
+fn range_reads_cross_chunks() {
+    let fixture = encrypted_snapshot();
+    let db = FSDatabase::create_or_open(&fixture.repo, Some(fixture.key.clone()))
+        .unwrap_or_else(|e| panic!("{}", e));
+    let tree = db.load_tree(&fixture.root).unwrap_or_else(|_| panic!());
+    let mut reader = db
+        .load_blob(&blob_ref(&tree, "big"))
+        .unwrap_or_else(|_| panic!());
+    assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), 200_000);
+    let mut buf = vec![0u8; 1000];
+    let offset = 64 * 1024 - 500;
+    assert_eq!(reader.read_at(offset, &mut buf).unwrap(), 1000);
+    let expected: Vec<u8> = (offset..offset + 1000).map(|i| (i % 251) as u8).collect();
+    assert_eq!(buf, expected);
+    assert_eq!(reader.read_at(199_900, &mut buf).unwrap(), 100);
+}
+
+#[test]
+fn wrong_or_missing_key_is_rejected() {
+    let fixture = encrypted_snapshot();
+    let wrong = key_file(fixture.dir.path(), "wrong", 2);
+    assert!(FSDatabase::create_or_open(&fixture.repo, Some(wrong)).is_err());
+    assert!(FSDatabase::create_or_open(&fixture.repo, None).is_err());
+    let passphrase = KeySource::Passphrase("passphrase".to_owned());
+    assert!(FSDatabase::create_or_open(&fixture.repo, Some(passphrase)).is_err());
+}
+
+#[test]
+fn modified_objects_are_detected() {
+    let fixture = encrypted_snapshot();
+    let db = FSDatabase::create_or_open(&fixture.repo, Some(fixture.key.clone()))
+        .unwrap_or_else(|e| panic!("{}", e));
+    for path in all_files(&fixture.repo.join("file")) {
+        let mut content = fs::read(&path).unwrap();
+        let last = content.len() - 1;
+        content[last] ^= 1;
+        fs::write(&path, content).unwrap();
+    }
+    let tree = db.load_tree(&fixture.root).unwrap_or_else(|_| panic!());
+    // small files are in packs, big is stored on its own
+    assert!(db.load_blob(&blob_ref(&tree, "big")).is_err());
+}
+
+#[test]
+fn swapped_objects_are_detected() {
+    let dir = tempfile::tempdir().unwrap();
+    let key = key_file(dir.path(), "key", 1);
+    let repo = dir.path().join("repo");
+    let db = FSDatabase::create_or_open(&repo, Some(key)).unwrap_or_else(|e| panic!("{}", e));
+    let mut blobs = Vec::new();
+    for byte in 0..2u8 {
+        let content = vec![byte; 100_000];
+        let stored = db
+            .store_blob_from_reader(&mut &content[..])
+            .unwrap_or_else(|_| panic!());
+        blobs.push(stored.blob_ref);
+    }
+    let files = all_files(&repo.join("file"));
+    assert_eq!(files.len(), 2);
+    let first = fs::read(&files[0]).unwrap();
+    fs::copy(&files[1], &files[0]).unwrap();
+    fs::write(&files[1], first).unwrap();
+    for blob in &blobs {
+        assert!(db.load_blob(blob).is_err());
+    }
+}
+
+#[test]
+fn changed_key_replaces_old_key() {
+    let fixture = encrypted_snapshot();
+    let db = FSDatabase::create_or_open(&fixture.repo, Some(fixture.key.clone()))
+        .unwrap_or_else(|e| panic!("{}", e));
+    let passphrase = KeySource::Passphrase("correct horse battery staple".to_owned());
+    db.change_key(&passphrase)
+        .unwrap_or_else(|e| panic!("{}", e));
+    assert!(FSDatabase::create_or_open(&fixture.repo, Some(fixture.key.clone())).is_err());
+    let db = FSDatabase::create_or_open(&fixture.repo, Some(passphrase))
+        .unwrap_or_else(|e| panic!("{}", e));
+    // the master key is the same, so old objects are still readable
+    assert_eq!(
+        db.load_tree(&fixture.root)
+            .unwrap_or_else(|_| panic!())
+            .entries
+            .len(),
+        2
+    );
+}
+
+// object ids of existing repositories must not change
+#[test]
+fn keyed_hasher_is_hmac_sha256() {
+    // RFC 4231, test cases 2 and 6
+    let cases: [(&[u8], &[u8], &str); 2] = [
+        (
+            b"Jefe",
+            b"what do ya want for nothing?",
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+        ),
+        (
+            &[0xaa; 131],
+            b"Test Using Larger Than Block-Size Key - Hash Key First",
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+        ),
+    ];
+    for &(key, data, mac) in &cases {
+        let mut hasher = KeyedHasher::new(key);
+        hasher.input(data);
+        let hash = hasher.result();
+        assert_eq!(hash.algorithm, HashAlgorithm::Sha256);
+        let hex: String = hash.digest.iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(hex, mac);
+    }
+}
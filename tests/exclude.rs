@@ -0,0 +1,184 @@
+extern crate gitty_backup_rs;
+extern crate tempfile;
+
+use gitty_backup_rs::exclude::ExcludeOptions;
+use gitty_backup_rs::exclude::ExcludeReason;
+use gitty_backup_rs::exclude::ExcludeRules;
+use gitty_backup_rs::exclude::CACHEDIR_TAG_FILENAME;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+fn example_dir() -> TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir_all(dir.path().join("sub/deeper")).unwrap();
+    fs::create_dir(dir.path().join("build")).unwrap();
+    fs::write(dir.path().join("a.log"), "a").unwrap();
+    fs::write(dir.path().join("a.txt"), "a").unwrap();
+    fs::write(dir.path().join("b.txt"), "b").unwrap();
+    fs::write(dir.path().join("build/out.o"), "o").unwrap();
+    fs::write(dir.path().join("sub/keep.log"), "k").unwrap();
+    fs::write(dir.path().join("sub/deeper/c.log"), "c").unwrap();
+    dir
+}
+
+fn explain(dir: &Path, options: &ExcludeOptions, path: &str) -> Option<(PathBuf, ExcludeReason)> {
+    let mut rules = ExcludeRules::new(dir, options).unwrap_or_else(|_| panic!());
+    rules.explain(&dir.join(path)).unwrap_or_else(|_| panic!())
+}
+
+// the matched path, the file the pattern came from (None for --exclude), the pattern and
+// whether it is a whitelist pattern
+fn pattern(result: Option<(PathBuf, ExcludeReason)>) -> (PathBuf, Option<PathBuf>, String, bool) {
+    match result {
+        Some((
+            path,
+            ExcludeReason::Pattern {
+                source,
+                pattern,
+                whitelist,
+            },
+        )) => (path, source, pattern, whitelist),
+        other => panic!("expected a pattern, got {:?}", other),
+    }
+}
+
+#[test]
+fn nested_gittyignore_takes_precedence() {
+    let dir = example_dir();
+    let root_ignore = dir.path().join(".gittyignore");
+    let sub_ignore = dir.path().join("sub/.gittyignore");
+    fs::write(&root_ignore, "*.log\n").unwrap();
+    fs::write(&sub_ignore, "!keep.log\n").unwrap();
+    let options = ExcludeOptions::default();
+
+    assert_eq!(
+        pattern(explain(dir.path(), &options, "a.log")),
+        (
+            dir.path().join("a.log"),
+            Some(root_ignore.clone()),
+            "*.log".to_owned(),
+            false
+        )
+    );
+    assert_eq!(
+        pattern(explain(dir.path(), &options, "sub/keep.log")),
+        (
+            dir.path().join("sub/keep.log"),
+            Some(sub_ignore),
+            "!keep.log".to_owned(),
+            true
+        )
+    );
+    // patterns of a parent apply further down
+    assert_eq!(
+        pattern(explain(dir.path(), &options, "sub/deeper/c.log")),
+        (
+            dir.path().join("sub/deeper/c.log"),
+            Some(root_ignore),
+            "*.log".to_owned(),
+            false
+        )
+    );
+    assert!(explain(dir.path(), &options, "a.txt").is_none());
+}
+
+#[test]
+fn excluded_parent_is_reported() {
+    let dir = example_dir();
+    let exclude_file = dir.path().join("repository-exclude");
+    fs::write(&exclude_file, "build/\n").unwrap();
+    let options = ExcludeOptions {
+        exclude_file: Some(exclude_file.clone()),
+        ..ExcludeOptions::default()
+    };
+    assert_eq!(
+        pattern(explain(dir.path(), &options, "build/out.o")),
+        (
+            dir.path().join("build"),
+            Some(exclude_file),
+            "build/".to_owned(),
+            false
+        )
+    );
+}
+
+#[test]
+fn gittyignore_takes_precedence_over_global_rules() {
+    let dir = example_dir();
+    let exclude_file = dir.path().join("repository-exclude");
+    fs::write(&exclude_file, "*.txt\n").unwrap();
+    fs::write(dir.path().join(".gittyignore"), "!a.txt\n").unwrap();
+    let options = ExcludeOptions {
+        exclude_file: Some(exclude_file.clone()),
+        patterns: vec!["a.log".to_owned()],
+        ..ExcludeOptions::default()
+    };
+    assert_eq!(
+        pattern(explain(dir.path(), &options, "a.txt")),
+        (
+            dir.path().join("a.txt"),
+            Some(dir.path().join(".gittyignore")),
+            "!a.txt".to_owned(),
+            true
+        )
+    );
+    assert_eq!(
+        pattern(explain(dir.path(), &options, "b.txt")),
+        (
+            dir.path().join("b.txt"),
+            Some(exclude_file),
+            "*.txt".to_owned(),
+            false
+        )
+    );
+    assert_eq!(
+        pattern(explain(dir.path(), &options, "a.log")),
+        (dir.path().join("a.log"), None, "a.log".to_owned(), false)
+    );
+}
+
+#[test]
+fn exclude_patterns_take_precedence_over_exclude_file() {
+    let dir = example_dir();
+    let exclude_file = dir.path().join("repository-exclude");
+    fs::write(&exclude_file, "*.txt\n").unwrap();
+    let options = ExcludeOptions {
+        exclude_file: Some(exclude_file),
+        patterns: vec!["!b.txt".to_owned()],
+        ..ExcludeOptions::default()
+    };
+    assert_eq!(
+        pattern(explain(dir.path(), &options, "b.txt")),
+        (dir.path().join("b.txt"), None, "!b.txt".to_owned(), true)
+    );
+}
+
+#[test]
+fn cache_directories_are_only_excluded_on_request() {
+    let dir = example_dir();
+    fs::write(
+        dir.path().join("build").join(CACHEDIR_TAG_FILENAME),
+        "Signature: 8a477f597d28d172789f06886806bc55\n",
+    )
+    .unwrap();
+    assert!(explain(dir.path(), &ExcludeOptions::default(), "build/out.o").is_none());
+
+    let options = ExcludeOptions {
+        exclude_caches: true,
+        ..ExcludeOptions::default()
+    };
+    match explain(dir.path(), &options, "build/out.o") {
+        Some((path, ExcludeReason::CacheDirTag)) => assert_eq!(path, dir.path().join("build")),
+        other => panic!("expected the cache directory tag, got {:?}", other),
+    }
+}
+
+#[test]
+fn paths_outside_the_root_are_rejected() {
+    let dir = example_dir();
+    let mut rules = ExcludeRules::new(&dir.path().join("sub"), &ExcludeOptions::default())
+        .unwrap_or_else(|_| panic!());
+    assert!(rules.explain(&dir.path().join("a.txt")).is_err());
+}
@@ -0,0 +1,93 @@
+extern crate blake3;
+extern crate gitty_backup_rs;
+extern crate sha2;
+extern crate tempfile;
+
+use gitty_backup_rs::database::config::RepoConfig;
+use gitty_backup_rs::database::crypto::KeySource;
+use gitty_backup_rs::database::fs_database::FSDatabase;
+use gitty_backup_rs::database::fs_database::FSDatabaseConfig;
+use gitty_backup_rs::database::GittyDatabase;
+use gitty_backup_rs::model::*;
+use sha2::Digest;
+use sha2::Sha256;
+use std::fs;
+use std::io::Cursor;
+use std::io::Read;
+use std::path::Path;
+
+const CONTENT: &[u8] = b"some file content";
+
+fn config(root: &Path, key: Option<KeySource>, hash_algorithm: HashAlgorithm) -> FSDatabaseConfig {
+    FSDatabaseConfig {
+        root: root.to_path_buf(),
+        object_prefix_length: 3,
+        key,
+        hash_algorithm,
+    }
+}
+
+fn store_and_read(db: &FSDatabase) -> GittyHash {
+    let stored = db
+        .store_blob_from_reader(&mut Cursor::new(CONTENT))
+        .unwrap_or_else(|_| panic!());
+    let mut content = Vec::new();
+    db.load_blob(&stored.blob_ref)
+        .unwrap_or_else(|_| panic!())
+        .read_to_end(&mut content)
+        .unwrap();
+    assert_eq!(content, CONTENT);
+    stored.blob_ref.hash
+}
+
+#[test]
+fn new_repositories_use_blake3() {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = dir.path().join("repo");
+    let db = FSDatabase::create_or_open(&repo, None).unwrap_or_else(|e| panic!("{}", e));
+    let hash = store_and_read(&db);
+    assert_eq!(hash.algorithm, HashAlgorithm::Blake3);
+    assert_eq!(&hash.digest, blake3::hash(CONTENT).as_bytes());
+    let config = RepoConfig::load(&repo.join("config")).unwrap();
+    assert_eq!(config.hash_algorithm, HashAlgorithm::Blake3);
+}
+
+#[test]
+fn sha256_repository_keeps_its_algorithm() {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = dir.path().join("repo");
+    let db = FSDatabase::open_or_create(config(&repo, None, HashAlgorithm::Sha256))
+        .unwrap_or_else(|e| panic!("{}", e));
+    let hash = store_and_read(&db);
+    assert_eq!(hash.algorithm, HashAlgorithm::Sha256);
+    assert_eq!(&hash.digest[..], &Sha256::digest(CONTENT)[..]);
+    let head = db.get_head_commit().unwrap_or_else(|_| panic!());
+    assert_eq!(head.hash.algorithm, HashAlgorithm::Sha256);
+    drop(db);
+
+    // the algorithm given when opening only applies to new repositories
+    let db = FSDatabase::open_or_create(config(&repo, None, HashAlgorithm::Blake3))
+        .unwrap_or_else(|e| panic!("{}", e));
+    assert_eq!(store_and_read(&db), hash);
+    db.load_commit(&head).unwrap_or_else(|_| panic!());
+}
+
+#[test]
+fn encrypted_sha256_repository() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_path = dir.path().join("key");
+    fs::write(&key_path, vec![5u8; 32]).unwrap();
+    let key = KeySource::KeyFile(key_path);
+    let repo = dir.path().join("repo");
+    let hash = {
+        let db =
+            FSDatabase::open_or_create(config(&repo, Some(key.clone()), HashAlgorithm::Sha256))
+                .unwrap_or_else(|e| panic!("{}", e));
+        store_and_read(&db)
+    };
+    assert_eq!(hash.algorithm, HashAlgorithm::Sha256);
+    // keyed, so the id doesn't reveal the content
+    assert_ne!(&hash.digest[..], &Sha256::digest(CONTENT)[..]);
+    let db = FSDatabase::create_or_open(&repo, Some(key)).unwrap_or_else(|e| panic!("{}", e));
+    assert_eq!(store_and_read(&db), hash);
+}
@@ -0,0 +1,210 @@
+extern crate chrono;
+extern crate gitty_backup_rs;
+extern crate serde_json;
+extern crate sha2;
+extern crate tempfile;
+
+use chrono::prelude::*;
+use gitty_backup_rs::database::config::RepoConfig;
+use gitty_backup_rs::database::config::FORMAT_VERSION;
+use gitty_backup_rs::database::fs_database::FSDatabase;
+use gitty_backup_rs::database::GittyDatabase;
+use gitty_backup_rs::model::*;
+use sha2::Digest;
+use sha2::Sha256;
+use std::ffi::OsString;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+
+fn loose_path(repo: &Path, dir: &str, hash: &GittyHash) -> PathBuf {
+    let hex: String = hash.digest.iter().map(|b| format!("{:02x}", b)).collect();
+    repo.join(dir).join(&hex[..3]).join(&hex[3..])
+}
+
+// stores content like versions without a config file
+fn write_loose(repo: &Path, dir: &str, content: &[u8]) -> GittyHash {
+    let mut digest = [0; 32];
+    digest.copy_from_slice(&Sha256::digest(content));
+    let hash = GittyHash {
+        algorithm: HashAlgorithm::Sha256,
+        digest,
+    };
+    let path = loose_path(repo, dir, &hash);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, content).unwrap();
+    hash
+}
+
+fn json_commit(root: GittyHash, parents: Vec<GittyHash>, depth: u64) -> Vec<u8> {
+    let author = GittyAuthor {
+        name: "user".to_owned(),
+        email: "user@host".to_owned(),
+    };
+    let time = FixedOffset::east(0).timestamp(1_500_000_000 + depth as i64, 0);
+    serde_json::to_vec(&GittyCommit {
+        author: author.clone(),
+        committer: author,
+        author_time: time,
+        commit_time: time,
+        message: "automatic commit".to_owned(),
+        depth,
+        parents,
+        root,
+        snapshot: GittySnapshotInfo::default(),
+    })
+    .unwrap()
+}
+
+// a repository as written before the format version existed, with one snapshot
+fn legacy_repository(repo: &Path) -> GittyHash {
+    let empty_tree = serde_json::to_vec(&GittyTree { entries: vec![] }).unwrap();
+    let empty_tree = write_loose(repo, "tree", &empty_tree);
+    let first = write_loose(repo, "commit", &json_commit(empty_tree, vec![], 0));
+    let blob = write_loose(repo, "file", b"old content");
+    let tree = GittyTree {
+        entries: vec![GittyTreeEntry::Blob(GittyBlobMetadata {
+            name: OsString::from("file.txt"),
+            modified: Utc.timestamp(1_500_000_000, 0),
+            permissions: Permissions {
+                kind: "unix".to_owned(),
+                mode: 0o100644,
+                uid: 1000,
+                gid: 1000,
+            },
+            size: 11,
+            is_symlink: false,
+            unstable: false,
+            hash: blob.clone(),
+        })],
+    };
+    let tree = write_loose(repo, "tree", &serde_json::to_vec(&tree).unwrap());
+    let head = write_loose(repo, "commit", &json_commit(tree, vec![first], 1));
+    fs::write(
+        repo.join("HEAD"),
+        serde_json::to_vec(&GittyCommitRef { hash: head.clone() }).unwrap(),
+    )
+    .unwrap();
+    blob
+}
+
+fn head_file(db: &FSDatabase) -> Vec<u8> {
+    let head = db.load_commit(&db.get_head_commit().unwrap_or_else(|_| panic!()));
+    let head = head.unwrap_or_else(|_| panic!());
+    let tree = db.load_tree(&GittyTreeRef { hash: head.root });
+    let tree = tree.unwrap_or_else(|_| panic!());
+    let hash = match tree.entries[0] {
+        GittyTreeEntry::Blob(ref b) => b.hash.clone(),
+        _ => panic!("not a file"),
+    };
+    let mut content = Vec::new();
+    db.load_blob(&GittyBlobRef { hash })
+        .unwrap_or_else(|_| panic!())
+        .read_to_end(&mut content)
+        .unwrap();
+    content
+}
+
+#[test]
+fn migrates_legacy_repository() {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = dir.path().join("repo");
+    legacy_repository(&repo);
+    let db = FSDatabase::create_or_open(&repo, None).unwrap_or_else(|e| panic!("{}", e));
+    assert_eq!(head_file(&db), b"old content");
+    let old_head = db.get_head_commit().unwrap_or_else(|_| panic!());
+
+    let stats = db.migrate().unwrap_or_else(|e| panic!("{}", e));
+    assert_eq!(stats.from_version, 0);
+    assert_eq!(stats.trees, 2);
+    assert_eq!(stats.commits, 2);
+    assert_ne!(db.get_head_commit().unwrap_or_else(|_| panic!()), old_head);
+    assert_eq!(head_file(&db), b"old content");
+    // everything is small enough to be packed
+    assert!(!repo.join("tree").exists() || fs::read_dir(repo.join("tree")).unwrap().count() == 0);
+    drop(db);
+
+    let config = RepoConfig::load(&repo.join("config")).unwrap();
+    assert_eq!(config.format_version, FORMAT_VERSION);
+    let db = FSDatabase::create_or_open(&repo, None).unwrap_or_else(|e| panic!("{}", e));
+    assert_eq!(head_file(&db), b"old content");
+    assert_eq!(db.migrate().unwrap_or_else(|e| panic!("{}", e)).trees, 0);
+}
+
+#[test]
+fn legacy_repository_keeps_its_format() {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = dir.path().join("repo");
+    legacy_repository(&repo);
+    let db = FSDatabase::create_or_open(&repo, None).unwrap_or_else(|e| panic!("{}", e));
+    let blob = db
+        .store_blob_from_reader(&mut &b"new content"[..])
+        .unwrap_or_else(|_| panic!())
+        .blob_ref;
+    let tree = GittyTree { entries: vec![] };
+    let tree_ref = db.store_tree(tree.clone()).unwrap_or_else(|_| panic!());
+    let head = db.get_head_commit().unwrap_or_else(|_| panic!());
+    db.update_head_commit(&head).unwrap_or_else(|_| panic!());
+    assert!(db.repack().is_err());
+    drop(db);
+
+    // as versions without a config file wrote them
+    assert!(!repo.join("pack").exists());
+    assert!(!repo.join("config").exists());
+    let blob_path = loose_path(&repo, "file", &blob.hash);
+    assert_eq!(fs::read(blob_path).unwrap(), b"new content");
+    let json = serde_json::to_vec(&tree).unwrap();
+    let tree_path = loose_path(&repo, "tree", &tree_ref.hash);
+    assert_eq!(fs::read(tree_path).unwrap(), json);
+    assert_eq!(tree_ref.hash, write_loose(&repo, "tree", &json));
+
+    let db = FSDatabase::create_or_open(&repo, None).unwrap_or_else(|e| panic!("{}", e));
+    assert_eq!(head_file(&db), b"old content");
+    db.migrate().unwrap_or_else(|e| panic!("{}", e));
+    assert_eq!(head_file(&db), b"old content");
+    db.repack().unwrap_or_else(|e| panic!("{}", e));
+}
+
+#[test]
+fn refuses_newer_formats() {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = dir.path().join("repo");
+    drop(FSDatabase::create_or_open(&repo, None).unwrap_or_else(|e| panic!("{}", e)));
+    let mut config = RepoConfig::load(&repo.join("config")).unwrap();
+    assert_eq!(config.format_version, FORMAT_VERSION);
+
+    config.features.push("from-the-future".to_owned());
+    config.save(&repo.join("config")).unwrap();
+    assert!(FSDatabase::create_or_open(&repo, None).is_err());
+
+    config.features.pop();
+    config.format_version = FORMAT_VERSION + 1;
+    config.save(&repo.join("config")).unwrap();
+    assert!(FSDatabase::create_or_open(&repo, None).is_err());
+}
+
+#[test]
+fn interrupted_migration_leaves_readable_repository() {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = dir.path().join("repo");
+    legacy_repository(&repo);
+    // a directory in place of a loose object makes repacking fail
+    let stray = loose_path(&repo, "file", &write_loose(&repo, "file", b"stray"));
+    fs::remove_file(&stray).unwrap();
+    fs::create_dir(&stray).unwrap();
+    let db = FSDatabase::create_or_open(&repo, None).unwrap_or_else(|e| panic!("{}", e));
+    let old_head = db.get_head_commit().unwrap_or_else(|_| panic!());
+    assert!(db.migrate().is_err());
+    drop(db);
+
+    // HEAD only moves once the config says the new format may be in use
+    let config = RepoConfig::load(&repo.join("config")).unwrap();
+    assert_eq!(config.format_version, FORMAT_VERSION);
+    let db = FSDatabase::create_or_open(&repo, None).unwrap_or_else(|e| panic!("{}", e));
+    assert_ne!(db.get_head_commit().unwrap_or_else(|_| panic!()), old_head);
+    assert_eq!(head_file(&db), b"old content");
+    fs::remove_dir(&stray).unwrap();
+    db.repack().unwrap_or_else(|e| panic!("{}", e));
+    assert_eq!(head_file(&db), b"old content");
+}
@@ -0,0 +1,156 @@
+extern crate gitty_backup_rs;
+extern crate tempfile;
+
+use gitty_backup_rs::database::fs_database::FSDatabase;
+use gitty_backup_rs::database::memory_database::MemoryDatabase;
+use gitty_backup_rs::database::GittyDatabase;
+use gitty_backup_rs::fs_walk::recursive_write_tree_to_db;
+use gitty_backup_rs::fs_walk::WalkOptions;
+use gitty_backup_rs::model::*;
+use gitty_backup_rs::progress::NoObserver;
+use std::fs;
+use std::io::Cursor;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+
+fn count_files(dir: &Path) -> usize {
+    if !dir.exists() {
+        return 0;
+    }
+    let mut count = 0;
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            count += count_files(&path);
+        } else {
+            count += 1;
+        }
+    }
+    count
+}
+
+fn read_blob(db: &dyn GittyDatabase, hash: &GittyHash) -> Vec<u8> {
+    let mut content = Vec::new();
+    db.load_blob(&GittyBlobRef { hash: hash.clone() })
+        .unwrap_or_else(|_| panic!())
+        .read_to_end(&mut content)
+        .unwrap();
+    content
+}
+
+fn blob_hash(tree: &GittyTree, name: &str) -> GittyHash {
+    for entry in &tree.entries {
+        if let GittyTreeEntry::Blob(b) = entry {
+            if b.name == name {
+                return b.hash.clone();
+            }
+        }
+    }
+    panic!("no file {}", name)
+}
+
+#[test]
+fn small_objects_are_packed() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    fs::create_dir_all(source.join("sub")).unwrap();
+    for i in 0..20 {
+        fs::write(source.join(format!("{}.txt", i)), format!("file {}", i)).unwrap();
+    }
+    fs::write(source.join("sub/big"), vec![3u8; 100_000]).unwrap();
+    let repo = dir.path().join("repo");
+    let root = {
+        let db = FSDatabase::create_or_open(&repo, None).unwrap_or_else(|e| panic!("{}", e));
+        recursive_write_tree_to_db(&source, &db, &WalkOptions::default(), &mut NoObserver)
+            .unwrap_or_else(|e| panic!("{}", e))
+            .root
+    };
+    assert_eq!(count_files(&repo.join("file")), 1);
+    assert_eq!(count_files(&repo.join("tree")), 0);
+    assert_eq!(count_files(&repo.join("commit")), 0);
+
+    let db = FSDatabase::create_or_open(&repo, None).unwrap_or_else(|e| panic!("{}", e));
+    let tree = db.load_tree(&root).unwrap_or_else(|_| panic!());
+    assert_eq!(read_blob(&db, &blob_hash(&tree, "7.txt")), b"file 7");
+    let mut reader = db
+        .load_blob(&GittyBlobRef {
+            hash: blob_hash(&tree, "12.txt"),
+        })
+        .unwrap_or_else(|_| panic!());
+    let mut buf = [0u8; 10];
+    assert_eq!(reader.read_at(5, &mut buf).unwrap(), 2);
+    assert_eq!(&buf[..2], b"12");
+}
+
+#[test]
+fn repack_moves_loose_objects() {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = dir.path().join("repo");
+    let db = FSDatabase::create_or_open(&repo, None).unwrap_or_else(|e| panic!("{}", e));
+    // a blob written by a version without packs
+    let content = b"stored before packs existed".to_vec();
+    let hash = MemoryDatabase::new()
+        .store_blob_from_reader(&mut Cursor::new(content.clone()))
+        .unwrap_or_else(|_| panic!())
+        .blob_ref
+        .hash;
+    let hex: String = hash.digest.iter().map(|b| format!("{:02x}", b)).collect();
+    let loose: PathBuf = repo.join("file").join(&hex[..3]).join(&hex[3..]);
+    fs::create_dir_all(loose.parent().unwrap()).unwrap();
+    fs::write(&loose, &content).unwrap();
+    // files next to the prefix directories are ignored
+    fs::write(repo.join("file/.DS_Store"), "").unwrap();
+    let storage = db.storage_stats().unwrap_or_else(|_| panic!());
+    assert_eq!(storage.blobs, 1);
+    assert_eq!(storage.blob_bytes, content.len() as u64);
+    // the empty tree and the initial commit
+    assert_eq!(storage.objects(), 3);
+
+    let stats = db.repack().unwrap_or_else(|e| panic!("{}", e));
+    assert_eq!(stats.objects, 1);
+    assert_eq!(stats.bytes, content.len() as u64);
+    assert!(!loose.exists());
+    let repacked = db.storage_stats().unwrap_or_else(|_| panic!());
+    assert_eq!(repacked.objects(), storage.objects());
+    assert_eq!(repacked.bytes(), storage.bytes());
+    assert_eq!(read_blob(&db, &hash), content);
+    drop(db);
+    let db = FSDatabase::create_or_open(&repo, None).unwrap_or_else(|e| panic!("{}", e));
+    assert_eq!(read_blob(&db, &hash), content);
+}
+
+#[test]
+fn packs_of_other_processes_are_found() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    fs::create_dir(&source).unwrap();
+    fs::write(source.join("file"), "written after opening").unwrap();
+    let repo = dir.path().join("repo");
+    let reader = FSDatabase::create_or_open(&repo, None).unwrap_or_else(|e| panic!("{}", e));
+    let before = reader.storage_stats().unwrap_or_else(|_| panic!());
+    // listed long after its last change, so the reader remembers it
+    let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+    fs::File::open(repo.join("pack"))
+        .and_then(|dir| dir.set_modified(an_hour_ago))
+        .unwrap();
+    let missing = GittyTreeRef {
+        hash: PLACEHOLDER_HASH,
+    };
+    assert!(reader.load_tree(&missing).is_err());
+    let root = {
+        let writer = FSDatabase::create_or_open(&repo, None).unwrap_or_else(|e| panic!("{}", e));
+        recursive_write_tree_to_db(&source, &writer, &WalkOptions::default(), &mut NoObserver)
+            .unwrap_or_else(|e| panic!("{}", e))
+            .root
+    };
+    let tree = reader.load_tree(&root).unwrap_or_else(|_| panic!());
+    assert_eq!(
+        read_blob(&reader, &blob_hash(&tree, "file")),
+        b"written after opening"
+    );
+    let after = reader.storage_stats().unwrap_or_else(|_| panic!());
+    assert_eq!(after.objects(), before.objects() + 2);
+}
@@ -0,0 +1,588 @@
+extern crate gitty_backup_rs;
+#[macro_use]
+extern crate serde_json;
+extern crate tempfile;
+
+use gitty_backup_rs::commits::commit_current_state_to_head;
+use gitty_backup_rs::database::commit_graph::CommitGraphEntry;
+use gitty_backup_rs::database::fs_database::FSDatabase;
+use gitty_backup_rs::database::memory_database::MemoryDatabase;
+use gitty_backup_rs::database::BlobReader;
+use gitty_backup_rs::database::DBError;
+use gitty_backup_rs::database::GittyDatabase;
+use gitty_backup_rs::database::StorageStats;
+use gitty_backup_rs::database::StoredBlob;
+use gitty_backup_rs::fs_walk::recursive_write_tree_to_db;
+use gitty_backup_rs::fs_walk::write_source_to_db;
+use gitty_backup_rs::fs_walk::ErrorPolicy;
+use gitty_backup_rs::fs_walk::SnapshotSource;
+use gitty_backup_rs::fs_walk::WalkOptions;
+use gitty_backup_rs::fs_walk::WalkResult;
+use gitty_backup_rs::model::*;
+use gitty_backup_rs::mounts::MountTable;
+use gitty_backup_rs::progress::JsonEvents;
+use gitty_backup_rs::progress::NoObserver;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::io::Cursor;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::fs::symlink;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn example_dir() -> TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    fs::create_dir_all(root.join("sub/deeper")).unwrap();
+    fs::create_dir(root.join("empty")).unwrap();
+    fs::write(root.join("a.txt"), "hello").unwrap();
+    fs::write(root.join("b.txt"), "hello").unwrap();
+    fs::write(root.join("sub/c.txt"), "world!").unwrap();
+    fs::write(root.join("sub/deeper/d.bin"), vec![7u8; 100_000]).unwrap();
+    symlink("a.txt", root.join("link")).unwrap();
+    dir
+}
+
+fn snapshot(dir: &Path, db: &(impl GittyDatabase + Sync), options: &WalkOptions) -> WalkResult {
+    recursive_write_tree_to_db(dir, db, options, &mut NoObserver)
+        .unwrap_or_else(|e| panic!("{}", e))
+}
+
+fn names(tree: &GittyTree) -> Vec<&OsStr> {
+    tree.entries
+        .iter()
+        .map(|e| match e {
+            GittyTreeEntry::Tree(t) => t.name.as_os_str(),
+            GittyTreeEntry::Blob(b) => b.name.as_os_str(),
+        })
+        .collect()
+}
+
+fn subtree(db: &dyn GittyDatabase, tree: &GittyTree, name: &str) -> GittyTree {
+    for entry in &tree.entries {
+        if let GittyTreeEntry::Tree(t) = entry {
+            if t.name == name {
+                return db
+                    .load_tree(&GittyTreeRef {
+                        hash: t.hash.clone(),
+                    })
+                    .unwrap_or_else(|_| panic!("missing tree {}", name));
+            }
+        }
+    }
+    panic!("no directory {} in {:?}", name, names(tree))
+}
+
+fn blob<'a>(tree: &'a GittyTree, name: &str) -> &'a GittyBlobMetadata {
+    tree.entries
+        .iter()
+        .filter_map(|e| match e {
+            GittyTreeEntry::Blob(b) if b.name == name => Some(b),
+            _ => None,
+        })
+        .next()
+        .unwrap_or_else(|| panic!("no file {} in {:?}", name, names(tree)))
+}
+
+fn read_blob(db: &dyn GittyDatabase, entry: &GittyBlobMetadata) -> Vec<u8> {
+    let mut content = Vec::new();
+    db.load_blob(&GittyBlobRef {
+        hash: entry.hash.clone(),
+    })
+    .unwrap_or_else(|_| panic!("missing blob"))
+    .read_to_end(&mut content)
+    .unwrap();
+    content
+}
+
+#[test]
+fn stores_directory_structure() {
+    let dir = example_dir();
+    let db = MemoryDatabase::new();
+    let result = snapshot(dir.path(), &db, &WalkOptions::default());
+    let root = db.load_tree(&result.root).unwrap_or_else(|_| panic!());
+    // directories first, then by name
+    assert_eq!(names(&root), vec!["empty", "sub", "a.txt", "b.txt", "link"]);
+    assert!(subtree(&db, &root, "empty").entries.is_empty());
+    let sub = subtree(&db, &root, "sub");
+    assert_eq!(names(&sub), vec!["deeper", "c.txt"]);
+    assert_eq!(read_blob(&db, blob(&sub, "c.txt")), b"world!");
+    let deeper = subtree(&db, &sub, "deeper");
+    assert_eq!(blob(&deeper, "d.bin").size, 100_000);
+    assert_eq!(read_blob(&db, blob(&deeper, "d.bin")), vec![7u8; 100_000]);
+    assert!(blob(&root, "link").is_symlink);
+    assert_eq!(blob(&root, "link").size, 5);
+    assert_eq!(read_blob(&db, blob(&root, "link")), b"a.txt");
+    assert_eq!(result.info.files, 5);
+    // the size of a symlink is the length of its target
+    assert_eq!(result.info.bytes, 5 + 5 + 6 + 100_000 + 5);
+}
+
+#[test]
+fn deduplicates_identical_files() {
+    let dir = example_dir();
+    let db = MemoryDatabase::new();
+    let result = snapshot(dir.path(), &db, &WalkOptions::default());
+    let root = db.load_tree(&result.root).unwrap_or_else(|_| panic!());
+    assert_eq!(blob(&root, "a.txt").hash, blob(&root, "b.txt").hash);
+    // "hello", "world!", d.bin and the target of link
+    assert_eq!(db.stats().blobs, 4);
+}
+
+#[test]
+fn tree_hash_is_deterministic() {
+    let dir = example_dir();
+    let first = snapshot(dir.path(), &MemoryDatabase::new(), &WalkOptions::default());
+    let second = snapshot(dir.path(), &MemoryDatabase::new(), &WalkOptions::default());
+    assert_eq!(first.root, second.root);
+    for threads in &[1, 2, 8] {
+        let options = WalkOptions {
+            threads: *threads,
+            ..WalkOptions::default()
+        };
+        let result = snapshot(dir.path(), &MemoryDatabase::new(), &options);
+        assert_eq!(first.root, result.root, "with {} threads", threads);
+    }
+}
+
+#[test]
+fn tree_hash_changes_with_content() {
+    let dir = example_dir();
+    let before = snapshot(dir.path(), &MemoryDatabase::new(), &WalkOptions::default());
+    fs::write(dir.path().join("sub/deeper/d.bin"), "changed").unwrap();
+    let after = snapshot(dir.path(), &MemoryDatabase::new(), &WalkOptions::default());
+    assert_ne!(before.root, after.root);
+}
+
+#[test]
+fn fs_and_memory_database_agree() {
+    let dir = example_dir();
+    let repo = tempfile::tempdir().unwrap();
+    let fs_db = FSDatabase::create_or_open(&repo.path().join("db"), None)
+        .unwrap_or_else(|e| panic!("{}", e));
+    let fs_result = snapshot(dir.path(), &fs_db, &WalkOptions::default());
+    let memory_result = snapshot(dir.path(), &MemoryDatabase::new(), &WalkOptions::default());
+    assert_eq!(fs_result.root, memory_result.root);
+    let root = fs_db
+        .load_tree(&fs_result.root)
+        .unwrap_or_else(|_| panic!());
+    assert_eq!(read_blob(&fs_db, blob(&root, "link")), b"a.txt");
+}
+
+#[test]
+fn honors_gittyignore() {
+    let dir = example_dir();
+    fs::write(dir.path().join(".gittyignore"), "*.bin\nempty/\n").unwrap();
+    let db = MemoryDatabase::new();
+    let result = snapshot(dir.path(), &db, &WalkOptions::default());
+    let root = db.load_tree(&result.root).unwrap_or_else(|_| panic!());
+    assert_eq!(
+        names(&root),
+        vec!["sub", ".gittyignore", "a.txt", "b.txt", "link"]
+    );
+    let deeper = subtree(&db, &subtree(&db, &root, "sub"), "deeper");
+    assert!(deeper.entries.is_empty());
+}
+
+#[test]
+fn invalid_nested_gittyignore_follows_error_policy() {
+    let dir = example_dir();
+    let ignorefile = dir.path().join("sub/.gittyignore");
+    fs::write(&ignorefile, "*.txt\nunclosed[\n").unwrap();
+    let db = MemoryDatabase::new();
+    let result = snapshot(dir.path(), &db, &WalkOptions::default());
+    assert_eq!(result.info.errors.len(), 1);
+    assert_eq!(result.info.errors[0].path, ignorefile.as_os_str());
+    // the valid patterns still apply
+    let root = db.load_tree(&result.root).unwrap_or_else(|_| panic!());
+    assert_eq!(
+        names(&subtree(&db, &root, "sub")),
+        vec!["deeper", ".gittyignore"]
+    );
+
+    let abort = WalkOptions {
+        on_error: ErrorPolicy::Abort,
+        ..WalkOptions::default()
+    };
+    assert!(recursive_write_tree_to_db(dir.path(), &db, &abort, &mut NoObserver).is_err());
+}
+
+#[test]
+fn paths_are_stored_at_their_absolute_location() {
+    let dir = example_dir();
+    let db = MemoryDatabase::new();
+    let source = SnapshotSource::Paths(vec![
+        dir.path().join("sub/c.txt"),
+        dir.path().join("a.txt"),
+        dir.path().join("sub"),
+    ]);
+    let result = write_source_to_db(source, &db, &WalkOptions::default(), &mut NoObserver)
+        .unwrap_or_else(|e| panic!("{}", e));
+    let mut tree = db.load_tree(&result.root).unwrap_or_else(|_| panic!());
+    let absolute = dir.path().canonicalize().unwrap();
+    for component in absolute.iter().skip(1) {
+        assert_eq!(names(&tree), vec![component]);
+        tree = subtree(&db, &tree, component.to_str().unwrap());
+    }
+    // sub/c.txt is part of sub
+    assert_eq!(names(&tree), vec!["sub", "a.txt"]);
+    assert_eq!(names(&subtree(&db, &tree, "sub")), vec!["deeper", "c.txt"]);
+}
+
+#[test]
+fn missing_paths_follow_error_policy() {
+    let dir = example_dir();
+    let db = MemoryDatabase::new();
+    let missing = dir.path().join("missing.txt");
+    let source = || SnapshotSource::Paths(vec![missing.clone(), dir.path().join("a.txt")]);
+    let result = write_source_to_db(source(), &db, &WalkOptions::default(), &mut NoObserver)
+        .unwrap_or_else(|e| panic!("{}", e));
+    assert_eq!(result.info.files, 1);
+    assert_eq!(result.info.errors.len(), 1);
+    assert_eq!(result.info.errors[0].path, missing.as_os_str());
+
+    let abort = WalkOptions {
+        on_error: ErrorPolicy::Abort,
+        ..WalkOptions::default()
+    };
+    assert!(write_source_to_db(source(), &db, &abort, &mut NoObserver).is_err());
+}
+
+#[test]
+fn stream_is_stored_at_path() {
+    let db = MemoryDatabase::new();
+    let source = SnapshotSource::Stream {
+        path: "dumps/db.sql".into(),
+        reader: Box::new(Cursor::new(b"create table".to_vec())),
+    };
+    let result = write_source_to_db(source, &db, &WalkOptions::default(), &mut NoObserver)
+        .unwrap_or_else(|e| panic!("{}", e));
+    let root = db.load_tree(&result.root).unwrap_or_else(|_| panic!());
+    let dumps = subtree(&db, &root, "dumps");
+    assert_eq!(read_blob(&db, blob(&dumps, "db.sql")), b"create table");
+    assert_eq!(result.info.bytes, 12);
+}
+
+#[test]
+fn stream_keeps_previous_snapshot() {
+    let dir = example_dir();
+    let db = MemoryDatabase::new();
+    let commit = |source| {
+        let commit_ref =
+            commit_current_state_to_head(source, &db, &WalkOptions::default(), &mut NoObserver)
+                .unwrap_or_else(|e| panic!("{}", e));
+        db.load_commit(&commit_ref).unwrap_or_else(|_| panic!())
+    };
+    let stream = |content: &[u8]| SnapshotSource::Stream {
+        path: "dumps/db.sql".into(),
+        reader: Box::new(Cursor::new(content.to_vec())),
+    };
+    let files = commit(SnapshotSource::Dir(dir.path().to_path_buf()))
+        .snapshot
+        .files;
+    commit(stream(b"create table"));
+    let latest = commit(stream(b"create table t"));
+    assert_eq!(latest.snapshot.files, files + 1);
+    let root = db
+        .load_tree(&GittyTreeRef { hash: latest.root })
+        .unwrap_or_else(|_| panic!());
+    assert_eq!(
+        names(&root),
+        vec!["dumps", "empty", "sub", "a.txt", "b.txt", "link"]
+    );
+    let dumps = subtree(&db, &root, "dumps");
+    assert_eq!(read_blob(&db, blob(&dumps, "db.sql")), b"create table t");
+
+    let into_file = SnapshotSource::Stream {
+        path: "a.txt/db.sql".into(),
+        reader: Box::new(Cursor::new(vec![])),
+    };
+    assert!(
+        commit_current_state_to_head(into_file, &db, &WalkOptions::default(), &mut NoObserver)
+            .is_err()
+    );
+}
+
+#[test]
+fn stream_snapshots_dont_repeat_previous_errors() {
+    let dir = example_dir();
+    let db = MemoryDatabase::new();
+    let source = SnapshotSource::Paths(vec![dir.path().join("missing"), dir.path().join("a.txt")]);
+    let latest =
+        commit_current_state_to_head(source, &db, &WalkOptions::default(), &mut NoObserver)
+            .and_then(|_| {
+                let stream = SnapshotSource::Stream {
+                    path: "dumps/db.sql".into(),
+                    reader: Box::new(Cursor::new(b"create table".to_vec())),
+                };
+                commit_current_state_to_head(stream, &db, &WalkOptions::default(), &mut NoObserver)
+            })
+            .unwrap_or_else(|e| panic!("{}", e));
+    let commit = db.load_commit(&latest).unwrap_or_else(|_| panic!());
+    let parent = GittyCommitRef {
+        hash: commit.parents[0].clone(),
+    };
+    let previous = db.load_commit(&parent).unwrap_or_else(|_| panic!());
+    assert_eq!(previous.snapshot.errors.len(), 1);
+    assert!(commit.snapshot.errors.is_empty());
+    assert_eq!(commit.snapshot.files, 2);
+    assert_eq!(commit.snapshot.bytes, previous.snapshot.bytes + 12);
+}
+
+#[test]
+fn mount_points_of_skipped_types_are_recorded() {
+    let dir = example_dir();
+    let dir_path = dir.path().canonicalize().unwrap();
+    let mount_point = dir_path.join("sub/deeper");
+    let table = format!(
+        "/dev/sda1 / ext4 rw 0 0\ntmpfs {} tmpfs rw 0 0\n",
+        mount_point.to_str().unwrap().replace(' ', "\\040")
+    );
+    let options = WalkOptions {
+        skip_fs_types: vec!["tmpfs".to_owned()],
+        mount_table: Some(MountTable::parse(table.as_bytes())),
+        ..WalkOptions::default()
+    };
+    let db = MemoryDatabase::new();
+    let commit_ref = commit_current_state_to_head(
+        SnapshotSource::Dir(dir_path.clone()),
+        &db,
+        &options,
+        &mut NoObserver,
+    )
+    .unwrap_or_else(|e| panic!("{}", e));
+    let commit = db.load_commit(&commit_ref).unwrap_or_else(|_| panic!());
+    let skipped = &commit.snapshot.skipped_mounts;
+    assert_eq!(skipped.len(), 1);
+    assert_eq!(skipped[0].path, mount_point.as_os_str());
+    assert_eq!(skipped[0].fs_type.as_deref(), Some("tmpfs"));
+    let root = db
+        .load_tree(&GittyTreeRef { hash: commit.root })
+        .unwrap_or_else(|_| panic!());
+    assert_eq!(names(&subtree(&db, &root, "sub")), vec!["c.txt"]);
+
+    // other filesystem types are backed up
+    let options = WalkOptions {
+        skip_fs_types: vec!["proc".to_owned()],
+        ..options
+    };
+    let result = snapshot(&dir_path, &db, &options);
+    assert!(result.info.skipped_mounts.is_empty());
+}
+
+#[test]
+fn json_events_describe_the_walk() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("a.txt"), "hello").unwrap();
+    fs::write(dir.path().join("b.txt"), "hello").unwrap();
+    let db = MemoryDatabase::new();
+    // one thread stores the files in order, so the second one is deduplicated
+    let options = WalkOptions {
+        threads: 1,
+        ..WalkOptions::default()
+    };
+    let mut out = Vec::new();
+    let result =
+        recursive_write_tree_to_db(dir.path(), &db, &options, &mut JsonEvents::new(&mut out))
+            .unwrap_or_else(|e| panic!("{}", e));
+
+    let events: Vec<serde_json::Value> = String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let fields = |event: &serde_json::Value| -> Vec<String> {
+        let mut keys: Vec<String> = event.as_object().unwrap().keys().cloned().collect();
+        keys.sort();
+        keys
+    };
+    let of_kind = |kind: &str| -> Vec<&serde_json::Value> {
+        events.iter().filter(|e| e["event"] == kind).collect()
+    };
+    let a = dir.path().join("a.txt").to_str().unwrap().to_owned();
+    let b = dir.path().join("b.txt").to_str().unwrap().to_owned();
+
+    let started = of_kind("file_started");
+    assert_eq!(started.len(), 2);
+    assert_eq!(fields(started[0]), vec!["event", "path", "size"]);
+    assert_eq!(started[0]["path"], a.as_str());
+    assert_eq!(started[0]["size"], 5);
+    assert_eq!(started[1]["path"], b.as_str());
+    let hashed = of_kind("bytes_hashed");
+    assert_eq!(hashed.len(), 2);
+    assert_eq!(fields(hashed[0]), vec!["bytes", "event", "path"]);
+    assert_eq!(hashed[0]["bytes"], 5);
+    let deduplicated = of_kind("deduplicated");
+    assert_eq!(deduplicated.len(), 1);
+    assert_eq!(
+        fields(deduplicated[0]),
+        vec!["bytes", "event", "hash", "path"]
+    );
+    assert_eq!(deduplicated[0]["path"], b.as_str());
+    let tree = db.load_tree(&result.root).unwrap_or_else(|_| panic!());
+    assert_eq!(
+        deduplicated[0]["hash"],
+        blob(&tree, "a.txt").hash.to_string().as_str()
+    );
+
+    // the root tree is stored last
+    let n = events.len();
+    assert_eq!(fields(&events[n - 2]), vec!["event", "hash", "path"]);
+    assert_eq!(events[n - 2]["event"], "tree_stored");
+    assert_eq!(events[n - 2]["path"], dir.path().to_str().unwrap());
+    assert_eq!(events[n - 2]["hash"], result.root.hash.to_string().as_str());
+    assert_eq!(
+        events[n - 1],
+        json!({"event": "finished", "files": 2, "bytes": 10})
+    );
+    assert_eq!(n, 7);
+}
+
+#[test]
+fn special_files_are_skipped() {
+    let dir = example_dir();
+    let _socket = UnixListener::bind(dir.path().join("agent.sock")).unwrap();
+    let db = MemoryDatabase::new();
+    let options = WalkOptions {
+        on_error: ErrorPolicy::Abort,
+        ..WalkOptions::default()
+    };
+    let result = snapshot(dir.path(), &db, &options);
+    assert!(result.info.errors.is_empty());
+    let root = db.load_tree(&result.root).unwrap_or_else(|_| panic!());
+    assert!(!names(&root).contains(&OsStr::new("agent.sock")));
+}
+
+// a repository on a full disk
+// counts the attempts to store a blob
+struct FullDatabase(MemoryDatabase, AtomicUsize);
+
+impl GittyDatabase for FullDatabase {
+    fn get_head_commit(&self) -> Result<GittyCommitRef, DBError> {
+        self.0.get_head_commit()
+    }
+    fn update_head_commit(&self, commit_ref: &GittyCommitRef) -> Result<(), DBError> {
+        self.0.update_head_commit(commit_ref)
+    }
+    fn load_blob(&self, blob_ref: &GittyBlobRef) -> Result<BlobReader, DBError> {
+        self.0.load_blob(blob_ref)
+    }
+    fn load_tree(&self, tree_ref: &GittyTreeRef) -> Result<GittyTree, DBError> {
+        self.0.load_tree(tree_ref)
+    }
+    fn load_commit(&self, commit_ref: &GittyCommitRef) -> Result<GittyCommit, DBError> {
+        self.0.load_commit(commit_ref)
+    }
+    fn commit_graph(&self) -> Result<Vec<CommitGraphEntry>, DBError> {
+        self.0.commit_graph()
+    }
+    fn storage_stats(&self) -> Result<StorageStats, DBError> {
+        self.0.storage_stats()
+    }
+    fn store_blob(&self, _: &Path, _: bool) -> Result<StoredBlob, DBError> {
+        self.1.fetch_add(1, Ordering::SeqCst);
+        Err(io::Error::other("no space left on device").into())
+    }
+    fn store_blob_from_reader(&self, _: &mut dyn Read) -> Result<StoredBlob, DBError> {
+        self.1.fetch_add(1, Ordering::SeqCst);
+        Err(io::Error::other("no space left on device").into())
+    }
+    fn store_tree(&self, tree: GittyTree) -> Result<GittyTreeRef, DBError> {
+        self.0.store_tree(tree)
+    }
+    fn store_commit(&self, commit: GittyCommit) -> Result<GittyCommitRef, DBError> {
+        self.0.store_commit(commit)
+    }
+}
+
+#[test]
+fn repository_errors_fail_the_snapshot() {
+    let dir = example_dir();
+    let db = FullDatabase(MemoryDatabase::new(), AtomicUsize::new(0));
+    let result =
+        recursive_write_tree_to_db(dir.path(), &db, &WalkOptions::default(), &mut NoObserver);
+    assert!(result.is_err());
+
+    // they are not retried
+    let single = tempfile::tempdir().unwrap();
+    fs::write(single.path().join("a.txt"), "a").unwrap();
+    let db = FullDatabase(MemoryDatabase::new(), AtomicUsize::new(0));
+    let retry = WalkOptions {
+        on_error: ErrorPolicy::Retry(3),
+        ..WalkOptions::default()
+    };
+    assert!(recursive_write_tree_to_db(single.path(), &db, &retry, &mut NoObserver).is_err());
+    assert_eq!(db.1.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn unreadable_directories_are_skipped() {
+    let dir = example_dir();
+    // listable, but the entries can't be stat'ed
+    let locked = dir.path().join("locked");
+    fs::create_dir_all(locked.join("inner")).unwrap();
+    fs::write(locked.join("inner/file"), b"hidden").unwrap();
+    fs::write(locked.join("file"), b"hidden").unwrap();
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o600)).unwrap();
+    let readable = fs::metadata(locked.join("file")).is_ok();
+    let db = MemoryDatabase::new();
+    let result = snapshot(dir.path(), &db, &WalkOptions::default());
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+    let root = db.load_tree(&result.root).unwrap_or_else(|_| panic!());
+    assert!(names(&root).contains(&OsStr::new("locked")));
+    if readable {
+        // permissions don't apply to root
+        assert!(result.info.errors.is_empty());
+        return;
+    }
+    let mut failed: Vec<_> = result.info.errors.iter().map(|e| e.path.clone()).collect();
+    failed.sort();
+    assert_eq!(
+        failed,
+        vec![
+            locked.join("file").into_os_string(),
+            locked.join("inner").into_os_string()
+        ]
+    );
+    assert!(names(&subtree(&db, &root, "locked")).is_empty());
+}
+
+#[test]
+fn changing_files_are_stored_once() {
+    let dir = example_dir();
+    let unchanged = MemoryDatabase::new();
+    snapshot(dir.path(), &unchanged, &WalkOptions::default());
+    let path = dir.path().join("growing.log");
+    fs::write(&path, vec![b'x'; 8 << 20]).unwrap();
+    let db = MemoryDatabase::new();
+    let done = AtomicBool::new(false);
+    let options = WalkOptions {
+        unstable_retries: 2,
+        ..WalkOptions::default()
+    };
+    let result = thread::scope(|scope| {
+        scope.spawn(|| {
+            let mut log = fs::OpenOptions::new().append(true).open(&path).unwrap();
+            while !done.load(Ordering::SeqCst) {
+                log.write_all(b"y").unwrap();
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+        let result = snapshot(dir.path(), &db, &options);
+        done.store(true, Ordering::SeqCst);
+        result
+    });
+    assert_eq!(result.info.unstable, [path.into_os_string()]);
+    // the reads that changed were not kept
+    let blobs = |db: &MemoryDatabase| db.storage_stats().unwrap_or_else(|_| panic!()).blobs;
+    assert_eq!(blobs(&db), blobs(&unchanged) + 1);
+}